@@ -20,7 +20,8 @@ fn main() {
         println!("rebuilding pdf");
         let contents = std::fs::read_to_string(filename).unwrap();
 
-        match calculatex::generate_latex(&contents) {
+        let dir = std::path::Path::new(filename).parent().unwrap();
+        match calculatex::generate_latex_in(&contents, dir) {
             Ok(output) => {
                 let mut md_file = tempfile::NamedTempFile::new().unwrap();
                 write!(md_file, "{}", output).unwrap();
@@ -0,0 +1,509 @@
+use crate::expr::{unit::Unit, val::Val, value::Value, Expr};
+use crate::latex::{FormatArgs, LaTeX, ToLaTeX};
+use crate::parser::fn_call::FnCall;
+use crate::CalcError;
+use num::bigint::BigInt;
+use num::rational::BigRational;
+use num::traits::ToPrimitive;
+use num::{Integer, One, Signed, Zero};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Standard atomic weights in g/mol, in order of atomic number. Elements
+// without one use the mass number of their longest lived isotope.
+#[rustfmt::skip]
+const ELEMENTS: &[(&str, f64)] = &[
+    ("H", 1.008), ("He", 4.0026), ("Li", 6.94), ("Be", 9.0122), ("B", 10.81),
+    ("C", 12.011), ("N", 14.007), ("O", 15.999), ("F", 18.998), ("Ne", 20.180),
+    ("Na", 22.990), ("Mg", 24.305), ("Al", 26.982), ("Si", 28.085), ("P", 30.974),
+    ("S", 32.06), ("Cl", 35.45), ("Ar", 39.948), ("K", 39.098), ("Ca", 40.078),
+    ("Sc", 44.956), ("Ti", 47.867), ("V", 50.942), ("Cr", 51.996), ("Mn", 54.938),
+    ("Fe", 55.845), ("Co", 58.933), ("Ni", 58.693), ("Cu", 63.546), ("Zn", 65.38),
+    ("Ga", 69.723), ("Ge", 72.630), ("As", 74.922), ("Se", 78.971), ("Br", 79.904),
+    ("Kr", 83.798), ("Rb", 85.468), ("Sr", 87.62), ("Y", 88.906), ("Zr", 91.224),
+    ("Nb", 92.906), ("Mo", 95.95), ("Tc", 98.0), ("Ru", 101.07), ("Rh", 102.91),
+    ("Pd", 106.42), ("Ag", 107.87), ("Cd", 112.41), ("In", 114.82), ("Sn", 118.71),
+    ("Sb", 121.76), ("Te", 127.60), ("I", 126.90), ("Xe", 131.29), ("Cs", 132.91),
+    ("Ba", 137.33), ("La", 138.91), ("Ce", 140.12), ("Pr", 140.91), ("Nd", 144.24),
+    ("Pm", 145.0), ("Sm", 150.36), ("Eu", 151.96), ("Gd", 157.25), ("Tb", 158.93),
+    ("Dy", 162.50), ("Ho", 164.93), ("Er", 167.26), ("Tm", 168.93), ("Yb", 173.05),
+    ("Lu", 174.97), ("Hf", 178.49), ("Ta", 180.95), ("W", 183.84), ("Re", 186.21),
+    ("Os", 190.23), ("Ir", 192.22), ("Pt", 195.08), ("Au", 196.97), ("Hg", 200.59),
+    ("Tl", 204.38), ("Pb", 207.2), ("Bi", 208.98), ("Po", 209.0), ("At", 210.0),
+    ("Rn", 222.0), ("Fr", 223.0), ("Ra", 226.0), ("Ac", 227.0), ("Th", 232.04),
+    ("Pa", 231.04), ("U", 238.03), ("Np", 237.0), ("Pu", 244.0), ("Am", 243.0),
+    ("Cm", 247.0), ("Bk", 247.0), ("Cf", 251.0), ("Es", 252.0), ("Fm", 257.0),
+    ("Md", 258.0), ("No", 259.0), ("Lr", 266.0), ("Rf", 267.0), ("Db", 268.0),
+    ("Sg", 269.0), ("Bh", 270.0), ("Hs", 277.0), ("Mt", 278.0), ("Ds", 281.0),
+    ("Rg", 282.0), ("Cn", 285.0), ("Nh", 286.0), ("Fl", 289.0), ("Mc", 290.0),
+    ("Lv", 293.0), ("Ts", 294.0), ("Og", 294.0),
+];
+
+pub fn atomic_mass(symbol: &str) -> Option<f64> {
+    ELEMENTS
+        .iter()
+        .find(|(s, _)| *s == symbol)
+        .map(|(_, mass)| *mass)
+}
+
+#[derive(Debug, Clone)]
+enum Group {
+    Element(String, u32),
+    // a bracketed group like (OH)2, remembering which brackets it was written with
+    Nested(Vec<Group>, u32, char),
+}
+
+// A chemical formula like Ca(OH)2, CuSO4*5H2O or SO4^2-
+#[derive(Debug, Clone)]
+pub struct Formula {
+    // the parts of a hydrate, each with how many of it there are
+    parts: Vec<(u32, Vec<Group>)>,
+    charge: i32,
+}
+
+fn parse_count(chars: &mut Peekable<Chars>) -> Result<Option<u32>, CalcError> {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        digits.push(*c);
+        chars.next();
+    }
+    if digits.is_empty() {
+        return Ok(None);
+    }
+    digits
+        .parse()
+        .map(Some)
+        .map_err(|_| CalcError::Other(format!("{} is too many atoms", digits)))
+}
+
+fn parse_groups(chars: &mut Peekable<Chars>, formula: &str) -> Result<Vec<Group>, CalcError> {
+    let invalid = || CalcError::Other(format!("Invalid chemical formula {}", formula));
+    let mut groups = Vec::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            'A'..='Z' => {
+                chars.next();
+                let mut symbol = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_lowercase()) {
+                    symbol.push(*c);
+                    chars.next();
+                }
+                if atomic_mass(&symbol).is_none() {
+                    return Err(CalcError::Other(format!(
+                        "Unknown element {} in {}",
+                        symbol, formula
+                    )));
+                }
+                groups.push(Group::Element(symbol, parse_count(chars)?.unwrap_or(1)));
+            }
+            '(' | '[' => {
+                chars.next();
+                let inner = parse_groups(chars, formula)?;
+                let close = if c == '(' { ')' } else { ']' };
+                if chars.next() != Some(close) || inner.is_empty() {
+                    return Err(invalid());
+                }
+                groups.push(Group::Nested(inner, parse_count(chars)?.unwrap_or(1), c));
+            }
+            _ => break,
+        }
+    }
+    Ok(groups)
+}
+
+impl Formula {
+    pub fn parse(formula: &str) -> Result<Formula, CalcError> {
+        let invalid = || CalcError::Other(format!("Invalid chemical formula {}", formula));
+        let mut chars = formula.chars().peekable();
+
+        let mut parts = Vec::new();
+        loop {
+            let count = parse_count(&mut chars)?.unwrap_or(1);
+            let groups = parse_groups(&mut chars, formula)?;
+            if groups.is_empty() {
+                return Err(invalid());
+            }
+            parts.push((count, groups));
+
+            // hydrates like CuSO4*5H2O or CuSO4.5H2O
+            match chars.peek() {
+                Some('*' | '.' | '·') => {
+                    chars.next();
+                }
+                _ => break,
+            }
+        }
+
+        // charges like Fe^3+, SO4^2- or NH4+
+        chars.next_if_eq(&'^');
+        let size = parse_count(&mut chars)?.unwrap_or(1) as i32;
+        let charge = match chars.next() {
+            Some('+') => size,
+            Some('-') => -size,
+            None => 0,
+            Some(_) => return Err(invalid()),
+        };
+        if chars.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Formula { parts, charge })
+    }
+
+    // How many of each element there are
+    pub fn atoms(&self) -> Result<BTreeMap<String, u32>, CalcError> {
+        fn count(groups: &[Group], mult: u32, atoms: &mut BTreeMap<String, u32>) -> Option<()> {
+            for group in groups {
+                match group {
+                    Group::Element(symbol, n) => {
+                        let total = atoms.entry(symbol.clone()).or_insert(0);
+                        *total = total.checked_add(n.checked_mul(mult)?)?;
+                    }
+                    Group::Nested(inner, n, _) => count(inner, n.checked_mul(mult)?, atoms)?,
+                }
+            }
+            Some(())
+        }
+
+        let mut atoms = BTreeMap::new();
+        for (mult, groups) in &self.parts {
+            count(groups, *mult, &mut atoms)
+                .ok_or_else(|| CalcError::Other("Too many atoms in a formula".to_string()))?;
+        }
+        Ok(atoms)
+    }
+
+    // in g/mol, ignoring the mass of the electrons in a charge
+    pub fn molar_mass(&self) -> Result<f64, CalcError> {
+        Ok(self
+            .atoms()?
+            .iter()
+            .map(|(symbol, n)| atomic_mass(symbol).unwrap() * *n as f64)
+            .sum())
+    }
+}
+
+impl ToLaTeX for Formula {
+    fn to_latex_ext(&self, _: &FormatArgs) -> Result<LaTeX, CalcError> {
+        fn groups_latex(groups: &[Group]) -> String {
+            let subscript = |n: u32| {
+                if n == 1 {
+                    String::new()
+                } else {
+                    format!("_{{{}}}", n)
+                }
+            };
+            groups
+                .iter()
+                .map(|group| match group {
+                    Group::Element(symbol, n) => format!("{}{}", symbol, subscript(*n)),
+                    Group::Nested(inner, n, '[') => {
+                        format!("[{}]{}", groups_latex(inner), subscript(*n))
+                    }
+                    Group::Nested(inner, n, _) => {
+                        format!("({}){}", groups_latex(inner), subscript(*n))
+                    }
+                })
+                .collect()
+        }
+
+        let parts: Vec<String> = self
+            .parts
+            .iter()
+            .map(|(count, groups)| match count {
+                1 => groups_latex(groups),
+                n => format!("{}{}", n, groups_latex(groups)),
+            })
+            .collect();
+        let charge = match self.charge {
+            0 => String::new(),
+            1 => "^{+}".to_string(),
+            -1 => "^{-}".to_string(),
+            c if c > 0 => format!("^{{{}+}}", c),
+            c => format!("^{{{}-}}", -c),
+        };
+
+        Ok(LaTeX::Math(format!(
+            "\\mathrm{{{}{}}}",
+            parts.join(" \\cdot "),
+            charge
+        )))
+    }
+}
+
+pub fn is_chem_fn(name: &str) -> bool {
+    matches!(name, "molar_mass" | "atoms")
+}
+
+// A count of atoms or molecules, which is exact
+pub fn exact_count(n: u32) -> Val {
+    Val {
+        exact: Some(BigRational::from_integer(n.into())),
+        ..Val::empty(n as f64)
+    }
+}
+
+// The formula arguments of molar_mass and atoms are kept whole by the parser
+fn formula_arg(fc: &FnCall, i: usize) -> Result<&str, CalcError> {
+    match fc.args.get(i) {
+        Some(Expr::Formula(formula)) => Ok(formula),
+        _ => Err(CalcError::Other(format!(
+            "{} takes a chemical formula, like {}(H2SO4)",
+            fc.name, fc.name
+        ))),
+    }
+}
+
+// molar_mass(H2SO4) in g/mol, and atoms(H2SO4) or atoms(H2SO4, O) counting atoms
+pub fn eval_chem_fn(fc: &FnCall) -> Result<Value, CalcError> {
+    let formula = Formula::parse(formula_arg(fc, 0)?)?;
+
+    Ok(match (fc.name.as_str(), fc.args.len()) {
+        ("molar_mass", 1) => {
+            let unit = (Unit::try_from("g")? / Unit::try_from("mol")?)?;
+            Val::empty(formula.molar_mass()?)
+                .with_unit(&unit)?
+                .clamp_num()
+                .into()
+        }
+        ("atoms", 1) => {
+            let total = formula
+                .atoms()?
+                .values()
+                .try_fold(0u32, |total, n| total.checked_add(*n))
+                .ok_or_else(|| CalcError::Other("Too many atoms in a formula".to_string()))?;
+            exact_count(total).into()
+        }
+        ("atoms", 2) => {
+            let element = formula_arg(fc, 1)?;
+            if atomic_mass(element).is_none() {
+                return Err(CalcError::Other(format!("Unknown element {}", element)));
+            }
+            exact_count(formula.atoms()?.get(element).cloned().unwrap_or(0)).into()
+        }
+        (name, n) => {
+            return Err(CalcError::Other(format!(
+                "Incorrect number of arguments to function {}, got {}",
+                name, n
+            )))
+        }
+    })
+}
+
+// The coefficients making matrix * x = 0, if there's exactly one such x up to scaling.
+// The matrix is put in reduced row echelon form, which leaves one column without a pivot
+// to be set to 1, and the rest follow from it. Exact arithmetic keeps the coefficients whole.
+fn null_vector(mut matrix: Vec<Vec<BigRational>>, columns: usize) -> Option<Vec<BigInt>> {
+    let mut pivots = Vec::new();
+    for col in 0..columns {
+        let row = pivots.len();
+        let pivot = match (row..matrix.len()).find(|&r| !matrix[r][col].is_zero()) {
+            Some(pivot) => pivot,
+            None => continue,
+        };
+        matrix.swap(row, pivot);
+        let scale = matrix[row][col].clone();
+        matrix[row].iter_mut().for_each(|x| *x = &*x / &scale);
+        let pivot_row = matrix[row].clone();
+        for (_, other) in matrix.iter_mut().enumerate().filter(|(r, _)| *r != row) {
+            let factor = other[col].clone();
+            for (x, p) in other.iter_mut().zip(&pivot_row) {
+                *x = &*x - &factor * p;
+            }
+        }
+        pivots.push(col);
+    }
+
+    let free: Vec<usize> = (0..columns).filter(|c| !pivots.contains(c)).collect();
+    if free.len() != 1 {
+        return None;
+    }
+    let mut x = vec![BigRational::zero(); columns];
+    x[free[0]] = BigRational::one();
+    for (row, &col) in pivots.iter().enumerate() {
+        x[col] = -matrix[row][free[0]].clone();
+    }
+
+    let lcm = x.iter().fold(BigInt::one(), |lcm, v| lcm.lcm(v.denom()));
+    let ints: Vec<BigInt> = x.iter().map(|v| (v * &lcm).to_integer()).collect();
+    let gcd = ints.iter().fold(BigInt::zero(), |gcd, v| gcd.gcd(v));
+    Some(ints.into_iter().map(|v| v / &gcd).collect())
+}
+
+// A chemical species in a reaction, and how many of it react
+#[derive(Debug, Clone)]
+pub struct Species {
+    pub name: String,
+    pub formula: Formula,
+    pub coefficient: u32,
+}
+
+// A balanced reaction like 4Fe + 3O2 -> 2Fe2O3
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub reactants: Vec<Species>,
+    pub products: Vec<Species>,
+}
+
+impl Reaction {
+    // Finds the smallest whole number coefficients which conserve every element and the charge
+    pub fn balance(reactants: &[String], products: &[String]) -> Result<Reaction, CalcError> {
+        let equation = format!("{} -> {}", reactants.join(" + "), products.join(" + "));
+        let formulas = reactants
+            .iter()
+            .chain(products)
+            .map(|f| Formula::parse(f))
+            .collect::<Result<Vec<Formula>, CalcError>>()?;
+
+        // a row for each element and the charge, with products on the other side
+        let atoms = formulas
+            .iter()
+            .map(Formula::atoms)
+            .collect::<Result<Vec<BTreeMap<String, u32>>, CalcError>>()?;
+        let elements: BTreeSet<&String> = atoms.iter().flat_map(BTreeMap::keys).collect();
+        let sign = |i: usize| if i < reactants.len() { 1 } else { -1 };
+        let mut matrix: Vec<Vec<BigRational>> = elements
+            .iter()
+            .map(|e| {
+                atoms
+                    .iter()
+                    .enumerate()
+                    .map(|(i, a)| {
+                        let n = a.get(*e).cloned().unwrap_or(0) as i64;
+                        BigRational::from_integer((sign(i) * n).into())
+                    })
+                    .collect()
+            })
+            .collect();
+        matrix.push(
+            formulas
+                .iter()
+                .enumerate()
+                .map(|(i, f)| BigRational::from_integer((sign(i) * f.charge as i64).into()))
+                .collect(),
+        );
+
+        let coefficients = null_vector(matrix, formulas.len()).ok_or_else(|| {
+            CalcError::Other(format!(
+                "{} can't be balanced, or can be balanced in more than one way",
+                equation
+            ))
+        })?;
+        let negative = coefficients.iter().any(Signed::is_negative);
+        let coefficients = coefficients
+            .iter()
+            .map(|c| if negative { -c } else { c.clone() }.to_u32())
+            .collect::<Option<Vec<u32>>>()
+            .filter(|c| !c.contains(&0))
+            .ok_or_else(|| {
+                CalcError::Other(format!(
+                    "{} can't be balanced without a species going the other way",
+                    equation
+                ))
+            })?;
+
+        let mut species = reactants
+            .iter()
+            .chain(products)
+            .zip(formulas)
+            .zip(coefficients)
+            .map(|((name, formula), coefficient)| Species {
+                name: name.clone(),
+                formula,
+                coefficient,
+            });
+        Ok(Reaction {
+            reactants: species.by_ref().take(reactants.len()).collect(),
+            products: species.collect(),
+        })
+    }
+}
+
+impl ToLaTeX for Reaction {
+    fn to_latex_ext(&self, args: &FormatArgs) -> Result<LaTeX, CalcError> {
+        let side = |species: &[Species]| {
+            species
+                .iter()
+                .map(|s| {
+                    let formula = s.formula.to_latex_ext(args)?.to_string();
+                    Ok(match s.coefficient {
+                        1 => formula,
+                        n => format!("{}{}", n, formula),
+                    })
+                })
+                .collect::<Result<Vec<String>, CalcError>>()
+                .map(|s| s.join(" + "))
+        };
+        Ok(LaTeX::Math(format!(
+            "{} \\rightarrow {}",
+            side(&self.reactants)?,
+            side(&self.products)?
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mass(formula: &str) -> f64 {
+        Formula::parse(formula).unwrap().molar_mass().unwrap()
+    }
+
+    #[test]
+    fn molar_masses() {
+        assert!((mass("H2O") - 18.015).abs() < 1e-9);
+        assert!((mass("H2SO4") - 98.072).abs() < 1e-9);
+        assert!((mass("Ca(OH)2") - 74.092).abs() < 1e-9);
+        assert!((mass("CuSO4*5H2O") - 249.677).abs() < 1e-9);
+        assert!((mass("SO4^2-") - 96.056).abs() < 1e-9);
+        assert!(Formula::parse("Xy2").is_err());
+        assert!(Formula::parse("Ca(OH2").is_err());
+        assert!(Formula::parse("(H2000000000)3")
+            .unwrap()
+            .molar_mass()
+            .is_err());
+        assert!(Formula::parse("H99999999999").is_err());
+    }
+
+    #[test]
+    fn formulas() {
+        let latex = |f: &str| Formula::parse(f).unwrap().to_latex().unwrap().to_string();
+        assert_eq!(latex("H2SO4"), "\\mathrm{H_{2}SO_{4}}");
+        assert_eq!(latex("Ca(OH)2"), "\\mathrm{Ca(OH)_{2}}");
+        assert_eq!(latex("CuSO4.5H2O"), "\\mathrm{CuSO_{4} \\cdot 5H_{2}O}");
+        assert_eq!(latex("Fe^3+"), "\\mathrm{Fe^{3+}}");
+        assert_eq!(latex("NH4+"), "\\mathrm{NH_{4}^{+}}");
+
+        let atoms = Formula::parse("K4[Fe(CN)6]").unwrap().atoms().unwrap();
+        assert_eq!(atoms["C"], 6);
+        assert_eq!(atoms["K"], 4);
+    }
+
+    #[test]
+    fn balancing() {
+        let coefficients = |reactants: &[&str], products: &[&str]| {
+            let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            Reaction::balance(&strings(reactants), &strings(products)).map(|r| {
+                r.reactants
+                    .iter()
+                    .chain(&r.products)
+                    .map(|s| s.coefficient)
+                    .collect::<Vec<u32>>()
+            })
+        };
+        assert_eq!(coefficients(&["Fe", "O2"], &["Fe2O3"]).unwrap(), [4, 3, 2]);
+        assert_eq!(
+            coefficients(&["C3H8", "O2"], &["CO2", "H2O"]).unwrap(),
+            [1, 5, 3, 4]
+        );
+        assert_eq!(
+            coefficients(&["MnO4-", "Fe^2+", "H+"], &["Mn^2+", "Fe^3+", "H2O"]).unwrap(),
+            [1, 5, 8, 1, 5, 4]
+        );
+        assert!(coefficients(&["H2"], &["O2"]).is_err());
+        assert!(coefficients(&["H2", "O2"], &["H2O", "H2O2"]).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::expr::unit::{CustomUnits, Unit};
+use crate::expr::val::Val;
+use crate::latex::UnitHint;
+use crate::parser::naive_string::parse_naive_string;
+use crate::parser::unit::parse_unit_expr;
+use crate::parser::{MathParser, Rule};
+use crate::CalcError;
+use pest::Parser;
+
+// A physical constant, named by the LaTeX it's written as
+pub struct Constant {
+    pub name: &'static str,
+    // a plain name which is easier to type, for the ones written with a command
+    pub alias: Option<&'static str>,
+    pub value: f64,
+    // the standard uncertainty, which is 0 for constants defined exactly
+    pub uncertainty: f64,
+    pub unit: &'static str,
+}
+
+// CODATA 2018 recommended values
+pub const CONSTANTS: &[Constant] = &[
+    Constant {
+        name: "c",
+        alias: None,
+        value: 299_792_458.0,
+        uncertainty: 0.0,
+        unit: "m/s",
+    },
+    Constant {
+        name: "G",
+        alias: None,
+        value: 6.674_30e-11,
+        uncertainty: 0.000_15e-11,
+        unit: "m^3/(kg*s^2)",
+    },
+    Constant {
+        name: "h",
+        alias: None,
+        value: 6.626_070_15e-34,
+        uncertainty: 0.0,
+        unit: "J*s",
+    },
+    Constant {
+        name: "\\hbar",
+        alias: Some("hbar"),
+        value: 1.054_571_817e-34,
+        uncertainty: 0.0,
+        unit: "J*s",
+    },
+    Constant {
+        name: "k_B",
+        alias: None,
+        value: 1.380_649e-23,
+        uncertainty: 0.0,
+        unit: "J/K",
+    },
+    Constant {
+        name: "N_A",
+        alias: None,
+        value: 6.022_140_76e23,
+        uncertainty: 0.0,
+        unit: "1/mol",
+    },
+    Constant {
+        name: "R",
+        alias: None,
+        value: 8.314_462_618,
+        uncertainty: 0.0,
+        unit: "J/(mol*K)",
+    },
+    Constant {
+        name: "\\epsilon_0",
+        alias: Some("epsilon_0"),
+        value: 8.854_187_812_8e-12,
+        uncertainty: 0.000_000_001_3e-12,
+        unit: "F/m",
+    },
+    Constant {
+        name: "\\mu_0",
+        alias: Some("mu_0"),
+        value: 1.256_637_062_12e-6,
+        uncertainty: 0.000_000_000_19e-6,
+        unit: "N/A^2",
+    },
+    Constant {
+        name: "m_e",
+        alias: None,
+        value: 9.109_383_701_5e-31,
+        uncertainty: 0.000_000_002_8e-31,
+        unit: "kg",
+    },
+    Constant {
+        name: "m_p",
+        alias: None,
+        value: 1.672_621_923_69e-27,
+        uncertainty: 0.000_000_000_51e-27,
+        unit: "kg",
+    },
+    Constant {
+        name: "q_e",
+        alias: None,
+        value: 1.602_176_634e-19,
+        uncertainty: 0.0,
+        unit: "C",
+    },
+    Constant {
+        name: "\\sigma_{SB}",
+        alias: Some("sigma_SB"),
+        value: 5.670_374_419e-8,
+        uncertainty: 0.0,
+        unit: "W/(m^2*K^4)",
+    },
+    Constant {
+        name: "a_0",
+        alias: None,
+        value: 5.291_772_109_03e-11,
+        uncertainty: 0.000_000_000_80e-11,
+        unit: "m",
+    },
+    Constant {
+        name: "g_n",
+        alias: None,
+        value: 9.806_65,
+        uncertainty: 0.0,
+        unit: "m/s^2",
+    },
+];
+
+impl Constant {
+    fn parse_unit(unit: &str) -> Result<UnitHint, CalcError> {
+        let unit_expr = MathParser::parse(Rule::unit_expr, unit)?.next().unwrap();
+        Ok(UnitHint {
+            unit: parse_unit_expr(unit_expr.clone(), &CustomUnits::new())?.eval()?,
+            pretty_string: parse_naive_string(unit_expr)?,
+        })
+    }
+
+    // The unit the constant is written in, which it's also printed in. The unit
+    // parser doesn't take reciprocals like 1/mol on their own, so those have none.
+    pub fn unit_hint(&self) -> Result<Option<UnitHint>, CalcError> {
+        if self.unit.starts_with("1/") {
+            Ok(None)
+        } else {
+            Constant::parse_unit(self.unit).map(Some)
+        }
+    }
+
+    // The value with as many significant figures as it's known to, for printing.
+    // Uncertain values go down to the second significant figure of the uncertainty.
+    pub fn display_val(&self) -> Result<Val, CalcError> {
+        let magnitude = |x: f64| x.abs().log10().floor() as i32;
+        let uncertain_digits = if self.uncertainty == 0.0 {
+            0
+        } else {
+            (magnitude(self.value) - magnitude(self.uncertainty) + 2).max(0) as usize
+        };
+        let mantissa = format!("{:e}", self.value);
+        let digits = mantissa
+            .split('e')
+            .next()
+            .unwrap()
+            .chars()
+            .filter(char::is_ascii_digit)
+            .count()
+            .max(uncertain_digits);
+        Ok(Val {
+            sig_figs: Some(digits as u32),
+            ..self.val()?
+        })
+    }
+
+    pub fn val(&self) -> Result<Val, CalcError> {
+        let unit = match self.unit.strip_prefix("1/") {
+            Some(unit) => (Unit::empty() / Constant::parse_unit(unit)?.unit)?,
+            None => Constant::parse_unit(self.unit)?.unit,
+        };
+        Ok(Val {
+            uncertainty: self.uncertainty,
+            ..Val::empty(self.value)
+        }
+        .with_unit(&unit)?
+        .clamp_num())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constants() {
+        for constant in CONSTANTS {
+            let val = constant.val().unwrap();
+            let scale = match constant.unit_hint().unwrap() {
+                Some(hint) => hint.unit.scale(),
+                None => 1.0,
+            };
+            assert!((val.base_num() / scale / constant.value - 1.0).abs() < 1e-12);
+        }
+
+        assert!(crate::generate_latex("c = 3 m\n!import constants\n").is_err());
+        assert!(crate::generate_latex("!import constants\n!import constants\n").is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::expr::{unit::Unit, val::Val};
+use crate::parser::expr::parse_number;
+use crate::CalcError;
+use std::path::Path;
+
+// A column to read from a CSV file, and the unit its numbers are in
+#[derive(Debug, Clone)]
+pub struct ColumnSpec {
+    pub name: String,
+    pub unit: Option<Unit>,
+}
+
+// The cells of a row, where a quoted cell can have commas in it
+// and writes a quote as two quotes, like "a ""b"", c"
+fn split_row(line: &str) -> Vec<String> {
+    let mut cells = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cells.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(String::new()),
+            _ => cells.last_mut().unwrap().push(c),
+        }
+    }
+    cells.iter().map(|cell| cell.trim().to_string()).collect()
+}
+
+// Decimals keep their significant figures like numbers in the document,
+// anything else f64 can read, like 1.5e-3, is taken as it is
+fn parse_cell(cell: &str) -> Option<Val> {
+    let digits = cell.strip_prefix('-').unwrap_or(cell);
+    let (whole, fract) = digits.split_once('.').unwrap_or((digits, "0"));
+    let is_decimal = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if is_decimal(whole) && is_decimal(fract) {
+        Some(parse_number(cell))
+    } else {
+        cell.parse::<f64>().ok().map(Val::empty)
+    }
+}
+
+// Reads the columns of a CSV file. A first row which isn't all numbers is a header,
+// and columns are picked out by name from it, otherwise they're taken in order.
+// Without any columns given, every column in the header is read without a unit.
+pub fn load_csv(path: &Path, columns: &[ColumnSpec]) -> Result<Vec<(String, Vec<Val>)>, CalcError> {
+    let file_name = path.display();
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| CalcError::Other(format!("Couldn't read {}: {}", file_name, e)))?;
+
+    // rows are numbered by their line in the file
+    let mut rows = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty())
+        .peekable();
+
+    let header = match rows.peek() {
+        Some((_, line)) => {
+            let cells = split_row(line);
+            if cells.iter().all(|c| parse_cell(c).is_some()) {
+                None
+            } else {
+                rows.next();
+                Some(cells)
+            }
+        }
+        None => return Err(CalcError::Other(format!("{} is empty", file_name))),
+    };
+
+    let all_columns: Vec<ColumnSpec>;
+    let columns = match (&header, columns.is_empty()) {
+        (Some(header), true) => {
+            all_columns = header
+                .iter()
+                .map(|name| ColumnSpec {
+                    name: name.clone(),
+                    unit: None,
+                })
+                .collect();
+            &all_columns
+        }
+        (None, true) => {
+            return Err(CalcError::Other(format!(
+                "{} has no header, name its columns like `with [t: s, x: m]`",
+                file_name
+            )))
+        }
+        (_, false) => columns,
+    };
+
+    let width = match &header {
+        Some(h) => h.len(),
+        None => split_row(rows.peek().unwrap().1).len(),
+    };
+    let indices = match &header {
+        Some(header) => columns
+            .iter()
+            .map(|c| {
+                header.iter().position(|h| h == &c.name).ok_or_else(|| {
+                    CalcError::Other(format!(
+                        "{} has no column {}, its columns are {}",
+                        file_name,
+                        c.name,
+                        header.join(", ")
+                    ))
+                })
+            })
+            .collect::<Result<Vec<usize>, CalcError>>()?,
+        None if columns.len() == width => (0..width).collect(),
+        None => {
+            return Err(CalcError::Other(format!(
+                "{} has {} columns but {} were named, give every column a name or add a header",
+                file_name,
+                width,
+                columns.len()
+            )))
+        }
+    };
+
+    let mut data = vec![Vec::new(); columns.len()];
+    for (row, line) in rows {
+        let cells = split_row(line);
+        if cells.len() != width {
+            return Err(CalcError::Other(format!(
+                "{} row {}: expected {} values but got {}",
+                file_name,
+                row,
+                width,
+                cells.len()
+            )));
+        }
+
+        for ((spec, &i), column) in columns.iter().zip(&indices).zip(data.iter_mut()) {
+            let val = parse_cell(&cells[i]).ok_or_else(|| {
+                CalcError::Other(format!(
+                    "{} row {}: {} in column {} isn't a number",
+                    file_name, row, cells[i], spec.name
+                ))
+            })?;
+            column.push(match &spec.unit {
+                Some(unit) => val.with_unit(unit)?.clamp_num(),
+                None => val,
+            });
+        }
+    }
+
+    Ok(columns.iter().map(|c| c.name.clone()).zip(data).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_errors() {
+        let columns = [
+            ColumnSpec {
+                name: "t".to_string(),
+                unit: None,
+            },
+            ColumnSpec {
+                name: "x".to_string(),
+                unit: None,
+            },
+        ];
+        let err = load_csv(Path::new("test_files/bad_row.csv"), &columns).unwrap_err();
+        assert!(err.to_string().contains("row 3"));
+
+        let t = ColumnSpec {
+            name: "time".to_string(),
+            unit: None,
+        };
+        let data = load_csv(Path::new("test_files/cart.csv"), &[t]).unwrap();
+        assert_eq!(data[0].1.len(), 5);
+        assert!(load_csv(Path::new("test_files/missing.csv"), &columns).is_err());
+    }
+
+    #[test]
+    fn quoted_cells() {
+        assert_eq!(split_row(r#"1,"2,5""#), ["1", "2,5"]);
+        assert_eq!(
+            split_row(r#" "time, s" , "say ""hi""" "#),
+            ["time, s", r#"say "hi""#]
+        );
+        assert_eq!(split_row("1,,2"), ["1", "", "2"]);
+    }
+}
@@ -6,7 +6,7 @@ use crate::parser::Rule;
 
 #[derive(Debug)]
 pub enum CalcError {
-    ParseError(PestError<Rule>),
+    ParseError(Box<PestError<Rule>>),
     UnitError(String),
     MathError,
     Other(String),
@@ -57,7 +57,7 @@ impl Error for CalcError {
 
 impl From<PestError<Rule>> for CalcError {
     fn from(e: PestError<Rule>) -> Self {
-        CalcError::ParseError(e)
+        CalcError::ParseError(Box::new(e))
     }
 }
 
@@ -1,10 +1,14 @@
+pub mod symbolic;
 pub mod val;
+pub mod value;
 
+use crate::latex::UnitHint;
+use crate::parser::naive_string::StringExpr;
 use crate::{function::eval_fn_call, parser::fn_call::FnCall};
 use std::collections::BTreeMap;
-use std::convert::TryFrom;
 
 use val::*;
+use value::{eval_linalg_fn, Value};
 
 pub mod unit;
 use unit::*;
@@ -21,6 +25,9 @@ pub enum Expr {
     Ident(String),
     FnCall(FnCall),
     Cons(Op, Vec<Expr>),
+    Vector(Vec<Expr>),
+    // a chemical formula given to molar_mass or atoms, like H2SO4
+    Formula(String),
 }
 
 impl std::fmt::Display for Expr {
@@ -31,21 +38,23 @@ impl std::fmt::Display for Expr {
             Expr::Ident(n) => write!(f, "{}", n),
             Expr::FnCall(fc) => write!(f, "{:?}", fc),
             Expr::Cons(op, e) => write!(f, "({:?}, {:?})", op, e),
+            Expr::Vector(es) => write!(f, "{:?}", es),
+            Expr::Formula(s) => write!(f, "{}", s),
         }
     }
 }
 
 impl Expr {
-    pub fn eval(&self, scope: &Scope) -> Result<Val, CalcError> {
+    pub fn eval(&self, scope: &Scope) -> Result<Value, CalcError> {
         let e = |a: &Expr| a.eval(scope);
         Ok(match self {
-            Expr::Atom(v) => v.clamp_num(),
+            Expr::Atom(v) => v.clamp_num().into(),
             Expr::ParenExpr(ex) => e(ex)?,
             Expr::Ident(n) => {
-                if let Some(v) = scope.variables.get(n) {
+                if let Some(v) = scope.variable(n) {
                     v.clone()
                 } else {
-                    (1.0, Unit::try_from(n)?).into()
+                    Value::Scalar((1.0, scope.unit(n)?).into())
                 }
             }
             Expr::FnCall(fc) => eval_fn_call(fc, scope)?,
@@ -53,15 +62,54 @@ impl Expr {
                 (Op::Plus, [a, b]) => (e(a)? + e(b)?)?,
                 (Op::Minus, [a, b]) => (e(a)? - e(b)?)?,
                 (Op::Minus, [a]) => (-e(a)?),
-                (Op::Mul, [a, b]) => e(a)? * e(b)?,
-                (Op::Div, [a, b]) => e(a)? / e(b)?,
-                (Op::Exp, [a, b]) => e(a)?.pow(&e(b)?),
-                (Op::AddUnit(u, _), [v]) => e(v)?.with_unit(&u),
+                (Op::Mul, [a, b]) => (e(a)? * e(b)?)?,
+                (Op::Div, [a, b]) => (e(a)? / e(b)?)?,
+                (Op::Exp, [a, b]) => e(a)?.pow(&e(b)?)?,
+                (Op::PlusMinus, [a, b]) => e(a)?.plus_minus(&e(b)?)?,
+                (Op::Dot, [a, b]) => e(a)?.dot(e(b)?)?,
+                (Op::Cross, [a, b]) => e(a)?.cross(e(b)?)?,
+                // |F| is the length of a vector, or the absolute value of anything else
+                (Op::Norm, [a]) => match e(a)? {
+                    v @ Value::Vector(_) => eval_linalg_fn("norm", vec![v])?,
+                    _ => eval_fn_call(
+                        &FnCall {
+                            name: "abs".to_string(),
+                            args: xs.clone(),
+                        },
+                        scope,
+                    )?,
+                },
+                (Op::AddUnit(u, _), [v]) => e(v)?.with_unit(&u)?,
                 _ => return Err(CalcError::MathError),
             },
+            Expr::Vector(es) => Value::from_elements(
+                es.iter()
+                    .map(e)
+                    .collect::<Result<Vec<Value>, CalcError>>()?,
+            )?,
+            Expr::Formula(f) => {
+                return Err(CalcError::Other(format!(
+                    "The formula {} can only be given to molar_mass or atoms",
+                    f
+                )))
+            }
         })
     }
 
+    // The unit a literal like `5 min` is written in, so values that
+    // come from it can be shown in the same unit
+    pub fn unit_hint(&self) -> Option<UnitHint> {
+        match self {
+            Expr::ParenExpr(e) => e.unit_hint(),
+            Expr::Cons(Op::Minus, xs) if xs.len() == 1 => xs[0].unit_hint(),
+            Expr::Cons(Op::AddUnit(unit, s), _) => Some(UnitHint {
+                unit: unit.clone(),
+                pretty_string: StringExpr::Atom(s.clone()),
+            }),
+            _ => None,
+        }
+    }
+
     pub fn remove_parens(&self) -> Self {
         if let Expr::ParenExpr(b) = self {
             *b.clone()
@@ -77,7 +125,9 @@ impl Expr {
             Expr::FnCall(FnCall { args, .. }) => {
                 args.iter_mut().for_each(|e| e.resolve_aliases(aliases))
             }
-            Expr::Cons(_, exprs) => exprs.iter_mut().for_each(|e| e.resolve_aliases(aliases)),
+            Expr::Cons(_, exprs) | Expr::Vector(exprs) => {
+                exprs.iter_mut().for_each(|e| e.resolve_aliases(aliases))
+            }
             _ => {}
         }
     }
@@ -90,5 +140,9 @@ pub enum Op {
     Mul,
     Div,
     Exp,
+    PlusMinus,
+    Dot,
+    Cross,
+    Norm,
     AddUnit(Unit, String),
 }
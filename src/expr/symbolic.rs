@@ -0,0 +1,953 @@
+use std::collections::BTreeMap;
+
+use num::rational::BigRational;
+use num::rational::Ratio;
+use num::{CheckedAdd, CheckedMul, CheckedSub, One, Signed, ToPrimitive, Zero};
+
+use super::{unit::Unit, val::Val, value::Value, Expr, Op};
+use crate::error::CalcError;
+use crate::parser::fn_call::FnCall;
+use crate::statement::Scope;
+
+// The maximum depth of user defined functions that are inlined, past
+// this they're assumed to be recursive
+const MAX_INLINE_DEPTH: usize = 128;
+
+// A whole number or fraction as an expression, fractions are written
+// as a division so they print as \frac rather than a rounded decimal
+fn num(r: BigRational) -> Expr {
+    if r.is_negative() {
+        return neg(num(-r));
+    }
+
+    let int = |n: &num::BigInt| {
+        Expr::Atom(Val {
+            exact: Some(BigRational::from_integer(n.clone())),
+            ..Val::empty(n.to_f64().unwrap_or(f64::NAN))
+        })
+    };
+    if r.is_integer() {
+        int(r.numer())
+    } else {
+        Expr::Cons(Op::Div, vec![int(r.numer()), int(r.denom())])
+    }
+}
+
+fn int(n: i64) -> Expr {
+    num(BigRational::from_integer(n.into()))
+}
+
+fn call(name: &str, arg: Expr) -> Expr {
+    Expr::FnCall(FnCall {
+        name: name.to_string(),
+        args: vec![arg],
+    })
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    Expr::Cons(Op::Plus, vec![a, b])
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    Expr::Cons(Op::Minus, vec![a, b])
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    Expr::Cons(Op::Mul, vec![a, b])
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    Expr::Cons(Op::Div, vec![a, b])
+}
+
+fn pow(a: Expr, b: Expr) -> Expr {
+    Expr::Cons(Op::Exp, vec![a, b])
+}
+
+fn neg(a: Expr) -> Expr {
+    Expr::Cons(Op::Minus, vec![a])
+}
+
+// The value of a unitless constant like 2 or -1/2, if it's known exactly
+fn constant(e: &Expr) -> Option<BigRational> {
+    match e {
+        Expr::Atom(v) if v.unit.desc.is_empty() && v.imag == 0.0 && v.uncertainty == 0.0 => {
+            v.exact.clone()
+        }
+        Expr::ParenExpr(a) => constant(a),
+        Expr::Cons(Op::Minus, a) if a.len() == 1 => constant(&a[0]).map(|r| -r),
+        _ => None,
+    }
+}
+
+fn is_constant(e: &Expr, n: i64) -> bool {
+    constant(e) == Some(BigRational::from_integer(n.into()))
+}
+
+fn depends_on(e: &Expr, var: &str) -> bool {
+    match e {
+        Expr::Atom(_) | Expr::Formula(_) => false,
+        Expr::Ident(n) => n == var,
+        Expr::ParenExpr(a) => depends_on(a, var),
+        Expr::FnCall(FnCall { args, .. }) | Expr::Cons(_, args) | Expr::Vector(args) => {
+            args.iter().any(|a| depends_on(a, var))
+        }
+    }
+}
+
+// Replaces variables with expressions, used to inline user defined functions
+fn substitute(e: &Expr, values: &BTreeMap<&str, &Expr>) -> Expr {
+    let sub = |a: &Expr| substitute(a, values);
+    match e {
+        Expr::Ident(n) => match values.get(n.as_str()) {
+            Some(v) => Expr::ParenExpr(Box::new((*v).clone())),
+            None => e.clone(),
+        },
+        Expr::Atom(_) | Expr::Formula(_) => e.clone(),
+        Expr::ParenExpr(a) => Expr::ParenExpr(Box::new(sub(a))),
+        Expr::FnCall(fc) => Expr::FnCall(FnCall {
+            name: fc.name.clone(),
+            args: fc.args.iter().map(sub).collect(),
+        }),
+        Expr::Cons(op, args) => Expr::Cons(op.clone(), args.iter().map(sub).collect()),
+        Expr::Vector(es) => Expr::Vector(es.iter().map(sub).collect()),
+    }
+}
+
+// The derivative of a builtin with respect to its argument u
+fn builtin_derivative(name: &str, u: &Expr) -> Option<Expr> {
+    let u = || u.clone();
+    let one_minus_u2 = || sub(int(1), pow(u(), int(2)));
+    Some(match name {
+        "sin" => call("cos", u()),
+        "cos" => neg(call("sin", u())),
+        "tan" => div(int(1), pow(call("cos", u()), int(2))),
+        "exp" => call("exp", u()),
+        "ln" => div(int(1), u()),
+        "log10" => div(int(1), mul(u(), call("ln", int(10)))),
+        "log2" => div(int(1), mul(u(), call("ln", int(2)))),
+        "sqrt" => div(int(1), mul(int(2), call("sqrt", u()))),
+        "cbrt" => div(int(1), mul(int(3), pow(call("cbrt", u()), int(2)))),
+        "asin" => div(int(1), call("sqrt", one_minus_u2())),
+        "acos" => neg(div(int(1), call("sqrt", one_minus_u2()))),
+        "atan" => div(int(1), add(int(1), pow(u(), int(2)))),
+        "sinh" => call("cosh", u()),
+        "cosh" => call("sinh", u()),
+        "tanh" => div(int(1), pow(call("cosh", u()), int(2))),
+        "asinh" => div(int(1), call("sqrt", add(pow(u(), int(2)), int(1)))),
+        "acosh" => div(int(1), call("sqrt", sub(pow(u(), int(2)), int(1)))),
+        "atanh" => div(int(1), one_minus_u2()),
+        "abs" => div(u(), call("abs", u())),
+        _ => return None,
+    })
+}
+
+// The derivative of `e` with respect to `var`, which isn't simplified
+pub fn differentiate(e: &Expr, var: &str, scope: &Scope) -> Result<Expr, CalcError> {
+    // a term that doesn't depend on var disappears from the derivative,
+    // so a sum like x^2 + 1 m has to be checked before it's lost
+    let mut var_unit = match scope.variable(var) {
+        Some(Value::Scalar(v)) => Some(unit_of(&v.unit)),
+        _ => None,
+    };
+    units(e, var, scope, &mut var_unit, 0)?;
+    derive(e, var, scope, 0)
+}
+
+// The unit `var` must have for the terms of `e` to add up, like m/s for v in
+// v^2 / (2 g) - 20 m, or None if nothing in `e` pins it down
+pub fn unknown_unit(e: &Expr, var: &str, scope: &Scope) -> Result<Option<Unit>, CalcError> {
+    let mut var_unit = None;
+    units(e, var, scope, &mut var_unit, 0)?;
+    Ok(var_unit)
+}
+
+fn unit_of(u: &Unit) -> Unit {
+    Unit {
+        desc: u.desc.clone(),
+        ..Unit::empty()
+    }
+}
+
+// The units of `e` as a unit times the unknown unit of `var` to a power,
+// or None if they can't be worked out. Sums fix the unit of `var` in
+// `var_unit` the first time they need it, and are an error if their
+// terms can't have the same units.
+fn units(
+    e: &Expr,
+    var: &str,
+    scope: &Scope,
+    var_unit: &mut Option<Unit>,
+    depth: usize,
+) -> Result<Option<(Unit, Ratio<i8>)>, CalcError> {
+    let u = |a: &Expr, var_unit: &mut Option<Unit>| units(a, var, scope, var_unit, depth);
+    if !depends_on(e, var) {
+        return Ok(match e.eval(scope) {
+            Ok(Value::Scalar(v)) => Some((unit_of(&v.unit), Ratio::zero())),
+            _ => None,
+        });
+    }
+
+    Ok(match e {
+        Expr::Ident(_) => Some(match var_unit {
+            Some(unit) => (unit.clone(), Ratio::zero()),
+            None => (Unit::empty(), Ratio::one()),
+        }),
+        Expr::ParenExpr(a) => u(a, var_unit)?,
+        Expr::Cons(op, args) => match (op, args.as_slice()) {
+            (Op::Minus, [a]) => u(a, var_unit)?,
+            (Op::Plus | Op::Minus, [a, b]) => {
+                let (a, b) = match (u(a, var_unit)?, u(b, var_unit)?) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Ok(None),
+                };
+                let known = |(unit, p): (Unit, Ratio<i8>), var_unit: &Option<Unit>| match var_unit {
+                    Some(v) if !p.is_zero() => Ok(((unit * v.pow(p)?)?, Ratio::zero())),
+                    _ => Ok::<_, CalcError>((unit, p)),
+                };
+                let ((a, pa), (b, pb)) = (known(a, var_unit)?, known(b, var_unit)?);
+                let mismatch = || {
+                    let term = |unit: &Unit, p: &Ratio<i8>| match (unit.desc.is_empty(), p) {
+                        (_, p) if p.is_zero() => unit.to_string(),
+                        (true, p) => format!("{}^{}", var, p),
+                        (false, p) => format!("{} {}^{}", unit, var, p),
+                    };
+                    Err(CalcError::UnitError(format!(
+                        "Can't add terms with units {} and {}",
+                        term(&a, &pa),
+                        term(&b, &pb)
+                    )))
+                };
+                if pa != pb {
+                    // a unit of var that makes the powers match, like s for t^2 m/s^2 + t m/s,
+                    // which has to be a whole power of each unit to make sense
+                    let unit = match pa
+                        .checked_sub(&pb)
+                        .and_then(|p| (b.clone() / a.clone()).ok()?.pow(p.recip()).ok())
+                    {
+                        Some(unit) => unit,
+                        None => return Ok(None),
+                    };
+                    if unit.desc.powers().iter().any(|(_, p)| !p.is_integer()) {
+                        return mismatch();
+                    }
+                    match unit.pow(pa).and_then(|unit_pa| a * unit_pa) {
+                        Ok(a) => {
+                            *var_unit = Some(unit);
+                            Some((a, Ratio::zero()))
+                        }
+                        Err(_) => None,
+                    }
+                } else if a.desc != b.desc {
+                    return mismatch();
+                } else {
+                    Some((a, pa))
+                }
+            }
+            (Op::Mul | Op::Dot | Op::Cross, [a, b]) => match (u(a, var_unit)?, u(b, var_unit)?) {
+                (Some((a, pa)), Some((b, pb))) => match (a * b, pa.checked_add(&pb)) {
+                    (Ok(unit), Some(p)) => Some((unit, p)),
+                    _ => None,
+                },
+                _ => None,
+            },
+            (Op::Div, [a, b]) => match (u(a, var_unit)?, u(b, var_unit)?) {
+                (Some((a, pa)), Some((b, pb))) => match (a / b, pa.checked_sub(&pb)) {
+                    (Ok(unit), Some(p)) => Some((unit, p)),
+                    _ => None,
+                },
+                _ => None,
+            },
+            (Op::Exp, [a, b]) => {
+                let p = constant(b)
+                    .and_then(|r| Some(Ratio::new(r.numer().to_i8()?, r.denom().to_i8()?)));
+                match (u(a, var_unit)?, p) {
+                    (Some((a, pa)), Some(p)) => match (a.pow(p), pa.checked_mul(&p)) {
+                        (Ok(a), Some(pa)) => Some((a, pa)),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }
+            (Op::AddUnit(unit, _), [a]) => match u(a, var_unit)? {
+                Some((a, pa)) => (a * unit_of(unit)).ok().map(|a| (a, pa)),
+                None => None,
+            },
+            _ => None,
+        },
+        Expr::FnCall(fc) => match scope.function(&fc.name) {
+            Some(fn_def) if depth < MAX_INLINE_DEPTH => {
+                let values = fn_def
+                    .params
+                    .iter()
+                    .map(String::as_str)
+                    .zip(fc.args.iter())
+                    .collect();
+                units(
+                    &substitute(&fn_def.body, &values),
+                    var,
+                    scope,
+                    var_unit,
+                    depth + 1,
+                )?
+            }
+            _ => None,
+        },
+        Expr::Atom(_) | Expr::Vector(_) | Expr::Formula(_) => None,
+    })
+}
+
+// The simplified result of a call like diff(x^2, x)
+pub fn eval_diff(fc: &FnCall, scope: &Scope) -> Result<Expr, CalcError> {
+    match fc.args.as_slice() {
+        [e, Expr::Ident(var)] => {
+            let e = expand_diffs(e, scope)?.unwrap_or_else(|| e.clone());
+            Ok(simplify(&differentiate(&e, var, scope)?))
+        }
+        _ => Err(CalcError::Other(
+            "diff takes an expression and a variable, like diff(x^2, x)".to_string(),
+        )),
+    }
+}
+
+// The variables every call to diff is with respect to
+pub fn diff_vars(e: &Expr) -> Vec<&str> {
+    match e {
+        Expr::FnCall(fc) => match (fc.name.as_str(), fc.args.as_slice()) {
+            ("diff", [a, Expr::Ident(var)]) => {
+                let mut vars = diff_vars(a);
+                vars.push(var);
+                vars
+            }
+            _ => fc.args.iter().flat_map(diff_vars).collect(),
+        },
+        Expr::ParenExpr(a) => diff_vars(a),
+        Expr::Cons(_, args) | Expr::Vector(args) => args.iter().flat_map(diff_vars).collect(),
+        Expr::Atom(_) | Expr::Ident(_) | Expr::Formula(_) => vec![],
+    }
+}
+
+// Replaces every call to diff with its result, or None if there aren't any
+pub fn expand_diffs(e: &Expr, scope: &Scope) -> Result<Option<Expr>, CalcError> {
+    let expand_all = |es: &[Expr]| -> Result<Option<Vec<Expr>>, CalcError> {
+        let expanded: Vec<Option<Expr>> = es
+            .iter()
+            .map(|a| expand_diffs(a, scope))
+            .collect::<Result<_, _>>()?;
+        Ok(if expanded.iter().all(Option::is_none) {
+            None
+        } else {
+            Some(
+                expanded
+                    .into_iter()
+                    .zip(es)
+                    .map(|(new, old)| new.unwrap_or_else(|| old.clone()))
+                    .collect(),
+            )
+        })
+    };
+
+    Ok(match e {
+        Expr::FnCall(fc) if fc.name == "diff" => {
+            let derivative = eval_diff(fc, scope)?;
+            Some(if matches!(derivative, Expr::Cons(_, _)) {
+                Expr::ParenExpr(Box::new(derivative))
+            } else {
+                derivative
+            })
+        }
+        Expr::FnCall(fc) => expand_all(&fc.args)?.map(|args| {
+            Expr::FnCall(FnCall {
+                name: fc.name.clone(),
+                args,
+            })
+        }),
+        Expr::ParenExpr(a) => expand_diffs(a, scope)?.map(|a| Expr::ParenExpr(Box::new(a))),
+        Expr::Cons(op, args) => expand_all(args)?.map(|args| Expr::Cons(op.clone(), args)),
+        Expr::Vector(es) => expand_all(es)?.map(Expr::Vector),
+        Expr::Atom(_) | Expr::Ident(_) | Expr::Formula(_) => None,
+    })
+}
+
+fn derive(e: &Expr, var: &str, scope: &Scope, depth: usize) -> Result<Expr, CalcError> {
+    let d = |a: &Expr| derive(a, var, scope, depth);
+    Ok(match e {
+        Expr::Atom(_) | Expr::Formula(_) => int(0),
+        Expr::Ident(n) => int((n == var) as i64),
+        Expr::ParenExpr(a) => d(a)?,
+        Expr::Vector(es) => Expr::Vector(es.iter().map(d).collect::<Result<_, _>>()?),
+        Expr::Cons(op, args) => match (op, args.as_slice()) {
+            (Op::Plus, [a, b]) => add(d(a)?, d(b)?),
+            (Op::Minus, [a, b]) => sub(d(a)?, d(b)?),
+            (Op::Minus, [a]) => neg(d(a)?),
+            (Op::Mul, [a, b]) => add(mul(d(a)?, b.clone()), mul(a.clone(), d(b)?)),
+            (Op::Dot | Op::Cross, [a, b]) => add(
+                Expr::Cons(op.clone(), vec![d(a)?, b.clone()]),
+                Expr::Cons(op.clone(), vec![a.clone(), d(b)?]),
+            ),
+            (Op::Div, [a, b]) => div(
+                sub(mul(d(a)?, b.clone()), mul(a.clone(), d(b)?)),
+                pow(b.clone(), int(2)),
+            ),
+            // power rule, exponential rule, and the general case
+            (Op::Exp, [a, b]) if !depends_on(b, var) => mul(
+                mul(b.clone(), pow(a.clone(), sub(b.clone(), int(1)))),
+                d(a)?,
+            ),
+            (Op::Exp, [a, b]) if !depends_on(a, var) => {
+                mul(mul(e.clone(), call("ln", a.clone())), d(b)?)
+            }
+            (Op::Exp, [a, b]) => mul(
+                e.clone(),
+                add(
+                    mul(d(b)?, call("ln", a.clone())),
+                    div(mul(b.clone(), d(a)?), a.clone()),
+                ),
+            ),
+            (Op::AddUnit(_, _), [v]) => Expr::Cons(op.clone(), vec![d(v)?]),
+            (Op::PlusMinus, _) => {
+                return Err(CalcError::Other(
+                    "Can't differentiate a value with an uncertainty".to_string(),
+                ))
+            }
+            _ => return Err(CalcError::MathError),
+        },
+        Expr::FnCall(fc) => {
+            if let Some(fn_def) = scope.function(&fc.name) {
+                if depth >= MAX_INLINE_DEPTH {
+                    return Err(CalcError::Other(format!(
+                        "Function {} is recursive",
+                        fc.name
+                    )));
+                }
+
+                let values = fn_def
+                    .params
+                    .iter()
+                    .map(String::as_str)
+                    .zip(fc.args.iter())
+                    .collect();
+                derive(&substitute(&fn_def.body, &values), var, scope, depth + 1)?
+            } else if !fc.args.iter().any(|a| depends_on(a, var)) {
+                int(0)
+            } else {
+                match (
+                    builtin_derivative(&fc.name, &fc.args[0]),
+                    fc.args.as_slice(),
+                ) {
+                    (Some(outer), [u]) => mul(outer, d(u)?),
+                    _ => return Err(CalcError::Other(format!("Can't differentiate {}", fc.name))),
+                }
+            }
+        }
+    })
+}
+
+// Simplifies an expression until it stops changing, and adds the
+// parentheses needed to print it since the tree has none of its own
+pub fn simplify(e: &Expr) -> Expr {
+    let mut e = strip_parens(e);
+    for _ in 0..32 {
+        let next = simplify_once(&e);
+        if format!("{:?}", next) == format!("{:?}", e) {
+            break;
+        }
+        e = next;
+    }
+    parenthesize(&e)
+}
+
+fn strip_parens(e: &Expr) -> Expr {
+    match e {
+        Expr::ParenExpr(a) => strip_parens(a),
+        Expr::FnCall(fc) => Expr::FnCall(FnCall {
+            name: fc.name.clone(),
+            args: fc.args.iter().map(strip_parens).collect(),
+        }),
+        Expr::Cons(op, args) => Expr::Cons(op.clone(), args.iter().map(strip_parens).collect()),
+        Expr::Vector(es) => Expr::Vector(es.iter().map(strip_parens).collect()),
+        _ => e.clone(),
+    }
+}
+
+fn simplify_once(e: &Expr) -> Expr {
+    let args = match e {
+        Expr::Cons(_, args) => args.iter().map(simplify_once).collect::<Vec<_>>(),
+        Expr::FnCall(fc) => {
+            let args: Vec<Expr> = fc.args.iter().map(simplify_once).collect();
+            return match (fc.name.as_str(), args.as_slice()) {
+                ("ln", [Expr::Ident(n)]) if n == "e" => int(1),
+                ("ln", [a]) | ("exp", [a])
+                    if is_constant(a, if fc.name == "ln" { 1 } else { 0 }) =>
+                {
+                    int((fc.name == "exp") as i64)
+                }
+                _ => Expr::FnCall(FnCall {
+                    name: fc.name.clone(),
+                    args,
+                }),
+            };
+        }
+        Expr::Vector(es) => return Expr::Vector(es.iter().map(simplify_once).collect()),
+        _ => return e.clone(),
+    };
+    let op = match e {
+        Expr::Cons(op, _) => op,
+        _ => unreachable!(),
+    };
+
+    // constant folding
+    let consts: Option<Vec<BigRational>> = args.iter().map(constant).collect();
+    if let Some(c) = consts {
+        let folded = match (op, c.as_slice()) {
+            (Op::Plus, [a, b]) => Some(a + b),
+            (Op::Minus, [a, b]) => Some(a - b),
+            (Op::Minus, [a]) => Some(-a),
+            (Op::Mul, [a, b]) => Some(a * b),
+            (Op::Div, [a, b]) if !b.is_zero() => Some(a / b),
+            (Op::Exp, [a, b])
+                if b.is_integer() && b.abs() <= BigRational::from_integer(64.into()) =>
+            {
+                let p = b.to_integer().to_i32().unwrap();
+                if a.is_zero() && p < 0 {
+                    None
+                } else {
+                    Some(num::pow::Pow::pow(a, p))
+                }
+            }
+            _ => None,
+        };
+        if let Some(r) = folded {
+            return num(r);
+        }
+    }
+
+    match (op, args.as_slice()) {
+        (Op::Plus, [_, _]) | (Op::Minus, [_, _]) => collect_terms(Expr::Cons(op.clone(), args)),
+        (Op::Minus, [a]) => match a {
+            Expr::Cons(Op::Minus, inner) if inner.len() == 1 => inner[0].clone(),
+            _ if is_constant(a, 0) => int(0),
+            _ => neg(a.clone()),
+        },
+        // fractions are moved outwards, so a (b / c) = a b / c
+        (Op::Mul, [Expr::Cons(Op::Div, inner), c]) => {
+            div(mul(inner[0].clone(), c.clone()), inner[1].clone())
+        }
+        (Op::Mul, [a, Expr::Cons(Op::Div, inner)]) => {
+            div(mul(a.clone(), inner[0].clone()), inner[1].clone())
+        }
+        (Op::Mul, [_, _]) => collect_factors(Expr::Cons(op.clone(), args)),
+        // a / (b / c) = a c / b
+        (Op::Div, [a, Expr::Cons(Op::Div, inner)]) => {
+            div(mul(a.clone(), inner[1].clone()), inner[0].clone())
+        }
+        // (a / b) / c = a / (b c)
+        (Op::Div, [Expr::Cons(Op::Div, inner), c]) => {
+            div(inner[0].clone(), mul(inner[1].clone(), c.clone()))
+        }
+        (Op::Div, [a, b]) => cancel(a, b),
+        // zero is zero in any unit
+        (Op::AddUnit(_, _), [a]) if is_constant(a, 0) => int(0),
+        (Op::Exp, [_, b]) if is_constant(b, 0) => int(1),
+        (Op::Exp, [a, b]) if is_constant(b, 1) => a.clone(),
+        (Op::Exp, [a, _]) if is_constant(a, 1) => int(1),
+        // (a^b)^c = a^(b c)
+        (Op::Exp, [Expr::Cons(Op::Exp, inner), c]) => {
+            pow(inner[0].clone(), mul(inner[1].clone(), c.clone()))
+        }
+        _ => Expr::Cons(op.clone(), args),
+    }
+}
+
+// Splits a term into its constant coefficient and the rest, e.g. 3 x into (3, x)
+fn split_coefficient(e: &Expr) -> (BigRational, Option<Expr>) {
+    if let Some(c) = constant(e) {
+        return (c, None);
+    }
+    if let Some((c, unit)) = unit_constant(e) {
+        return (c, Some(unit));
+    }
+    match e {
+        Expr::Cons(Op::Minus, a) if a.len() == 1 => {
+            let (c, rest) = split_coefficient(&a[0]);
+            (-c, rest)
+        }
+        // the number of a constant with a unit is part of the coefficient,
+        // so 3 m x - 6 m x is -3 m x
+        Expr::Cons(Op::Mul, a) => {
+            let (c, like) = group_factors(e);
+            if like
+                .iter()
+                .any(|(_, base, _)| unit_constant(base).is_some())
+            {
+                return (c, Some(build_product(BigRational::one(), like)));
+            }
+            match (constant(&a[0]), constant(&a[1])) {
+                (Some(c), _) => {
+                    let (c2, rest) = split_coefficient(&a[1]);
+                    (c * c2, rest)
+                }
+                (_, Some(c)) => {
+                    let (c2, rest) = split_coefficient(&a[0]);
+                    (c * c2, rest)
+                }
+                _ => (BigRational::one(), Some(e.clone())),
+            }
+        }
+        _ => (BigRational::one(), Some(e.clone())),
+    }
+}
+
+// Flattens a chain of additions and subtractions into signed terms
+fn flatten_sum(e: &Expr, sign: bool, terms: &mut Vec<(BigRational, Option<Expr>)>) {
+    match e {
+        Expr::Cons(Op::Plus, a) if a.len() == 2 => {
+            flatten_sum(&a[0], sign, terms);
+            flatten_sum(&a[1], sign, terms);
+        }
+        Expr::Cons(Op::Minus, a) if a.len() == 2 => {
+            flatten_sum(&a[0], sign, terms);
+            flatten_sum(&a[1], !sign, terms);
+        }
+        _ => {
+            let (c, rest) = split_coefficient(e);
+            terms.push((if sign { c } else { -c }, rest));
+        }
+    }
+}
+
+// Collects like terms, so x + 2 x - 1 + 3 becomes 3 x + 2
+fn collect_terms(e: Expr) -> Expr {
+    let mut terms = Vec::new();
+    flatten_sum(&e, true, &mut terms);
+
+    let mut constant = BigRational::zero();
+    let mut like: Vec<(String, BigRational, Expr)> = Vec::new();
+    for (c, rest) in terms {
+        match rest {
+            None => constant += c,
+            Some(rest) => {
+                let key = format!("{:?}", rest);
+                match like.iter_mut().find(|(k, _, _)| k == &key) {
+                    Some((_, total, _)) => *total += c,
+                    None => like.push((key, c, rest)),
+                }
+            }
+        }
+    }
+
+    let mut terms: Vec<(BigRational, Option<Expr>)> = like
+        .into_iter()
+        .map(|(_, c, rest)| (c, Some(rest)))
+        .chain(std::iter::once((constant, None)))
+        .filter(|(c, _)| !c.is_zero())
+        .collect();
+    // start with a positive term if there is one, so it's 1 - x rather than -x + 1
+    if let Some(i) = terms.iter().position(|(c, _)| c.is_positive()) {
+        let first = terms.remove(i);
+        terms.insert(0, first);
+    }
+
+    let mut res: Option<Expr> = None;
+    for (c, rest) in terms {
+        let magnitude = |c: BigRational| match &rest {
+            Some(rest) if c.is_one() => rest.clone(),
+            // put the coefficient back into the number of a constant with a unit
+            Some(rest) if has_unit_constant(rest) => {
+                let (c2, like) = group_factors(rest);
+                build_product(c * c2, like)
+            }
+            Some(rest) => mul(num(c), rest.clone()),
+            None => num(c),
+        };
+        res = Some(match res {
+            None if c.is_negative() => neg(magnitude(-c)),
+            None => magnitude(c),
+            Some(sum) if c.is_negative() => sub(sum, magnitude(-c)),
+            Some(sum) => add(sum, magnitude(c)),
+        });
+    }
+    res.unwrap_or_else(|| int(0))
+}
+
+fn has_unit_constant(e: &Expr) -> bool {
+    let mut factors = Vec::new();
+    flatten_product(e, &mut factors);
+    factors
+        .iter()
+        .any(|(base, _)| unit_constant(base).is_some())
+}
+
+// Flattens a chain of multiplications, with each factor as a base and exponent
+fn flatten_product(e: &Expr, factors: &mut Vec<(Expr, Expr)>) {
+    match e {
+        Expr::Cons(Op::Mul, a) if a.len() == 2 => {
+            flatten_product(&a[0], factors);
+            flatten_product(&a[1], factors);
+        }
+        Expr::Cons(Op::Exp, a) if a.len() == 2 => factors.push((a[0].clone(), a[1].clone())),
+        _ => factors.push((e.clone(), int(1))),
+    }
+}
+
+// Collects like factors and constants, so 2 x x 3 becomes 6 x^2
+fn collect_factors(e: Expr) -> Expr {
+    let (coefficient, like) = group_factors(&e);
+    if coefficient.is_zero() {
+        return int(0);
+    }
+    build_product(coefficient, like)
+}
+
+// Cancels factors in the numerator and denominator, so 2 x^3 / (4 x) becomes x^2 / 2
+fn cancel(a: &Expr, b: &Expr) -> Expr {
+    let (top_coefficient, mut top) = group_factors(a);
+    let (bottom_coefficient, bottom) = group_factors(b);
+    if bottom_coefficient.is_zero() {
+        return div(a.clone(), b.clone());
+    } else if top_coefficient.is_zero() {
+        return int(0);
+    }
+
+    let mut rest = Vec::new();
+    for (key, base, exp) in bottom {
+        let i = top.iter().position(|(k, _, _)| k == &key);
+        match (i, constant(&exp)) {
+            (Some(i), Some(p)) if constant(&top[i].2).is_some() => {
+                let diff = constant(&top[i].2).unwrap() - p;
+                if diff.is_positive() {
+                    top[i].2 = num(diff);
+                } else {
+                    top.remove(i);
+                    if diff.is_negative() {
+                        rest.push((key, base, num(-diff)));
+                    }
+                }
+            }
+            _ => rest.push((key, base, exp)),
+        }
+    }
+
+    let ratio = top_coefficient / bottom_coefficient;
+    // a constant with a unit on top takes the whole ratio, so it's 0.7 m t rather than 7 m t / 10
+    let (top_ratio, bottom_ratio) = if top.iter().any(|(_, base, _)| unit_constant(base).is_some())
+    {
+        (ratio, BigRational::one())
+    } else {
+        (
+            BigRational::from_integer(ratio.numer().clone()),
+            BigRational::from_integer(ratio.denom().clone()),
+        )
+    };
+    let top = build_product(top_ratio, top);
+    let bottom = build_product(bottom_ratio, rest);
+    if is_constant(&bottom, 1) {
+        top
+    } else {
+        div(top, bottom)
+    }
+}
+
+// The constant coefficient of a product, and its other factors
+// grouped by base as (key, base, exponent)
+fn group_factors(e: &Expr) -> (BigRational, Vec<(String, Expr, Expr)>) {
+    let mut factors = Vec::new();
+    flatten_product(e, &mut factors);
+
+    let mut coefficient = BigRational::one();
+    let mut like: Vec<(String, Expr, Expr)> = Vec::new();
+    for (base, exp) in factors {
+        match (constant(&base), constant(&exp)) {
+            (Some(c), Some(p)) if p.is_one() => coefficient *= c,
+            // a constant with a unit like 4.9 m/s^2 gives its number to the
+            // coefficient, which build_product puts back in front of the unit
+            (None, Some(p)) if p.is_one() && unit_constant(&base).is_some() => {
+                let (c, unit) = unit_constant(&base).unwrap();
+                coefficient *= c;
+                like.push((format!("{:?}", unit), unit, exp));
+            }
+            _ => {
+                let key = format!("{:?}", base);
+                match like.iter_mut().find(|(k, _, _)| k == &key) {
+                    Some((_, _, total)) => *total = simplify_once(&add(total.clone(), exp)),
+                    None => like.push((key, base, exp)),
+                }
+            }
+        }
+    }
+
+    (coefficient, like)
+}
+
+// Splits a constant with a unit like 4.9 m/s^2 into its number and 1 m/s^2
+fn unit_constant(e: &Expr) -> Option<(BigRational, Expr)> {
+    match e {
+        Expr::Cons(Op::AddUnit(unit, s), a) if a.len() == 1 => Some((
+            constant(&a[0])?,
+            Expr::Cons(Op::AddUnit(unit.clone(), s.clone()), vec![int(1)]),
+        )),
+        _ => None,
+    }
+}
+
+fn build_product(mut coefficient: BigRational, mut like: Vec<(String, Expr, Expr)>) -> Expr {
+    // the coefficient is written as the number of the first unit, so it's 9.8 m/s^2 t
+    let unit = like
+        .iter()
+        .position(|(_, base, exp)| is_constant(exp, 1) && unit_constant(base).is_some());
+    if let (Some(i), false) = (unit, coefficient.is_one()) {
+        let (key, base, exp) = like.remove(i);
+        let magnitude = coefficient.abs();
+        let number = Expr::Atom(Val {
+            exact: Some(magnitude.clone()),
+            ..Val::empty(magnitude.to_f64().unwrap_or(f64::NAN))
+        });
+        let base = match base {
+            Expr::Cons(op, _) => Expr::Cons(op, vec![number]),
+            _ => unreachable!(),
+        };
+        like.insert(0, (key, base, exp));
+        coefficient = if coefficient.is_negative() {
+            -BigRational::one()
+        } else {
+            BigRational::one()
+        };
+    }
+
+    let mut res = like.into_iter().map(|(_, base, exp)| {
+        if is_constant(&exp, 1) {
+            base
+        } else {
+            pow(base, exp)
+        }
+    });
+    let product = match res.next() {
+        Some(first) => res.fold(first, mul),
+        None => return num(coefficient),
+    };
+
+    if coefficient.is_one() {
+        product
+    } else if (-coefficient.clone()).is_one() {
+        neg(product)
+    } else if coefficient.is_negative() {
+        neg(mul(num(-coefficient), product))
+    } else {
+        mul(num(coefficient), product)
+    }
+}
+
+fn wrap(e: Expr) -> Expr {
+    Expr::ParenExpr(Box::new(e))
+}
+
+fn is_sum(e: &Expr) -> bool {
+    matches!(e, Expr::Cons(Op::Plus, _) | Expr::Cons(Op::Minus, _))
+}
+
+// Adds the parentheses implied by the tree's structure
+fn parenthesize(e: &Expr) -> Expr {
+    match e {
+        Expr::FnCall(fc) => Expr::FnCall(FnCall {
+            name: fc.name.clone(),
+            args: fc.args.iter().map(parenthesize).collect(),
+        }),
+        Expr::Vector(es) => Expr::Vector(es.iter().map(parenthesize).collect()),
+        Expr::Cons(op, args) => {
+            let args: Vec<Expr> = args.iter().map(parenthesize).collect();
+            let args = match (op, args.as_slice()) {
+                (Op::Mul | Op::Dot | Op::Cross, [a, b]) => vec![
+                    if is_sum(a) {
+                        wrap(a.clone())
+                    } else {
+                        a.clone()
+                    },
+                    if is_sum(b) {
+                        wrap(b.clone())
+                    } else {
+                        b.clone()
+                    },
+                ],
+                (Op::Minus, [a, b]) => vec![
+                    a.clone(),
+                    if is_sum(b) {
+                        wrap(b.clone())
+                    } else {
+                        b.clone()
+                    },
+                ],
+                (Op::Minus, [a]) if is_sum(a) => vec![wrap(a.clone())],
+                (Op::Exp, [a, b]) if matches!(a, Expr::Cons(_, _)) => {
+                    vec![wrap(a.clone()), b.clone()]
+                }
+                _ => args,
+            };
+            Expr::Cons(op.clone(), args)
+        }
+        _ => e.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::unit::CustomUnits;
+    use crate::latex::ToLaTeX;
+    use crate::parser::{expr::parse_expr, MathParser, Rule};
+    use pest::Parser;
+
+    fn parse(s: &str) -> Expr {
+        parse_expr(
+            MathParser::parse(Rule::expression, s)
+                .unwrap()
+                .next()
+                .unwrap(),
+            &CustomUnits::new(),
+        )
+        .unwrap()
+    }
+
+    fn latex(e: &Expr) -> String {
+        e.to_latex().unwrap().to_string()
+    }
+
+    fn diff(s: &str) -> String {
+        let scope = Scope::default();
+        latex(&simplify(&differentiate(&parse(s), "x", &scope).unwrap()))
+    }
+
+    #[test]
+    fn simplify_identities() {
+        assert_eq!(latex(&simplify(&parse("x * 1 + 0"))), "x");
+        assert_eq!(latex(&simplify(&parse("x^1 * y^0"))), "x");
+        assert_eq!(latex(&simplify(&parse("0 * x + 2 * 3"))), "6");
+        assert_eq!(latex(&simplify(&parse("-(-x)"))), "x");
+    }
+
+    #[test]
+    fn simplify_like_terms() {
+        assert_eq!(latex(&simplify(&parse("x + 2 * x - 3"))), "3 \\times x - 3");
+        assert_eq!(latex(&simplify(&parse("x * x * 2"))), "2 \\times x^{2}");
+        assert_eq!(
+            latex(&simplify(&parse("2 * x^3 / (4 * x)"))),
+            "\\frac{x^{2}}{2}"
+        );
+    }
+
+    #[test]
+    fn derivatives() {
+        assert_eq!(diff("x^3"), "3 \\times x^{2}");
+        assert_eq!(diff("sin(2 * x)"), "2 \\times \\text{cos}(2 \\times x)");
+        assert_eq!(diff("1 / x"), "\\frac{-1}{x^{2}}");
+        assert_eq!(diff("y * x"), "y");
+    }
+
+    #[test]
+    fn derivative_units() {
+        // x can only be s for these terms to have the same units
+        let scope = Scope::default();
+        assert!(differentiate(&parse("x^2 * 1 m/s^2 + x * 1 m/s"), "x", &scope).is_ok());
+        assert!(differentiate(&parse("x^2 + 1 m"), "x", &scope).is_err());
+        assert!(differentiate(&parse("x^2 * 1 m/s^2 + x * 1 m/s + 1 kg"), "x", &scope).is_err());
+    }
+}
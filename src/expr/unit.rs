@@ -1,7 +1,8 @@
 #![allow(clippy::clippy::suspicious_arithmetic_impl)]
 
-use num::rational::Ratio;
-use num::{One, Zero};
+use num::rational::{BigRational, Ratio};
+use num::BigInt;
+use num::{CheckedAdd, CheckedMul, CheckedSub, One, Zero};
 use std::fmt::Debug;
 
 use std::convert::TryFrom;
@@ -68,6 +69,58 @@ lazy_static! {
     };
 }
 
+// Units defined in the document with the `unit` statement, by name
+pub type CustomUnits = BTreeMap<String, Unit>;
+
+// Defines a unit in terms of existing units, or as a new base
+// dimension if `unit` is None. Existing units can't be redefined.
+pub fn define_custom_unit(
+    name: &str,
+    unit: Option<Unit>,
+    custom: &mut CustomUnits,
+) -> Result<(), CalcError> {
+    if find_unit(name, custom).is_ok() {
+        return Err(CalcError::UnitError(format!("{} is already a unit", name)));
+    }
+
+    let unit = unit.unwrap_or_else(|| {
+        let mut map = BTreeMap::new();
+        map.insert(name.to_string(), Ratio::one());
+        Unit {
+            desc: UnitDesc::Custom(map),
+            ..Unit::empty()
+        }
+    });
+
+    custom.insert(name.to_string(), unit);
+    Ok(())
+}
+
+// Looks up a builtin unit, or one the document defined, by name
+pub fn find_unit(s: &str, custom: &CustomUnits) -> Result<Unit, CalcError> {
+    let s = s.trim();
+    let unprefixed = |s: &str| unprefixed_unit(s).or_else(|| custom.get(s).cloned());
+    // Try the unit as written first. Otherwise, find if the unit starts with
+    // an SI prefix, in which case it should be stripped and added to the exponent.
+    unprefixed(s)
+        .or_else(|| {
+            UNIT_PREFIXES
+                .iter()
+                .chain(UNIT_PREFIXES_ABBR.iter())
+                .filter(|(p, _)| !p.is_empty())
+                .find_map(|(prefix, exp)| {
+                    // a prefix would scale the offset too, so kdegC means nothing
+                    let base =
+                        unprefixed(s.strip_prefix(prefix)?).filter(|base| base.offset == 0.0)?;
+                    Some(Unit {
+                        exp: exp + base.exp,
+                        ..base
+                    })
+                })
+        })
+        .ok_or_else(|| CalcError::UnitError(format!("{} is not a variable or a valid unit", s)))
+}
+
 pub enum BaseUnit {
     Meter,
     Gram,
@@ -109,20 +162,22 @@ pub const BASE_UNITS: [BaseUnit; 7] = [
 // The array is a list of powers for each SI base unit.
 // E.g. meters^2 / kelvin is [2, 0, 0, 0, -1, 0, 0]
 // --
-// Custom is not implemented yet, but I plan for users
-// to be able to create custom units, in which case the
-// map would just be [unit_name -> power]
+// Custom is used once a unit involves a dimension defined in the
+// document with `unit widget`. The map is [unit_name -> power] for
+// every non-zero power, including the SI base units, so widgets per second
+// is {"s": -1, "widget": 1}. A Custom desc without any custom dimensions
+// is always normalized back to Base.
 #[derive(Clone, Debug)]
 pub enum UnitDesc {
     Base([Ratio<i8>; 7]),
-    Custom(BTreeMap<String, Ratio<u8>>),
+    Custom(BTreeMap<String, Ratio<i8>>),
 }
 
 impl PartialEq for UnitDesc {
     fn eq(&self, other: &Self) -> bool {
         match (&self, &other) {
             (UnitDesc::Base(a), UnitDesc::Base(b)) => a == b,
-            _ => todo!(),
+            _ => self.to_map() == other.to_map(),
         }
     }
 }
@@ -145,16 +200,114 @@ impl UnitDesc {
     pub fn is_empty(&self) -> bool {
         match self {
             UnitDesc::Base(a) => a == &[Ratio::zero(); 7],
-            UnitDesc::Custom(_) => todo!(),
+            UnitDesc::Custom(map) => map.values().all(|p| p == &Ratio::zero()),
         }
     }
 
+    // Every power multiplied by p, so m^2 to the 1/2 is m
+    pub fn pow(&self, p: Ratio<i8>) -> Result<Self, CalcError> {
+        let times_p = |pow: &Ratio<i8>| pow.checked_mul(&p).ok_or_else(|| power_too_large(p));
+        Ok(match self {
+            UnitDesc::Base(a) => {
+                let mut res = [Ratio::zero(); 7];
+                for (r, pow) in res.iter_mut().zip(a.iter()) {
+                    *r = times_p(pow)?;
+                }
+                UnitDesc::Base(res)
+            }
+            UnitDesc::Custom(map) => UnitDesc::from_map(
+                map.iter()
+                    .map(|(name, pow)| Ok((name.clone(), times_p(pow)?)))
+                    .collect::<Result<_, CalcError>>()?,
+            ),
+        })
+    }
+
     pub fn largest_power(&self) -> Ratio<i8> {
         match self {
             UnitDesc::Base(a) => *a.iter().max().unwrap_or(&Ratio::zero()),
-            UnitDesc::Custom(_) => todo!(),
+            UnitDesc::Custom(map) => *map.values().max().unwrap_or(&Ratio::zero()),
+        }
+    }
+
+    // The non-zero powers of each unit, SI base units first
+    pub fn powers(&self) -> Vec<(String, Ratio<i8>)> {
+        match self {
+            UnitDesc::Base(a) => a
+                .iter()
+                .zip(BASE_UNITS.iter())
+                .filter(|(pow, _)| pow != &&Ratio::zero())
+                .map(|(pow, unit)| (unit.to_string(), *pow))
+                .collect(),
+            UnitDesc::Custom(map) => {
+                let is_base = |name: &String| BASE_UNITS.iter().any(|b| &b.to_string() == name);
+                let mut powers = UnitDesc::Base(UnitDesc::base_powers(map)).powers();
+                powers.extend(
+                    map.iter()
+                        .filter(|(name, pow)| !is_base(name) && pow != &&Ratio::zero())
+                        .map(|(name, pow)| (name.clone(), *pow)),
+                );
+                powers
+            }
+        }
+    }
+
+    fn to_map(&self) -> BTreeMap<String, Ratio<i8>> {
+        self.powers().into_iter().collect()
+    }
+
+    fn base_powers(map: &BTreeMap<String, Ratio<i8>>) -> [Ratio<i8>; 7] {
+        let mut arr = [Ratio::zero(); 7];
+        arr.iter_mut()
+            .zip(BASE_UNITS.iter())
+            .for_each(|(pow, unit)| {
+                *pow = *map.get(&unit.to_string()).unwrap_or(&Ratio::zero());
+            });
+        arr
+    }
+
+    fn from_map(mut map: BTreeMap<String, Ratio<i8>>) -> Self {
+        map.retain(|_, pow| pow != &Ratio::zero());
+        if map
+            .keys()
+            .all(|name| BASE_UNITS.iter().any(|b| &b.to_string() == name))
+        {
+            UnitDesc::Base(UnitDesc::base_powers(&map))
+        } else {
+            UnitDesc::Custom(map)
         }
     }
+
+    // Combines the powers of two descs with f, used for Mul and Div,
+    // which fails if a power no longer fits
+    fn combine(
+        &self,
+        rhs: &Self,
+        f: impl Fn(&Ratio<i8>, &Ratio<i8>) -> Option<Ratio<i8>>,
+    ) -> Result<Self, CalcError> {
+        let combined = |a: &Ratio<i8>, b: &Ratio<i8>| f(a, b).ok_or_else(|| power_too_large(b));
+        Ok(match (self, rhs) {
+            (UnitDesc::Base(a), UnitDesc::Base(b)) => {
+                let mut res = [Ratio::zero(); 7];
+                for (r, (a, b)) in res.iter_mut().zip(a.iter().zip(b.iter())) {
+                    *r = combined(a, b)?;
+                }
+                UnitDesc::Base(res)
+            }
+            _ => {
+                let mut res = self.to_map();
+                for (name, pow) in rhs.to_map() {
+                    let entry = res.entry(name).or_insert_with(Ratio::zero);
+                    *entry = combined(entry, &pow)?;
+                }
+                UnitDesc::from_map(res)
+            }
+        })
+    }
+}
+
+fn power_too_large(p: impl std::fmt::Display) -> CalcError {
+    CalcError::UnitError(format!("The power {} of a unit is too large", p))
 }
 
 #[derive(Clone, PartialEq)]
@@ -162,6 +315,11 @@ pub struct Unit {
     pub desc: UnitDesc,
     pub exp: i64,
     pub mult: f64,
+    // Only non-zero for absolute temperature scales like celsius,
+    // where the value in kelvin is `num * mult * 10^exp + offset`.
+    // Values with an offset are treated as points on that scale,
+    // while offset-free temperature units are differences.
+    pub offset: f64,
 }
 
 impl Default for Unit {
@@ -170,6 +328,7 @@ impl Default for Unit {
             desc: UnitDesc::Base([Ratio::zero(); 7]),
             exp: 0,
             mult: 1.0,
+            offset: 0.0,
         }
     }
 }
@@ -185,207 +344,332 @@ impl Unit {
         Self::default()
     }
 
-    pub fn pow(&self, rhs: i64) -> Self {
-        let mut ret = self.clone();
-        if rhs > 0 {
-            (0..rhs - 1).for_each(|_| ret = ret.clone() * self.clone());
-        } else if rhs == 0 {
-            ret = Unit::empty();
-        } else {
-            (0..rhs.abs() + 1).for_each(|_| ret = ret.clone() / self.clone());
+    pub fn pow(&self, p: impl Into<Ratio<i8>>) -> Result<Self, CalcError> {
+        let p = p.into();
+        if p.is_one() {
+            return Ok(self.clone());
         }
-        ret
+        if p.is_integer() {
+            let n = p.to_integer() as i32;
+            return Ok(Unit {
+                desc: self.desc.pow(p)?,
+                exp: self.exp * n as i64,
+                mult: self.mult.powi(n),
+                offset: 0.0,
+            });
+        }
+
+        // a fractional power of a prefix, like km^(1/2), isn't a whole power of ten,
+        // so what's left over goes in the multiplier
+        let p_f64 = *p.numer() as f64 / *p.denom() as f64;
+        let exp = self.exp as f64 * p_f64;
+        Ok(Unit {
+            desc: self.desc.pow(p)?,
+            exp: exp.floor() as i64,
+            mult: self.mult.powf(p_f64) * 10f64.powf(exp - exp.floor()),
+            offset: 0.0,
+        })
+    }
+
+    // The size of this unit relative to the base units, mult * 10^exp
+    pub fn scale(&self) -> f64 {
+        self.mult * 10f64.powi(self.exp as i32)
+    }
+
+    // mult * 10^exp as a rational, for exact arithmetic
+    pub fn exact_scale(&self) -> Option<BigRational> {
+        let ten = BigRational::from_integer(BigInt::from(10));
+        Some(exact_from_float(self.mult)? * ten.pow(self.exp as i32))
     }
 }
 
+// A power as a unit can have it, if it's a simple fraction like 2, -1 or 1/2
+pub fn power_ratio(p: f64) -> Option<Ratio<i8>> {
+    Ratio::<i8>::approximate_float(p)
+        .filter(|r| (*r.numer() as f64 / *r.denom() as f64 - p).abs() < 1e-9)
+}
+
+// Unit multipliers and offsets are written as decimals like 3.048 or 273.15,
+// so the closest simple fraction recovers the exact definition
+pub fn exact_from_float(f: f64) -> Option<BigRational> {
+    let r = Ratio::<i64>::approximate_float(f)?;
+    Some(BigRational::new(
+        BigInt::from(*r.numer()),
+        BigInt::from(*r.denom()),
+    ))
+}
+
 impl std::convert::TryFrom<&str> for Unit {
     type Error = CalcError;
 
+    // only the builtin units, see find_unit for the document's own
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let s = s.trim();
-        Ok({
-            // Find if the unit starts with an SI prefix, in which case it should be
-            // stripped and added to the exponent.
-            // Unfortunately, some units start with an abbreviated SI prefix so they
-            // have to be hardcoded out.
-            let (stripped, exp) = if !matches!(
-                s,
-                "ampere"
-                    | "amp"
-                    | "amps"
-                    | "amperes"
-                    | "amu"
-                    | "day"
-                    | "days"
-                    | "deg"
-                    | "degree"
-                    | "degrees"
-                    | "hours"
-                    | "hour"
-                    | "hz"
-                    | "meters"
-                    | "meter"
-                    | "m"
-                    | "minutes"
-                    | "min"
-                    | "mol"
-                    | "moles"
-                    | "M"
-                    | "year"
-                    | "years"
-                    | "pascal"
-                    | "Pa"
-                    | "coulomb"
-                    | "coulombs"
-                    | "T"
-                    | "tesla"
-                    | "teslas"
-                    | "kat"
-                    | "katal"
-                    | "katals"
-                    | "farad"
-                    | "farads"
-            ) {
-                UNIT_PREFIXES
-                    .iter()
-                    .chain(UNIT_PREFIXES_ABBR.iter())
-                    .filter(|(p, _)| !p.is_empty())
-                    .find_map(|(prefix, exp)| {
-                        s.trim()
-                            .strip_prefix(prefix)
-                            .map(|stripped| (stripped, exp))
-                    })
-                    .unwrap_or((s.trim(), &0))
-            } else {
-                (s, &0)
-            };
-
-            let base = match stripped {
-                "meters" | "meter" | "m" => BaseUnit::Meter.into(),
-                "grams" | "gram" | "g" | "gm" => BaseUnit::Gram.into(),
-                "second" | "seconds" | "s" => BaseUnit::Second.into(),
-                "amp" | "amps" | "ampere" | "amperes" => BaseUnit::Ampere.into(),
-                "kelvin" | "K" => BaseUnit::Kelvin.into(),
-                "moles" | "mols" | "mol" | "mole" => BaseUnit::Mole.into(),
-                "candela" => BaseUnit::Candela.into(),
-                "rad" | "radian" | "radians" => Unit::empty(),
-                "rev" | "revolution" | "revolutions" => Unit {
-                    desc: [0; 7].into(),
-                    exp: 0,
-                    mult: 2.0 * std::f64::consts::PI,
-                },
-                "deg" | "degree" | "degrees" => Unit {
-                    desc: [0; 7].into(),
-                    exp: -2,
-                    mult: 1.745329,
-                },
-                "J" | "joule" => Unit {
-                    desc: [2, 1, -2, 0, 0, 0, 0].into(),
-                    exp: 3,
-                    mult: 1.0,
-                },
-                "N" | "newton" => Unit {
-                    desc: [1, 1, -2, 0, 0, 0, 0].into(),
-                    exp: 3,
-                    mult: 1.0,
-                },
-                "minute" | "min" => Unit {
-                    mult: 6.0,
-                    exp: 1,
-                    ..BaseUnit::Second.into()
-                },
-                "hour" | "hours" => Unit {
-                    mult: 3.6,
-                    exp: 3,
-                    ..BaseUnit::Second.into()
-                },
-                "day" | "days" => Unit {
-                    mult: 8.64,
-                    exp: 4,
-                    ..BaseUnit::Second.into()
-                },
-                "year" | "years" => Unit {
-                    mult: 3.1536,
-                    exp: 7,
-                    ..BaseUnit::Second.into()
-                },
-                "amu" => Unit {
-                    mult: 1.6603145,
-                    exp: -24,
-                    ..BaseUnit::Gram.into()
-                },
-                "hz" => Unit {
-                    desc: [0, 0, -1, 0, 0, 0, 0].into(),
-                    ..Unit::empty()
-                },
-                "L" | "liter" => Unit {
-                    desc: [3, 0, 0, 0, 0, 0, 0].into(),
-                    exp: -3,
-                    mult: 1.0,
-                },
-                "Pa" | "pascal" => Unit {
-                    desc: [-1, 1, -2, 0, 0, 0, 0].into(),
-                    exp: 3,
-                    mult: 1.0,
-                },
-                "W" | "watt" => Unit {
-                    desc: [2, 1, -3, 0, 0, 0, 0].into(),
-                    exp: 3,
-                    mult: 1.0,
-                },
-                "C" | "coulomb" | "coulombs" => {
-                    Unit::try_from("seconds").unwrap() * Unit::try_from("amps").unwrap()
-                }
-                "V" | "volt" | "volts" => {
-                    Unit::try_from("W").unwrap() * Unit::try_from("A").unwrap()
-                }
-                "F" | "farad" | "farads" => {
-                    Unit::try_from("C").unwrap() * Unit::try_from("V").unwrap()
-                }
-                "Ω" | "ohm" | "ohms" => {
-                    Unit::try_from("V").unwrap() / Unit::try_from("A").unwrap()
-                }
-                "S" | "siemen" | "siemens" => {
-                    Unit::try_from("A").unwrap() * Unit::try_from("V").unwrap()
-                }
-                "Wb" | "weber" | "webers" => {
-                    Unit::try_from("V").unwrap() * Unit::try_from("s").unwrap()
-                }
-                "T" | "tesla" | "teslas" => {
-                    Unit::try_from("Wb").unwrap() * Unit::try_from("m").unwrap().pow(2)
-                }
-                "H" | "henry" | "henries" => {
-                    Unit::try_from("Wb").unwrap() / Unit::try_from("A").unwrap().pow(2)
-                }
-                "lx" | "lux" => Unit::try_from("lm").unwrap() / Unit::try_from("m").unwrap().pow(2),
-                "Bq" | "becquerel" | "becquerels" => Unit::try_from("hz").unwrap(),
-                "Gy" | "gray" | "grays" => {
-                    Unit::try_from("J").unwrap() / Unit::try_from("kg").unwrap()
-                }
-                "Sy" | "sievert" | "sieverts" => {
-                    Unit::try_from("J").unwrap() / Unit::try_from("kg").unwrap()
-                }
-                "kat" | "katal" | "katals" => {
-                    Unit::try_from("mol").unwrap() / Unit::try_from("s").unwrap()
-                }
-                "M" => Unit::try_from("moles").unwrap() / Unit::try_from("L").unwrap(),
-                _ => {
-                    return Err(CalcError::UnitError(format!(
-                        "{} is not a variable or a valid unit",
-                        s
-                    )));
-                }
-            };
-
-            Unit {
-                desc: base.desc,
-                exp: exp + base.exp,
-                mult: base.mult,
-            }
-        })
+        find_unit(s, &CustomUnits::new())
     }
 }
 
+fn unprefixed_unit(s: &str) -> Option<Unit> {
+    Some(match s {
+        "meters" | "meter" | "m" => BaseUnit::Meter.into(),
+        "grams" | "gram" | "g" | "gm" => BaseUnit::Gram.into(),
+        "second" | "seconds" | "s" => BaseUnit::Second.into(),
+        "A" | "amp" | "amps" | "ampere" | "amperes" => BaseUnit::Ampere.into(),
+        "kelvin" | "K" => BaseUnit::Kelvin.into(),
+        "moles" | "mols" | "mol" | "mole" => BaseUnit::Mole.into(),
+        "candela" => BaseUnit::Candela.into(),
+        "rad" | "radian" | "radians" => Unit::empty(),
+        "rev" | "revolution" | "revolutions" => Unit {
+            desc: [0; 7].into(),
+            exp: 0,
+            mult: 2.0 * std::f64::consts::PI,
+            offset: 0.0,
+        },
+        "deg" | "degree" | "degrees" => Unit {
+            desc: [0; 7].into(),
+            exp: -2,
+            mult: 1.745329,
+            offset: 0.0,
+        },
+        "J" | "joule" => Unit {
+            desc: [2, 1, -2, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 1.0,
+            offset: 0.0,
+        },
+        "N" | "newton" => Unit {
+            desc: [1, 1, -2, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 1.0,
+            offset: 0.0,
+        },
+        "minute" | "min" => Unit {
+            mult: 6.0,
+            exp: 1,
+            ..BaseUnit::Second.into()
+        },
+        "hour" | "hours" => Unit {
+            mult: 3.6,
+            exp: 3,
+            ..BaseUnit::Second.into()
+        },
+        "day" | "days" => Unit {
+            mult: 8.64,
+            exp: 4,
+            ..BaseUnit::Second.into()
+        },
+        "year" | "years" => Unit {
+            mult: 3.1536,
+            exp: 7,
+            ..BaseUnit::Second.into()
+        },
+        "amu" => Unit {
+            mult: 1.6603145,
+            exp: -24,
+            ..BaseUnit::Gram.into()
+        },
+        "hz" | "Hz" | "hertz" => Unit {
+            desc: [0, 0, -1, 0, 0, 0, 0].into(),
+            ..Unit::empty()
+        },
+        "L" | "liter" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -3,
+            mult: 1.0,
+            offset: 0.0,
+        },
+        "Pa" | "pascal" => Unit {
+            desc: [-1, 1, -2, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 1.0,
+            offset: 0.0,
+        },
+        "W" | "watt" => Unit {
+            desc: [2, 1, -3, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 1.0,
+            offset: 0.0,
+        },
+        "C" | "coulomb" | "coulombs" => {
+            (Unit::try_from("seconds").unwrap() * Unit::try_from("amps").unwrap()).unwrap()
+        }
+        "V" | "volt" | "volts" => {
+            (Unit::try_from("W").unwrap() / Unit::try_from("A").unwrap()).unwrap()
+        }
+        "F" | "farad" | "farads" => {
+            (Unit::try_from("C").unwrap() / Unit::try_from("V").unwrap()).unwrap()
+        }
+        "Ω" | "ohm" | "ohms" => {
+            (Unit::try_from("V").unwrap() / Unit::try_from("A").unwrap()).unwrap()
+        }
+        "S" | "siemen" | "siemens" => {
+            (Unit::try_from("A").unwrap() / Unit::try_from("V").unwrap()).unwrap()
+        }
+        "Wb" | "weber" | "webers" => {
+            (Unit::try_from("V").unwrap() * Unit::try_from("s").unwrap()).unwrap()
+        }
+        "T" | "tesla" | "teslas" => {
+            (Unit::try_from("Wb").unwrap() / Unit::try_from("m").unwrap().pow(2).unwrap()).unwrap()
+        }
+        "H" | "henry" | "henries" => {
+            (Unit::try_from("Wb").unwrap() / Unit::try_from("A").unwrap()).unwrap()
+        }
+        "lx" | "lux" => {
+            (Unit::try_from("lm").unwrap() / Unit::try_from("m").unwrap().pow(2).unwrap()).unwrap()
+        }
+        "Bq" | "becquerel" | "becquerels" => Unit::try_from("hz").unwrap(),
+        "Gy" | "gray" | "grays" => {
+            (Unit::try_from("J").unwrap() / Unit::try_from("kg").unwrap()).unwrap()
+        }
+        "Sy" | "sievert" | "sieverts" => {
+            (Unit::try_from("J").unwrap() / Unit::try_from("kg").unwrap()).unwrap()
+        }
+        "kat" | "katal" | "katals" => {
+            (Unit::try_from("mol").unwrap() / Unit::try_from("s").unwrap()).unwrap()
+        }
+        "M" => (Unit::try_from("moles").unwrap() / Unit::try_from("L").unwrap()).unwrap(),
+        // US customary and imperial units, using the exact definitions
+        // in terms of SI units where there is one
+        "in" | "inch" | "inches" => Unit {
+            mult: 2.54,
+            exp: -2,
+            ..BaseUnit::Meter.into()
+        },
+        "ft" | "foot" | "feet" => Unit {
+            mult: 3.048,
+            exp: -1,
+            ..BaseUnit::Meter.into()
+        },
+        "yd" | "yard" | "yards" => Unit {
+            mult: 9.144,
+            exp: -1,
+            ..BaseUnit::Meter.into()
+        },
+        "mi" | "mile" | "miles" => Unit {
+            mult: 1.609344,
+            exp: 3,
+            ..BaseUnit::Meter.into()
+        },
+        "nmi" => Unit {
+            mult: 1.852,
+            exp: 3,
+            ..BaseUnit::Meter.into()
+        },
+        "acre" | "acres" => Unit {
+            desc: [2, 0, 0, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 4.0468564224,
+            offset: 0.0,
+        },
+        "lb" | "lbs" | "pound" | "pounds" => Unit {
+            mult: 4.5359237,
+            exp: 2,
+            ..BaseUnit::Gram.into()
+        },
+        "oz" | "ounce" | "ounces" => Unit {
+            mult: 2.8349523125,
+            exp: 1,
+            ..BaseUnit::Gram.into()
+        },
+        "ton" | "tons" => Unit {
+            mult: 9.0718474,
+            exp: 5,
+            ..BaseUnit::Gram.into()
+        },
+        "st" | "stone" => Unit {
+            mult: 6.35029318,
+            exp: 3,
+            ..BaseUnit::Gram.into()
+        },
+        "slug" | "slugs" => {
+            let lbf_s2 =
+                Unit::try_from("lbf").unwrap() * Unit::try_from("s").unwrap().pow(2).unwrap();
+            (lbf_s2.unwrap() / Unit::try_from("ft").unwrap()).unwrap()
+        }
+        "lbf" => Unit {
+            desc: [1, 1, -2, 0, 0, 0, 0].into(),
+            exp: 3,
+            mult: 4.4482216152605,
+            offset: 0.0,
+        },
+        "kip" | "kips" => Unit {
+            exp: 6,
+            ..Unit::try_from("lbf").unwrap()
+        },
+        "psi" => (Unit::try_from("lbf").unwrap() / Unit::try_from("in").unwrap().pow(2).unwrap())
+            .unwrap(),
+        "gal" | "gallon" | "gallons" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -3,
+            mult: 3.785411784,
+            offset: 0.0,
+        },
+        "qt" | "quart" | "quarts" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -4,
+            mult: 9.46352946,
+            offset: 0.0,
+        },
+        "pt" | "pint" | "pints" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -4,
+            mult: 4.73176473,
+            offset: 0.0,
+        },
+        "cup" | "cups" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -4,
+            mult: 2.365882365,
+            offset: 0.0,
+        },
+        "floz" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -5,
+            mult: 2.95735295625,
+            offset: 0.0,
+        },
+        "impgal" => Unit {
+            desc: [3, 0, 0, 0, 0, 0, 0].into(),
+            exp: -3,
+            mult: 4.54609,
+            offset: 0.0,
+        },
+        "BTU" | "btu" => Unit {
+            desc: [2, 1, -2, 0, 0, 0, 0].into(),
+            exp: 6,
+            mult: 1.05505585262,
+            offset: 0.0,
+        },
+        "ftlbf" => (Unit::try_from("ft").unwrap() * Unit::try_from("lbf").unwrap()).unwrap(),
+        "hp" | "horsepower" => Unit {
+            desc: [2, 1, -3, 0, 0, 0, 0].into(),
+            exp: 5,
+            mult: 7.456998715822702,
+            offset: 0.0,
+        },
+        "mph" => (Unit::try_from("mi").unwrap() / Unit::try_from("hour").unwrap()).unwrap(),
+        "fps" => (Unit::try_from("ft").unwrap() / Unit::try_from("s").unwrap()).unwrap(),
+        "kn" | "knot" | "knots" => {
+            (Unit::try_from("nmi").unwrap() / Unit::try_from("hour").unwrap()).unwrap()
+        }
+        "degR" | "rankine" => Unit {
+            mult: 5.0 / 0.9,
+            exp: -1,
+            ..BaseUnit::Kelvin.into()
+        },
+        "degC" | "celsius" => Unit {
+            offset: 273.15,
+            ..BaseUnit::Kelvin.into()
+        },
+        "degF" | "fahrenheit" => Unit {
+            offset: 459.67 / 1.8,
+            ..Unit::try_from("degR").unwrap()
+        },
+        "deltaC" => BaseUnit::Kelvin.into(),
+        "deltaF" => Unit::try_from("degR").unwrap(),
+        _ => return None,
+    })
+}
+
 impl TryFrom<String> for Unit {
     type Error = CalcError;
 
@@ -420,6 +704,7 @@ impl From<BaseUnit> for Unit {
             desc,
             exp: 0,
             mult: 1.0,
+            offset: 0.0,
         }
     }
 }
@@ -428,68 +713,53 @@ impl From<BaseUnit> for Unit {
 // ToLatex handles the proper formatting
 impl std::fmt::Display for Unit {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.desc.clone() {
-            UnitDesc::Base(arr) => {
-                let res =
-                    arr.iter()
-                        .zip(BASE_UNITS.iter())
-                        .fold("".to_string(), |acc, (pow, unit)| match pow {
-                            r if r == &Ratio::zero() => acc,
-                            r if r == &Ratio::one() => format!("{} {}", acc, unit.to_string()),
-                            _ => format!("{} {}^{}", acc, unit.to_string(), pow),
-                        });
-                write!(f, "{}", res.trim())
-            }
-            UnitDesc::Custom(_map) => {
-                todo!()
-            }
-        }
+        let res = self
+            .desc
+            .powers()
+            .iter()
+            .fold("".to_string(), |acc, (unit, pow)| match pow {
+                r if r == &Ratio::one() => format!("{} {}", acc, unit),
+                _ => format!("{} {}^{}", acc, unit, pow),
+            });
+        write!(f, "{}", res.trim())
     }
 }
 
 impl std::ops::Mul for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, CalcError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self.desc, rhs.desc) {
-            (UnitDesc::Base(a), UnitDesc::Base(b)) => {
-                let mut res = [Ratio::zero(); 7];
-                res.iter_mut()
-                    .zip(a.iter().zip(b.iter()))
-                    .for_each(|(r, (a, b))| {
-                        *r = a + b;
-                    });
-                Unit {
-                    desc: UnitDesc::Base(res),
-                    exp: self.exp + rhs.exp,
-                    mult: self.mult * rhs.mult,
-                }
-            }
-            _ => todo!(),
-        }
+        // an offset only survives scaling by a dimensionless unit,
+        // e.g. the `1 * degC` created by `25 degC`
+        let offset = match (self.desc.is_empty(), rhs.desc.is_empty()) {
+            (true, _) => rhs.offset,
+            (_, true) => self.offset,
+            _ => 0.0,
+        };
+
+        Ok(Unit {
+            desc: self.desc.combine(&rhs.desc, |a, b| a.checked_add(b))?,
+            exp: self.exp + rhs.exp,
+            mult: self.mult * rhs.mult,
+            offset,
+        })
     }
 }
 
 impl std::ops::Div for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, CalcError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        match (self.desc, rhs.desc) {
-            (UnitDesc::Base(a), UnitDesc::Base(b)) => {
-                let mut res = [Ratio::zero(); 7];
-                res.iter_mut()
-                    .zip(a.iter().zip(b.iter()))
-                    .for_each(|(r, (a, b))| {
-                        *r = a - b;
-                    });
-                Unit {
-                    desc: UnitDesc::Base(res),
-                    exp: self.exp - rhs.exp,
-                    mult: self.mult / rhs.mult,
-                }
-            }
-            _ => todo!(),
-        }
+        Ok(Unit {
+            desc: self.desc.combine(&rhs.desc, |a, b| a.checked_sub(b))?,
+            exp: self.exp - rhs.exp,
+            mult: self.mult / rhs.mult,
+            offset: if rhs.desc.is_empty() {
+                self.offset
+            } else {
+                0.0
+            },
+        })
     }
 }
 
@@ -517,43 +787,43 @@ mod tests {
 
     #[test]
     fn pow_unit_meter() {
-        let unit = Unit::from(BaseUnit::Meter).pow(3);
+        let unit = Unit::from(BaseUnit::Meter).pow(3).unwrap();
         assert_eq!(unit.to_string(), "m^3");
     }
 
     #[test]
     fn pow_unit_gram() {
-        let unit = Unit::from(BaseUnit::Gram).pow(3);
+        let unit = Unit::from(BaseUnit::Gram).pow(3).unwrap();
         assert_eq!(unit.to_string(), "g^3");
     }
 
     #[test]
     fn pow_unit_second() {
-        let unit = Unit::from(BaseUnit::Second).pow(3);
+        let unit = Unit::from(BaseUnit::Second).pow(3).unwrap();
         assert_eq!(unit.to_string(), "s^3");
     }
 
     #[test]
     fn pow_unit_ampere() {
-        let unit = Unit::from(BaseUnit::Ampere).pow(3);
+        let unit = Unit::from(BaseUnit::Ampere).pow(3).unwrap();
         assert_eq!(unit.to_string(), "A^3");
     }
 
     #[test]
     fn pow_unit_kelvin() {
-        let unit = Unit::from(BaseUnit::Kelvin).pow(3);
+        let unit = Unit::from(BaseUnit::Kelvin).pow(3).unwrap();
         assert_eq!(unit.to_string(), "K^3");
     }
 
     #[test]
     fn pow_unit_mole() {
-        let unit = Unit::from(BaseUnit::Mole).pow(3);
+        let unit = Unit::from(BaseUnit::Mole).pow(3).unwrap();
         assert_eq!(unit.to_string(), "mol^3");
     }
 
     #[test]
     fn pow_unit_candela() {
-        let unit = Unit::from(BaseUnit::Candela).pow(3);
+        let unit = Unit::from(BaseUnit::Candela).pow(3).unwrap();
         assert_eq!(unit.to_string(), "cd^3");
     }
 
@@ -573,15 +843,15 @@ mod tests {
 
     #[test]
     fn partial_eq_unit_pow_success() {
-        let unit1 = Unit::from(BaseUnit::Meter).pow(3);
-        let unit2 = Unit::from(BaseUnit::Meter).pow(3);
+        let unit1 = Unit::from(BaseUnit::Meter).pow(3).unwrap();
+        let unit2 = Unit::from(BaseUnit::Meter).pow(3).unwrap();
         assert_eq!(unit1, unit2);
     }
 
     #[test]
     fn partial_eq_unit_pow_failure() {
-        let unit1 = Unit::from(BaseUnit::Meter).pow(2);
-        let unit2 = Unit::from(BaseUnit::Meter).pow(3);
+        let unit1 = Unit::from(BaseUnit::Meter).pow(2).unwrap();
+        let unit2 = Unit::from(BaseUnit::Meter).pow(3).unwrap();
         assert_ne!(unit1, unit2);
     }
 
@@ -663,17 +933,48 @@ mod tests {
         assert_eq!(unit2.to_string(), "s");
     }
 
+    #[test]
+    fn try_from_unit_us_customary() {
+        let unit1 = Unit::try_from("ft").unwrap();
+        assert_eq!(unit1.to_string(), "m");
+
+        let unit2 = Unit::try_from("psi").unwrap();
+        assert_eq!(unit2.to_string(), "m^-1 g s^-2");
+
+        let unit3 = Unit::try_from("hp").unwrap();
+        assert_eq!(unit3.to_string(), "m^2 g s^-3");
+    }
+
+    #[test]
+    fn try_from_unit_prefix_letter() {
+        // these start with an SI prefix but shouldn't be stripped
+        let unit1 = Unit::try_from("kelvin").unwrap();
+        assert_eq!(unit1.exp, 0);
+
+        let unit2 = Unit::try_from("mi").unwrap();
+        assert_eq!(unit2.exp, 3);
+
+        let unit3 = Unit::try_from("mK").unwrap();
+        assert_eq!(unit3.exp, -3);
+    }
+
+    #[test]
+    fn try_from_prefixed_offset_unit() {
+        assert!(Unit::try_from("kdegC").is_err());
+        assert!(Unit::try_from("mdegF").is_err());
+    }
+
     #[test]
     fn mult_meter() {
         let unit = Unit::try_from("meters").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "m^2");
     }
 
     #[test]
     fn mult_gram() {
         let unit = Unit::try_from("grams").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "g^2");
     }
 
@@ -681,21 +982,21 @@ mod tests {
     fn mult_meters_grams() {
         let meters = Unit::try_from("meters").unwrap();
         let grams = Unit::try_from("grams").unwrap();
-        let result = (meters * grams).to_string();
+        let result = (meters * grams).unwrap().to_string();
         assert_eq!(result, "m g");
     }
 
     #[test]
     fn mult_second() {
         let unit = Unit::try_from("seconds").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "s^2");
     }
 
     #[test]
     fn mult_ampere() {
         let unit = Unit::try_from("amperes").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "A^2");
     }
 
@@ -703,21 +1004,21 @@ mod tests {
     fn mult_ampere_second() {
         let amperes = Unit::try_from("amperes").unwrap();
         let second = Unit::try_from("second").unwrap();
-        let result = (amperes * second).to_string();
+        let result = (amperes * second).unwrap().to_string();
         assert_eq!(result, "s A");
     }
 
     #[test]
     fn mult_mole() {
         let unit = Unit::try_from("moles").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "mol^2");
     }
 
     #[test]
     fn mult_joule() {
         let unit = Unit::try_from("joule").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "m^4 g^2 s^-4");
     }
 
@@ -725,14 +1026,14 @@ mod tests {
     fn mult_joule_second() {
         let joules = Unit::try_from("joule").unwrap();
         let second = Unit::try_from("second").unwrap();
-        let result = (joules * second).to_string();
+        let result = (joules * second).unwrap().to_string();
         assert_eq!(result, "m^2 g s^-1");
     }
 
     #[test]
     fn mult_newton() {
         let unit = Unit::try_from("N").unwrap();
-        let out = (unit.clone() * unit).to_string();
+        let out = (unit.clone() * unit).unwrap().to_string();
         assert_eq!(out, "m^2 g^2 s^-4");
     }
 
@@ -740,48 +1041,118 @@ mod tests {
     fn div_meter_gram() {
         let mut unit1 = Unit::try_from("meters").unwrap();
         let mut unit2 = Unit::try_from("grams").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "m g^-1");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "m g^-1");
     }
 
     #[test]
     fn div_joule_meter() {
         let mut unit1 = Unit::try_from("joule").unwrap();
         let mut unit2 = Unit::try_from("meter").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "m g s^-2");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "m g s^-2");
     }
 
     #[test]
     fn div_joule_second() {
         let mut unit1 = Unit::try_from("joule").unwrap();
         let mut unit2 = Unit::try_from("second").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "m^2 g s^-3");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "m^2 g s^-3");
     }
 
     #[test]
     fn div_ampere_second() {
         let mut unit1 = Unit::try_from("ampere").unwrap();
         let mut unit2 = Unit::try_from("second").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "s^-1 A");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "s^-1 A");
     }
 
     #[test]
     fn div_ampere_ampere() {
         let mut unit1 = Unit::try_from("ampere").unwrap();
         let mut unit2 = Unit::try_from("ampere").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "");
     }
 
     #[test]
     fn div_meter_meter() {
         let mut unit1 = Unit::try_from("meters").unwrap();
         let mut unit2 = Unit::try_from("meters").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "");
     }
 
     #[test]
     fn div_gram_gram() {
         let mut unit1 = Unit::try_from("grams").unwrap();
         let mut unit2 = Unit::try_from("grams").unwrap();
-        assert_eq!((unit1 / unit2).to_string(), "");
+        assert_eq!((unit1 / unit2).unwrap().to_string(), "");
+    }
+
+    #[test]
+    fn mult_custom_second() {
+        let mut custom = CustomUnits::new();
+        define_custom_unit("widget", None, &mut custom).unwrap();
+        assert!(Unit::try_from("widget").is_err());
+        let widget = find_unit("widget", &custom).unwrap();
+        let second = Unit::try_from("second").unwrap();
+        assert_eq!((widget / second).unwrap().to_string(), "s^-1 widget");
+    }
+
+    #[test]
+    fn div_custom_custom() {
+        let mut custom = CustomUnits::new();
+        define_custom_unit("gizmo", None, &mut custom).unwrap();
+        let unit1 = find_unit("gizmo", &custom).unwrap();
+        let unit2 = find_unit("gizmo", &custom).unwrap().pow(2).unwrap();
+        assert_eq!((unit1.clone() / unit1.clone()).unwrap().to_string(), "");
+        assert_eq!(
+            (unit1.clone() / unit1).unwrap().desc,
+            UnitDesc::from([0; 7])
+        );
+        assert_eq!(unit2.desc.largest_power(), Ratio::from(2));
+    }
+
+    #[test]
+    fn desc_pow() {
+        let area = Unit::try_from("m").unwrap().pow(2).unwrap();
+        assert_eq!(
+            area.desc.pow(Ratio::new(1, 2)).unwrap(),
+            UnitDesc::from([1, 0, 0, 0, 0, 0, 0])
+        );
+        let root_hz = Unit::try_from("s")
+            .unwrap()
+            .desc
+            .pow(Ratio::new(-1, 2))
+            .unwrap();
+        assert_eq!(root_hz.largest_power(), Ratio::zero());
+        assert_eq!(root_hz.powers()[0].1, Ratio::new(-1, 2));
+        // seven square roots of m need a denominator of 128
+        let root = (0..6).try_fold(Unit::try_from("m").unwrap().desc, |d, _| {
+            d.pow(Ratio::new(1, 2))
+        });
+        assert!(root.unwrap().pow(Ratio::new(1, 2)).is_err());
+    }
+
+    #[test]
+    fn mult_power_overflow() {
+        let unit = Unit::try_from("m").unwrap().pow(100).unwrap();
+        assert!((unit.clone() * unit.clone()).is_err());
+        assert!((unit.clone() / unit.pow(-1).unwrap()).is_err());
+        let mut custom = CustomUnits::new();
+        define_custom_unit("gizmo", None, &mut custom).unwrap();
+        let gizmos = find_unit("gizmo", &custom).unwrap().pow(100).unwrap();
+        assert!((gizmos.clone() * gizmos).is_err());
+    }
+
+    #[test]
+    fn try_from_derived_custom() {
+        let furlong = Unit {
+            mult: 2.01168,
+            exp: 2,
+            ..BaseUnit::Meter.into()
+        };
+        let mut custom = CustomUnits::new();
+        define_custom_unit("furlong", Some(furlong.clone()), &mut custom).unwrap();
+        assert_eq!(find_unit("furlong", &custom).unwrap(), furlong);
+        assert!(define_custom_unit("m", None, &mut custom).is_err());
+        assert!(define_custom_unit("furlong", None, &mut custom).is_err());
     }
 }
@@ -1,4 +1,6 @@
+use crate::error::CalcError;
 use crate::expr::unit::Unit;
+use num::rational::Ratio;
 
 #[derive(Debug, Clone)]
 pub enum UnitExpr {
@@ -10,19 +12,19 @@ pub enum UnitExpr {
 pub enum UnitOp {
     Mul,
     Div,
-    Exp(i64),
+    Exp(Ratio<i8>),
 }
 
 impl UnitExpr {
-    pub fn eval(&self) -> Unit {
-        match self {
+    pub fn eval(&self) -> Result<Unit, CalcError> {
+        Ok(match self {
             UnitExpr::Atom(u) => u.clone(),
             UnitExpr::Cons(op, xs) => match (op, xs.as_slice()) {
-                (UnitOp::Mul, [a, b]) => a.eval() * b.eval(),
-                (UnitOp::Div, [a, b]) => a.eval() / b.eval(),
-                (UnitOp::Exp(p), [a]) => a.eval().pow(*p as i64),
+                (UnitOp::Mul, [a, b]) => (a.eval()? * b.eval()?)?,
+                (UnitOp::Div, [a, b]) => (a.eval()? / b.eval()?)?,
+                (UnitOp::Exp(p), [a]) => a.eval()?.pow(*p)?,
                 _ => panic!(),
             },
-        }
+        })
     }
 }
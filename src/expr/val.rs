@@ -1,8 +1,11 @@
+use num::complex::Complex64;
+use num::rational::{BigRational, Ratio};
 use num::traits::Pow;
+use num::{Signed, ToPrimitive, Zero};
 
 use crate::error::CalcError;
 
-use super::unit::Unit;
+use super::unit::{exact_from_float, power_ratio, Unit};
 
 use std::fmt::{self, Debug, Display, Formatter};
 
@@ -10,6 +13,17 @@ use std::fmt::{self, Debug, Display, Formatter};
 pub struct Val {
     pub num: f64,
     pub unit: Unit,
+    // standard uncertainty, kept in base units (the scale of num * mult * 10^exp)
+    // so that renormalizing num and unit never has to touch it
+    pub uncertainty: f64,
+    // significant figures of the literals this came from,
+    // None for exact values like unit conversions or constants
+    pub sig_figs: Option<u32>,
+    // the exact value in base units when it's known, i.e. everything
+    // it was computed from was rational. Only used with !exact
+    pub exact: Option<BigRational>,
+    // imaginary part, in base units like the uncertainty
+    pub imag: f64,
 }
 
 impl std::ops::Neg for Val {
@@ -18,6 +32,10 @@ impl std::ops::Neg for Val {
     fn neg(self) -> Self::Output {
         Val {
             num: self.num * -1.0,
+            exact: self.exact.as_ref().map(|r| -r),
+            // + 0.0 avoids a -0.0 imaginary part, which would put
+            // sqrt(-4) on the wrong side of the branch cut
+            imag: -self.imag + 0.0,
             ..self
         }
         .clamp_num()
@@ -26,7 +44,7 @@ impl std::ops::Neg for Val {
 
 impl PartialEq for Val {
     fn eq(&self, other: &Self) -> bool {
-        self.num == other.num && self.unit == other.unit
+        self.num == other.num && self.imag == other.imag && self.unit == other.unit
     }
 }
 
@@ -52,6 +70,19 @@ impl std::ops::Add<Val> for Val {
     type Output = Result<Val, CalcError>;
 
     fn add(self, rhs: Val) -> Self::Output {
+        // adding a difference to an absolute temperature stays on its scale
+        match (self.unit.offset != 0.0, rhs.unit.offset != 0.0) {
+            (true, true) => {
+                return Err(CalcError::UnitError(
+                    "Can't add two absolute temperatures, use deltaC or deltaF for differences"
+                        .to_string(),
+                ))
+            }
+            (true, false) => return Ok((self.without_offset() + rhs)?.with_offset(&self.unit)),
+            (false, true) => return Ok((self + rhs.without_offset())?.with_offset(&rhs.unit)),
+            (false, false) => {}
+        }
+
         if self.unit.desc == rhs.unit.desc {
             let (larger_exp, smaller_exp) = if rhs.unit.exp.abs() > self.unit.exp.abs() {
                 (&rhs, &self)
@@ -70,13 +101,18 @@ impl std::ops::Add<Val> for Val {
                 num /= 10f64.powi(num.log10() as i32);
             }
 
+            let base_num = num * larger_exp.unit.mult * 10f64.powi(exp as i32);
             Ok(Val {
                 num,
                 unit: Unit {
                     exp,
                     mult: larger_exp.unit.mult,
-                    ..self.unit
+                    ..self.unit.clone()
                 },
+                uncertainty: self.uncertainty.hypot(rhs.uncertainty),
+                sig_figs: sum_sig_figs(&self, &rhs, base_num),
+                exact: exact_op(&self.exact, &rhs.exact, |a, b| Some(a + b)),
+                imag: self.imag + rhs.imag,
             }
             .clamp_num())
         } else {
@@ -93,6 +129,19 @@ impl std::ops::Sub<Val> for Val {
     type Output = Result<Val, CalcError>;
 
     fn sub(self, rhs: Val) -> Self::Output {
+        // subtracting a difference from an absolute temperature stays on its scale,
+        // the difference between two absolute temperatures is in kelvin
+        match (self.unit.offset != 0.0, rhs.unit.offset != 0.0) {
+            (true, true) => return self.without_offset() - rhs.without_offset(),
+            (true, false) => return Ok((self.without_offset() - rhs)?.with_offset(&self.unit)),
+            (false, true) => {
+                return Err(CalcError::UnitError(
+                    "Can't subtract an absolute temperature from a difference".to_string(),
+                ))
+            }
+            (false, false) => {}
+        }
+
         if self.unit.desc == rhs.unit.desc {
             let larger_exp = if rhs.unit.exp.abs() > self.unit.exp.abs() {
                 &rhs
@@ -111,13 +160,18 @@ impl std::ops::Sub<Val> for Val {
                 num /= 10f64.powi(num.log10() as i32);
             }
 
+            let base_num = num * larger_exp.unit.mult * 10f64.powi(exp as i32);
             Ok(Val {
                 num,
                 unit: Unit {
                     exp,
                     mult: larger_exp.unit.mult,
-                    ..self.unit
+                    ..self.unit.clone()
                 },
+                uncertainty: self.uncertainty.hypot(rhs.uncertainty),
+                sig_figs: sum_sig_figs(&self, &rhs, base_num),
+                exact: exact_op(&self.exact, &rhs.exact, |a, b| Some(a - b)),
+                imag: self.imag - rhs.imag,
             }
             .clamp_num())
         } else {
@@ -131,42 +185,89 @@ impl std::ops::Sub<Val> for Val {
 }
 
 impl std::ops::Mul<Val> for Val {
-    type Output = Val;
+    type Output = Result<Val, CalcError>;
 
     fn mul(self, rhs: Val) -> Self::Output {
-        let mut new_num = self.num * rhs.num;
-        let mut new_unit = self.unit * rhs.unit;
+        let (lhs, rhs) = (self.without_offset(), rhs.without_offset());
+        let (a, b) = (lhs.base_num(), rhs.base_num());
+        let mut new_num = lhs.num * rhs.num;
+        let mut new_unit = (lhs.unit * rhs.unit)?;
+        // (a + bi)(c + di) = ac - bd + (ad + bc)i
+        new_num -= lhs.imag * rhs.imag / new_unit.scale();
+        let imag = if lhs.imag == 0.0 && rhs.imag == 0.0 {
+            0.0
+        } else {
+            a * rhs.imag + lhs.imag * b
+        };
 
         if new_num.abs() >= 10f64 {
             new_unit.exp += new_num.log10() as i64;
             new_num = new_num.signum() * (new_num / 10f64.powi(new_num.log10() as i32)).abs();
         }
 
-        Val {
+        Ok(Val {
             num: new_num,
             unit: new_unit,
+            // the uncertainty of a complex value is in its magnitude
+            uncertainty: if lhs.uncertainty == 0.0 && rhs.uncertainty == 0.0 {
+                0.0
+            } else {
+                let (a, b) = (a.hypot(lhs.imag), b.hypot(rhs.imag));
+                (b * lhs.uncertainty).hypot(a * rhs.uncertainty)
+            },
+            sig_figs: product_sig_figs(lhs.sig_figs, rhs.sig_figs),
+            exact: exact_op(&lhs.exact, &rhs.exact, |a, b| Some(a * b)),
+            imag,
         }
-        .clamp_num()
+        .clamp_num())
     }
 }
 
 impl std::ops::Div<Val> for Val {
-    type Output = Val;
+    type Output = Result<Val, CalcError>;
 
     fn div(self, rhs: Val) -> Self::Output {
-        let mut new_num = self.num / rhs.num;
-        let mut new_unit = self.unit / rhs.unit;
+        let (lhs, rhs) = (self.without_offset(), rhs.without_offset());
+        let (a, b) = (lhs.base_num(), rhs.base_num());
+        let mut new_num = lhs.num / rhs.num;
+        let mut new_unit = (lhs.unit / rhs.unit)?;
+        // (a + bi)/(c + di) = ((ac + bd) + (bc - ad)i) / (c^2 + d^2)
+        let denom = b * b + rhs.imag * rhs.imag;
+        let imag = if rhs.imag != 0.0 {
+            new_num = (a * b + lhs.imag * rhs.imag) / denom / new_unit.scale();
+            (lhs.imag * b - a * rhs.imag) / denom
+        } else if lhs.imag != 0.0 {
+            lhs.imag / b
+        } else {
+            // a real 0/0 is NaN, not NaN + NaNi
+            0.0
+        };
 
         if new_num.abs() >= 10f64 {
             new_unit.exp += new_num.log10() as i64;
             new_num = new_num.signum() * new_num / 10f64.powi(new_num.log10() as i32);
         }
 
-        Val {
+        Ok(Val {
             num: new_num,
             unit: new_unit,
+            uncertainty: if lhs.uncertainty == 0.0 && rhs.uncertainty == 0.0 {
+                0.0
+            } else {
+                let (a, b) = (a.hypot(lhs.imag), b.hypot(rhs.imag));
+                (lhs.uncertainty / b).hypot(a * rhs.uncertainty / (b * b))
+            },
+            sig_figs: product_sig_figs(lhs.sig_figs, rhs.sig_figs),
+            exact: exact_op(&lhs.exact, &rhs.exact, |a, b| {
+                if b.is_zero() {
+                    None
+                } else {
+                    Some(a / b)
+                }
+            }),
+            imag,
         }
-        .clamp_num()
+        .clamp_num())
     }
 }
 
@@ -175,17 +276,60 @@ impl Val {
         Self {
             unit: Unit::empty(),
             num: val,
+            uncertainty: 0.0,
+            sig_figs: None,
+            exact: None,
+            imag: 0.0,
         }
     }
 
-    pub fn with_unit(&self, unit: &Unit) -> Val {
-        Val {
+    pub fn with_unit(&self, unit: &Unit) -> Result<Val, CalcError> {
+        Ok(Val {
             num: self.num,
-            unit: self.unit.clone() * unit.clone(),
+            unit: (self.unit.clone() * unit.clone())?,
+            uncertainty: self.uncertainty * unit.scale(),
+            sig_figs: self.sig_figs,
+            exact: exact_op(&self.exact, &unit.exact_scale(), |a, b| Some(a * b)),
+            imag: self.imag * unit.scale(),
+        })
+    }
+
+    // The value in base units, ignoring any temperature offset
+    pub fn base_num(&self) -> f64 {
+        self.num * self.unit.mult * 10f64.powi(self.unit.exp as i32)
+    }
+
+    // The uncertainty on the same scale as num
+    pub fn num_uncertainty(&self) -> f64 {
+        self.uncertainty / self.unit.scale()
+    }
+
+    // The imaginary part on the same scale as num
+    pub fn num_imag(&self) -> f64 {
+        self.imag / self.unit.scale()
+    }
+
+    // Attaches the magnitude of rhs as an uncertainty, as in 10.2 \pm 0.1,
+    // combining it with any uncertainty self already has
+    pub fn plus_minus(&self, rhs: &Val) -> Result<Val, CalcError> {
+        if self.unit.desc != rhs.unit.desc {
+            return Err(CalcError::UnitError(format!(
+                "Uncertainty with unit {} doesn't match value with unit {}",
+                rhs.unit, self.unit
+            )));
         }
+
+        let rhs = rhs.without_offset();
+        Ok(Val {
+            uncertainty: self
+                .uncertainty
+                .hypot(rhs.base_num().abs())
+                .hypot(rhs.uncertainty),
+            ..self.clone()
+        })
     }
 
-    pub fn pow(&self, rhs: &Val) -> Val {
+    pub fn pow(&self, rhs: &Val) -> Result<Val, CalcError> {
         if rhs.unit.desc.is_empty() || rhs.num.fract() == 0.0 {
             let p = rhs.num * 10f64.powi(rhs.unit.exp as i32);
             // dbg!(self.num);
@@ -203,20 +347,109 @@ impl Val {
 
             // lazy but simple
             // Avoids a bug with exponentiating small values.
-            let scaled_num = self.num * 10f64.powi(self.unit.exp as i32) * self.unit.mult;
-            Val {
-                num: scaled_num.pow(p),
+            let base = self.without_offset();
+            let scaled_num = base.base_num();
+            // roots of negative numbers and complex powers go through Complex,
+            // so sqrt(-4) is 2i rather than NaN
+            let complex = base.imag != 0.0 || rhs.imag != 0.0;
+            let (num, imag) = if complex || (scaled_num < 0.0 && p.fract() != 0.0) {
+                let z = Complex64::new(scaled_num, base.imag);
+                let z = if rhs.imag == 0.0 && p.fract() == 0.0 {
+                    z.powi(p as i32)
+                } else {
+                    z.powc(Complex64::new(p, rhs.imag))
+                };
+                (z.re, z.im)
+            } else {
+                (scaled_num.pow(p), 0.0)
+            };
+            // first order propagation: dz = p a^(p-1) da + z ln(a) dp
+            // each term is skipped when its input is exact, so an overflowing
+            // derivative doesn't turn an exact result into inf ± NaN
+            let base_term = if base.uncertainty == 0.0 {
+                0.0
+            } else {
+                p * scaled_num.pow(p - 1.0) * base.uncertainty
+            };
+            let power_term = if rhs.uncertainty == 0.0 {
+                0.0
+            } else {
+                num * scaled_num.abs().ln() * rhs.uncertainty
+            };
+            let uncertainty = base_term.hypot(power_term);
+            // units can have fractional powers like m^(1/2), but not any power
+            let unit_power = match power_ratio(p) {
+                Some(power) => power,
+                None if self.unit.desc.is_empty() => Ratio::zero(),
+                None => {
+                    return Err(CalcError::UnitError(format!(
+                        "Can't raise a value in {} to the power {}, units can only have powers like 2, -1 or 1/2",
+                        self.unit, p
+                    )))
+                }
+            };
+            Ok(Val {
+                num,
                 unit: Unit {
-                    desc: self.unit.pow(p as i64).desc,
+                    desc: self.unit.pow(unit_power)?.desc,
                     ..Unit::default()
                 },
+                uncertainty,
+                // the exponent is treated as exact, as are powers of ten
+                // written out for scientific notation like 6.02 * 10^23
+                sig_figs: self.sig_figs.filter(|_| scaled_num != 10.0),
+                exact: exact_pow(&base.exact, &rhs.exact),
+                imag,
             }
-            .clamp_num()
+            .clamp_num())
         } else {
-            panic!()
+            Err(CalcError::UnitError(format!(
+                "Can't raise a value to the power {}, powers can't have units",
+                rhs
+            )))
         }
     }
 
+    // Converts an absolute temperature like 25 degC to kelvin,
+    // anything else is returned unchanged
+    pub fn without_offset(&self) -> Val {
+        if self.unit.offset == 0.0 {
+            return self.clone();
+        }
+
+        Val {
+            num: self.base_num() + self.unit.offset,
+            unit: Unit {
+                desc: self.unit.desc.clone(),
+                ..Unit::empty()
+            },
+            uncertainty: self.uncertainty,
+            sig_figs: self.sig_figs,
+            exact: exact_op(&self.exact, &exact_from_float(self.unit.offset), |a, b| {
+                Some(a + b)
+            }),
+            imag: self.imag,
+        }
+        .clamp_num()
+    }
+
+    // Puts a temperature onto the scale of `unit`, which may have an offset
+    pub fn with_offset(&self, unit: &Unit) -> Val {
+        let kelvin = self.without_offset();
+        let kelvin_num = kelvin.base_num();
+        Val {
+            num: (kelvin_num - unit.offset) / (unit.mult * 10f64.powi(unit.exp as i32)),
+            unit: unit.clone(),
+            uncertainty: kelvin.uncertainty,
+            sig_figs: kelvin.sig_figs,
+            exact: exact_op(&kelvin.exact, &exact_from_float(unit.offset), |a, b| {
+                Some(a - b)
+            }),
+            imag: kelvin.imag,
+        }
+        .clamp_num()
+    }
+
     pub fn clamp_num(&self) -> Val {
         if self.num == 0.0 {
             return self.clone();
@@ -231,7 +464,12 @@ impl Val {
                 mult: self.unit.mult / 10f64.powi(mult_log10 as i32),
                 exp: self.unit.exp + num_log10 + mult_log10,
                 desc: self.unit.desc.clone(),
+                offset: self.unit.offset,
             },
+            uncertainty: self.uncertainty,
+            sig_figs: self.sig_figs,
+            exact: self.exact.clone(),
+            imag: self.imag,
         };
 
         if res.num.abs() < 1.0 {
@@ -249,6 +487,61 @@ impl Val {
     }
 }
 
+// The power of ten of the leading digit of n
+pub fn order_of_magnitude(n: f64) -> i32 {
+    if n == 0.0 {
+        0
+    } else {
+        n.abs().log10().floor() as i32
+    }
+}
+
+// Products and quotients keep the fewest significant figures of their inputs
+fn product_sig_figs(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    a.into_iter().chain(b).min()
+}
+
+// Sums and differences keep the least precise decimal place of their inputs,
+// compared in base units so that e.g. 1.2 km + 30.5 m is rounded to the 100 m
+fn sum_sig_figs(lhs: &Val, rhs: &Val, result: f64) -> Option<u32> {
+    let last_place = |v: &Val| {
+        // a zero's figures are counted from the ones place of its unit
+        let magnitude = if v.num == 0.0 {
+            v.unit.scale()
+        } else {
+            v.base_num()
+        };
+        v.sig_figs
+            .map(|n| order_of_magnitude(magnitude) - n as i32 + 1)
+    };
+    let place = last_place(lhs).into_iter().chain(last_place(rhs)).max()?;
+    Some((order_of_magnitude(result) - place + 1).max(1) as u32)
+}
+
+fn exact_op(
+    a: &Option<BigRational>,
+    b: &Option<BigRational>,
+    f: impl Fn(&BigRational, &BigRational) -> Option<BigRational>,
+) -> Option<BigRational> {
+    f(a.as_ref()?, b.as_ref()?)
+}
+
+// Only integer powers stay rational, anything else falls back to floats.
+// Large exponents are left to floats as well so the numbers can't blow up.
+fn exact_pow(base: &Option<BigRational>, p: &Option<BigRational>) -> Option<BigRational> {
+    let (base, p) = (base.as_ref()?, p.as_ref()?);
+    if !p.is_integer() || p.abs() > BigRational::from_integer(1024.into()) {
+        return None;
+    }
+
+    let p = p.to_integer().to_i32()?;
+    if base.is_zero() && p < 0 {
+        None
+    } else {
+        Some(base.pow(p))
+    }
+}
+
 impl<V, U> From<(V, U)> for Val
 where
     V: Into<f64>,
@@ -258,6 +551,10 @@ where
         Self {
             unit: u.into(),
             num: v.into(),
+            uncertainty: 0.0,
+            sig_figs: None,
+            exact: None,
+            imag: 0.0,
         }
     }
 }
@@ -267,6 +564,7 @@ mod test {
 
     use super::*;
     use crate::expr::BaseUnit;
+    use std::convert::TryFrom;
 
     #[test]
     fn create_val() {
@@ -306,34 +604,136 @@ mod test {
     fn mult_val_m_m_success() {
         let val1: Val = (1.5, BaseUnit::Meter).into();
         let val2: Val = (2, BaseUnit::Meter).into();
-        assert_eq!((val1 * val2).to_string(), "3 m^2");
+        assert_eq!((val1 * val2).unwrap().to_string(), "3 m^2");
     }
 
     #[test]
     fn mult_val_m_g_success() {
         let val1: Val = (1.5, BaseUnit::Meter).into();
         let val2: Val = (2.0, BaseUnit::Gram).into();
-        assert_eq!((val1 * val2).to_string(), "3 m g");
+        assert_eq!((val1 * val2).unwrap().to_string(), "3 m g");
     }
 
     #[test]
     fn mult_val_m_s_success() {
         let val1: Val = (1.5, BaseUnit::Meter).into();
         let val2: Val = (2.0, BaseUnit::Second).into();
-        assert_eq!((val1 * val2).to_string(), "3 m s");
+        assert_eq!((val1 * val2).unwrap().to_string(), "3 m s");
     }
 
     #[test]
     fn mult_val_m_A_success() {
         let val1: Val = (1.5, BaseUnit::Meter).into();
         let val2: Val = (2.0, BaseUnit::Ampere).into();
-        assert_eq!((val1 * val2).to_string(), "3 m A");
+        assert_eq!((val1 * val2).unwrap().to_string(), "3 m A");
     }
 
     #[test]
     fn mult_val_pow_success() {
         let val1: Val = (1.5, BaseUnit::Meter).into();
         let val2: Val = (2.0, BaseUnit::Ampere).into();
-        assert_eq!(val1.pow(&val2), "2.25 m^2");
+        assert_eq!(val1.pow(&val2).unwrap(), "2.25 m^2");
+    }
+
+    #[test]
+    fn val_fractional_pow() {
+        let area =
+            (Val::from((16.0, BaseUnit::Meter)) * Val::from((1.0, BaseUnit::Meter))).unwrap();
+        assert_eq!(area.pow(&Val::empty(0.5)).unwrap(), "4 m");
+        let time: Val = (4.0, BaseUnit::Second).into();
+        assert_eq!(
+            time.pow(&Val::empty(-0.5)).unwrap().to_string(),
+            "0.5 s^-1/2"
+        );
+        assert!(time.pow(&Val::empty(0.123)).is_err());
+        assert!(time.pow(&(0.5, BaseUnit::Meter).into()).is_err());
+    }
+
+    #[test]
+    fn exact_pow_overflow() {
+        let res = Val::empty(2.0).pow(&Val::empty(10000.0)).unwrap();
+        assert!(res.num.is_infinite());
+        assert_eq!(res.uncertainty, 0.0);
+        let i = Val {
+            imag: 1.0,
+            ..Val::empty(0.0)
+        };
+        assert_eq!(i.pow(&i).unwrap().uncertainty, 0.0);
+    }
+
+    #[test]
+    fn add_absolute_temperatures_failure() {
+        let val1: Val = Val::empty(20.0)
+            .with_unit(&Unit::try_from("degC").unwrap())
+            .unwrap();
+        let val2: Val = Val::empty(30.0)
+            .with_unit(&Unit::try_from("degC").unwrap())
+            .unwrap();
+        assert!((val1 + val2).is_err());
+    }
+
+    #[test]
+    fn sub_absolute_temperatures_success() {
+        let val1: Val = Val::empty(30.0)
+            .with_unit(&Unit::try_from("degC").unwrap())
+            .unwrap();
+        let val2: Val = Val::empty(20.0)
+            .with_unit(&Unit::try_from("degC").unwrap())
+            .unwrap();
+        let diff = (val1 - val2).unwrap();
+        assert_eq!(diff.unit.offset, 0.0);
+        assert_eq!(diff.unit.desc, Unit::from(BaseUnit::Kelvin).desc);
+        assert!((diff.num * diff.unit.mult * 10f64.powi(diff.unit.exp as i32) - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sub_absolute_from_difference_failure() {
+        let val1: Val = Val::empty(10.0)
+            .with_unit(&Unit::try_from("deltaC").unwrap())
+            .unwrap();
+        let val2: Val = Val::empty(25.0)
+            .with_unit(&Unit::try_from("degC").unwrap())
+            .unwrap();
+        assert!((val1 - val2).is_err());
+    }
+
+    #[test]
+    fn mult_val_uncertainty() {
+        let val1 = Val::empty(2.0).plus_minus(&Val::empty(0.1)).unwrap();
+        let val2 = Val::empty(3.0).plus_minus(&Val::empty(0.2)).unwrap();
+        let res = (val1 * val2).unwrap();
+        assert_eq!(res, "6");
+        assert!((res.uncertainty - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mult_val_complex() {
+        let val1 = Val {
+            imag: 1.0,
+            ..Val::empty(1.0)
+        };
+        let val2 = Val {
+            imag: -1.0,
+            ..Val::empty(1.0)
+        };
+        let res = (val1 * val2).unwrap();
+        assert_eq!(res, "2");
+        assert_eq!(res.imag, 0.0);
+
+        let i = Val {
+            imag: 1.0,
+            ..Val::empty(0.0)
+        };
+        let uncertain = Val::empty(3.0).plus_minus(&Val::empty(0.1)).unwrap();
+        assert!(((uncertain.clone() * i.clone()).unwrap().uncertainty - 0.1).abs() < 1e-9);
+        assert!(((uncertain / i).unwrap().uncertainty - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn div_val_zero_by_zero() {
+        let res = (Val::empty(0.0) / Val::empty(0.0)).unwrap();
+        assert!(res.num.is_nan());
+        assert_eq!(res.imag, 0.0);
+        assert_eq!(res.uncertainty, 0.0);
     }
 }
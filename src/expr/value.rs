@@ -0,0 +1,552 @@
+use crate::error::CalcError;
+
+use num::rational::BigRational;
+use num::ToPrimitive;
+
+use super::unit::Unit;
+use super::val::Val;
+
+// The result of evaluating an expression, either a single quantity
+// or a vector or matrix of quantities which share a unit
+#[derive(Clone, Debug)]
+pub enum Value {
+    Scalar(Val),
+    Vector(Vec<Val>),
+    // stored as a list of rows
+    Matrix(Vec<Vec<Val>>),
+}
+
+impl From<Val> for Value {
+    fn from(v: Val) -> Self {
+        Value::Scalar(v)
+    }
+}
+
+impl Value {
+    pub fn vector(elems: Vec<Val>) -> Result<Value, CalcError> {
+        if let Some(first) = elems.first() {
+            if elems.iter().any(|v| v.unit.desc != first.unit.desc) {
+                return Err(CalcError::UnitError(
+                    "All elements of a vector must have the same unit".to_string(),
+                ));
+            }
+        }
+
+        Ok(Value::Vector(elems))
+    }
+
+    pub fn matrix(rows: Vec<Vec<Val>>) -> Result<Value, CalcError> {
+        if rows.iter().any(|r| r.len() != rows[0].len()) {
+            return Err(CalcError::Other(
+                "All rows of a matrix must have the same length".to_string(),
+            ));
+        }
+        if let Some(first) = rows.first().and_then(|r| r.first()) {
+            if rows
+                .iter()
+                .flatten()
+                .any(|v| v.unit.desc != first.unit.desc)
+            {
+                return Err(CalcError::UnitError(
+                    "All elements of a matrix must have the same unit".to_string(),
+                ));
+            }
+        }
+
+        Ok(Value::Matrix(rows))
+    }
+
+    // A bracketed list, which is a matrix if every element is a vector
+    pub fn from_elements(elems: Vec<Value>) -> Result<Value, CalcError> {
+        if elems.iter().all(|v| matches!(v, Value::Vector(_))) {
+            let rows = elems.into_iter().map(|v| match v {
+                Value::Vector(vs) => vs,
+                _ => unreachable!(),
+            });
+            Value::matrix(rows.collect())
+        } else {
+            let elems: Result<Vec<Val>, CalcError> = elems.into_iter().map(Value::scalar).collect();
+            Value::vector(elems?)
+        }
+    }
+
+    pub fn scalar(self) -> Result<Val, CalcError> {
+        match self {
+            Value::Scalar(v) => Ok(v),
+            Value::Vector(_) => Err(CalcError::Other(
+                "Expected a number but got a vector".to_string(),
+            )),
+            Value::Matrix(_) => Err(CalcError::Other(
+                "Expected a number but got a matrix".to_string(),
+            )),
+        }
+    }
+
+    // Every number in the value, a list's elements in order or a matrix's row by row
+    pub fn elements(self) -> Vec<Val> {
+        match self {
+            Value::Scalar(v) => vec![v],
+            Value::Vector(vs) => vs,
+            Value::Matrix(rows) => rows.into_iter().flatten().collect(),
+        }
+    }
+
+    pub fn clamp_num(&self) -> Value {
+        self.map(|v| v.clamp_num())
+    }
+
+    pub fn with_unit(&self, unit: &Unit) -> Result<Value, CalcError> {
+        self.try_map(|v| v.with_unit(unit))
+    }
+
+    pub fn pow(&self, rhs: &Value) -> Result<Value, CalcError> {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok(a.pow(b)?.into()),
+            (Value::Matrix(a), Value::Scalar(b)) => matrix_pow(a, b),
+            _ => Err(CalcError::Other(
+                "Can't raise a vector to a power".to_string(),
+            )),
+        }
+    }
+
+    pub fn plus_minus(&self, rhs: &Value) -> Result<Value, CalcError> {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok(a.plus_minus(b)?.into()),
+            _ => Err(CalcError::Other(
+                "Uncertainties can only be given for numbers".to_string(),
+            )),
+        }
+    }
+
+    fn snap_exact(&self) -> Value {
+        self.map(snap_exact)
+    }
+
+    fn map(&self, f: impl Fn(&Val) -> Val) -> Value {
+        match self {
+            Value::Scalar(v) => Value::Scalar(f(v)),
+            Value::Vector(vs) => Value::Vector(vs.iter().map(f).collect()),
+            Value::Matrix(rows) => {
+                Value::Matrix(rows.iter().map(|r| r.iter().map(&f).collect()).collect())
+            }
+        }
+    }
+
+    fn try_map(&self, f: impl Fn(&Val) -> Result<Val, CalcError>) -> Result<Value, CalcError> {
+        Ok(match self {
+            Value::Scalar(v) => Value::Scalar(f(v)?),
+            Value::Vector(vs) => Value::Vector(vs.iter().map(f).collect::<Result<_, _>>()?),
+            Value::Matrix(rows) => Value::Matrix(
+                rows.iter()
+                    .map(|r| r.iter().map(&f).collect())
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    // Applies f element-wise to two vectors of the same length
+    fn zip_with(
+        lhs: Vec<Val>,
+        rhs: Vec<Val>,
+        f: impl Fn(Val, Val) -> Result<Val, CalcError>,
+    ) -> Result<Value, CalcError> {
+        if lhs.len() != rhs.len() {
+            return Err(CalcError::Other(format!(
+                "Vectors have different lengths, {} and {}",
+                lhs.len(),
+                rhs.len()
+            )));
+        }
+
+        let elems: Result<Vec<Val>, CalcError> =
+            lhs.into_iter().zip(rhs).map(|(a, b)| f(a, b)).collect();
+        Value::vector(elems?)
+    }
+
+    // Applies f element-wise to two matrices of the same shape
+    fn zip_rows_with(
+        lhs: Vec<Vec<Val>>,
+        rhs: Vec<Vec<Val>>,
+        f: impl Fn(Val, Val) -> Result<Val, CalcError>,
+    ) -> Result<Value, CalcError> {
+        if lhs.len() != rhs.len() || lhs[0].len() != rhs[0].len() {
+            return Err(CalcError::Other(format!(
+                "Matrices have different sizes, {}x{} and {}x{}",
+                lhs.len(),
+                lhs[0].len(),
+                rhs.len(),
+                rhs[0].len()
+            )));
+        }
+
+        let rows: Result<Vec<Vec<Val>>, CalcError> = lhs
+            .into_iter()
+            .zip(rhs)
+            .map(|(a, b)| a.into_iter().zip(b).map(|(a, b)| f(a, b)).collect())
+            .collect();
+        Value::matrix(rows?)
+    }
+
+    // a \cdot b, the dot product of two vectors and a plain product otherwise
+    pub fn dot(self, rhs: Value) -> Result<Value, CalcError> {
+        match (self, rhs) {
+            (a @ Value::Vector(_), b @ Value::Vector(_)) => eval_linalg_fn("dot", vec![a, b]),
+            (a, b) => a * b,
+        }
+    }
+
+    // a \times b, the cross product of two vectors and a plain product otherwise
+    pub fn cross(self, rhs: Value) -> Result<Value, CalcError> {
+        match (self, rhs) {
+            (a @ Value::Vector(_), b @ Value::Vector(_)) => eval_linalg_fn("cross", vec![a, b]),
+            (a, b) => a * b,
+        }
+    }
+}
+
+impl std::ops::Neg for Value {
+    type Output = Value;
+
+    fn neg(self) -> Self::Output {
+        self.map(|v| -v.clone())
+    }
+}
+
+impl std::ops::Add<Value> for Value {
+    type Output = Result<Value, CalcError>;
+
+    fn add(self, rhs: Value) -> Self::Output {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok((a + b)?.into()),
+            (Value::Vector(a), Value::Vector(b)) => Value::zip_with(a, b, |a, b| a + b),
+            (Value::Matrix(a), Value::Matrix(b)) => Value::zip_rows_with(a, b, |a, b| a + b),
+            _ => Err(CalcError::Other(
+                "Can only add values of the same shape".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::ops::Sub<Value> for Value {
+    type Output = Result<Value, CalcError>;
+
+    fn sub(self, rhs: Value) -> Self::Output {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok((a - b)?.into()),
+            (Value::Vector(a), Value::Vector(b)) => Value::zip_with(a, b, |a, b| a - b),
+            (Value::Matrix(a), Value::Matrix(b)) => Value::zip_rows_with(a, b, |a, b| a - b),
+            _ => Err(CalcError::Other(
+                "Can only subtract values of the same shape".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::ops::Mul<Value> for Value {
+    type Output = Result<Value, CalcError>;
+
+    fn mul(self, rhs: Value) -> Self::Output {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok((a * b)?.into()),
+            (Value::Scalar(s), Value::Vector(vs)) | (Value::Vector(vs), Value::Scalar(s)) => {
+                Value::vector(
+                    vs.into_iter()
+                        .map(|v| s.clone() * v)
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            (Value::Scalar(s), Value::Matrix(rows)) | (Value::Matrix(rows), Value::Scalar(s)) => {
+                Value::matrix(
+                    rows.into_iter()
+                        .map(|r| r.into_iter().map(|v| s.clone() * v).collect())
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            (Value::Matrix(a), Value::Matrix(b)) => Value::matrix(matmul(&a, &b)?),
+            (Value::Matrix(a), Value::Vector(b)) => {
+                let column: Vec<Vec<Val>> = b.into_iter().map(|v| vec![v]).collect();
+                Value::vector(matmul(&a, &column)?.into_iter().flatten().collect())
+            }
+            (Value::Vector(_), Value::Vector(_)) => Err(CalcError::Other(
+                "Can't multiply two vectors, use dot or cross".to_string(),
+            )),
+            (Value::Vector(_), Value::Matrix(_)) => Err(CalcError::Other(
+                "Can't multiply a vector by a matrix".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::ops::Div<Value> for Value {
+    type Output = Result<Value, CalcError>;
+
+    fn div(self, rhs: Value) -> Self::Output {
+        match (self, rhs) {
+            (Value::Scalar(a), Value::Scalar(b)) => Ok((a / b)?.into()),
+            (Value::Vector(vs), Value::Scalar(s)) => Value::vector(
+                vs.into_iter()
+                    .map(|v| v / s.clone())
+                    .collect::<Result<_, _>>()?,
+            ),
+            (Value::Matrix(rows), Value::Scalar(s)) => Value::matrix(
+                rows.into_iter()
+                    .map(|r| r.into_iter().map(|v| v / s.clone()).collect())
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => Err(CalcError::Other(
+                "Can't divide by a vector or matrix, use inv or solve".to_string(),
+            )),
+        }
+    }
+}
+
+// Builtins which take vectors or matrices
+pub fn is_linalg_fn(name: &str) -> bool {
+    matches!(
+        name,
+        "dot" | "cross" | "norm" | "det" | "inv" | "transpose" | "solve"
+    )
+}
+
+pub fn eval_linalg_fn(name: &str, args: Vec<Value>) -> Result<Value, CalcError> {
+    match (name, args.as_slice()) {
+        ("dot", [Value::Vector(a), Value::Vector(b)]) if a.len() == b.len() => {
+            Ok(dot(a, b)?.into())
+        }
+        ("cross", [Value::Vector(a), Value::Vector(b)]) if a.len() == 3 && b.len() == 3 => {
+            let component = |i: usize, j: usize| -> Result<Val, CalcError> {
+                (a[i].clone() * b[j].clone())? - (a[j].clone() * b[i].clone())?
+            };
+            Value::vector(vec![component(1, 2)?, component(2, 0)?, component(0, 1)?])
+        }
+        ("cross", [Value::Vector(_), Value::Vector(_)]) => Err(CalcError::Other(
+            "cross takes two vectors of length 3".to_string(),
+        )),
+        ("norm", [Value::Vector(a)]) => {
+            // complex components count with their magnitude, and each
+            // component's uncertainty is weighted by its share of the norm
+            let sizes: Vec<f64> = a.iter().map(|v| v.base_num().hypot(v.imag)).collect();
+            let norm = sizes.iter().map(|m| m * m).sum::<f64>().sqrt();
+            let uncertainty = a
+                .iter()
+                .zip(&sizes)
+                .map(|(v, m)| {
+                    let weight = if norm == 0.0 { 1.0 } else { m / norm };
+                    (weight * v.uncertainty).powi(2)
+                })
+                .sum::<f64>()
+                .sqrt();
+            let unit = Unit {
+                desc: a[0].unit.desc.clone(),
+                ..Unit::empty()
+            };
+            let norm = Val {
+                uncertainty,
+                ..Val::empty(norm)
+            };
+            Ok(norm.with_unit(&unit)?.clamp_num().into())
+        }
+        ("transpose", [Value::Matrix(a)]) => Value::matrix(transpose(a)),
+        ("det", [Value::Matrix(a)]) if is_square(a) => {
+            Ok(Value::from(det(a)?).snap_exact().clamp_num())
+        }
+        ("inv", [Value::Matrix(a)]) if is_square(a) => {
+            Ok(Value::Matrix(inv(a)?).snap_exact().clamp_num())
+        }
+        ("solve", [Value::Matrix(a), Value::Vector(b)]) if is_square(a) && a.len() == b.len() => {
+            let b: Vec<Vec<Val>> = b.iter().map(|v| vec![v.clone()]).collect();
+            let x = solve(a, &b)?.into_iter().flatten().collect();
+            Ok(Value::Vector(x).snap_exact().clamp_num())
+        }
+        ("solve", [Value::Matrix(a), Value::Matrix(b)]) if is_square(a) && a.len() == b.len() => {
+            Ok(Value::Matrix(solve(a, b)?).snap_exact().clamp_num())
+        }
+        ("det" | "inv" | "solve", [Value::Matrix(_), ..]) => {
+            Err(CalcError::Other(format!("{} takes a square matrix", name)))
+        }
+        _ => Err(CalcError::Other(format!(
+            "Incorrect arguments to function {}",
+            name
+        ))),
+    }
+}
+
+// Elimination piles up floating point error, but when the inputs were
+// exact the result is too, so use that for the float value instead
+fn snap_exact(v: &Val) -> Val {
+    match v.exact.as_ref().and_then(|r| r.to_f64()) {
+        Some(x) => Val {
+            num: x / v.unit.scale(),
+            ..v.clone()
+        },
+        None => v.clone(),
+    }
+}
+
+fn is_square(a: &[Vec<Val>]) -> bool {
+    a.len() == a[0].len()
+}
+
+fn dot(a: &[Val], b: &[Val]) -> Result<Val, CalcError> {
+    let mut products = a.iter().zip(b).map(|(a, b)| a.clone() * b.clone());
+    let first = products.next().ok_or(CalcError::MathError)??;
+    Ok(snap_exact(&products.try_fold(first, |sum, p| sum + p?)?))
+}
+
+fn transpose(a: &[Vec<Val>]) -> Vec<Vec<Val>> {
+    (0..a[0].len())
+        .map(|j| a.iter().map(|r| r[j].clone()).collect())
+        .collect()
+}
+
+fn matmul(a: &[Vec<Val>], b: &[Vec<Val>]) -> Result<Vec<Vec<Val>>, CalcError> {
+    if a[0].len() != b.len() {
+        return Err(CalcError::Other(format!(
+            "Can't multiply a {}x{} matrix by a {}x{} one",
+            a.len(),
+            a[0].len(),
+            b.len(),
+            b[0].len()
+        )));
+    }
+
+    let columns = transpose(b);
+    a.iter()
+        .map(|r| columns.iter().map(|c| dot(r, c)).collect())
+        .collect()
+}
+
+fn identity(n: usize) -> Vec<Vec<Val>> {
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| {
+                    let k = (i == j) as i32;
+                    Val {
+                        exact: Some(BigRational::from_integer(k.into())),
+                        ..Val::empty(k.into())
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Gaussian elimination with partial pivoting, applying the same row
+// operations to b. Returns the sign of the row swaps, or None if a is singular.
+fn row_reduce(a: &mut [Vec<Val>], b: &mut [Vec<Val>]) -> Result<Option<f64>, CalcError> {
+    let n = a.len();
+    if a.iter().flatten().any(|v| !v.base_num().is_finite()) {
+        return Err(CalcError::MathError);
+    }
+    let largest = a
+        .iter()
+        .flatten()
+        .map(|v| v.base_num().abs())
+        .fold(0.0, f64::max);
+
+    let mut sign = 1.0;
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| {
+                let (x, y) = (a[i][col].base_num().abs(), a[j][col].base_num().abs());
+                x.total_cmp(&y)
+            })
+            .unwrap();
+        if a[pivot][col].base_num().abs() <= largest * 1e-12 {
+            return Ok(None);
+        }
+        if pivot != col {
+            a.swap(pivot, col);
+            b.swap(pivot, col);
+            sign = -sign;
+        }
+
+        let (pivot_a, pivot_b) = (a[col].clone(), b[col].clone());
+        for row in col + 1..n {
+            let factor = (a[row][col].clone() / pivot_a[col].clone())?;
+            let subtract = |x: &mut Val, p: &Val| -> Result<(), CalcError> {
+                *x = (x.clone() - (factor.clone() * p.clone())?)?;
+                Ok(())
+            };
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_a[col..]) {
+                subtract(x, p)?;
+            }
+            for (x, p) in b[row].iter_mut().zip(&pivot_b) {
+                subtract(x, p)?;
+            }
+        }
+    }
+
+    Ok(Some(sign))
+}
+
+fn det(a: &[Vec<Val>]) -> Result<Val, CalcError> {
+    let (mut a, mut b) = (a.to_vec(), vec![Vec::new(); a.len()]);
+    let sign = row_reduce(&mut a, &mut b)?;
+
+    // a singular matrix still has a determinant with units
+    let sign = sign.unwrap_or(0.0);
+    let scale = Val {
+        exact: Some(BigRational::from_integer((sign as i32).into())),
+        ..Val::empty(sign)
+    };
+    (0..a.len()).try_fold(scale, |d, i| d * a[i][i].clone())
+}
+
+// Solves a x = b for x, where b can have several columns
+fn solve(a: &[Vec<Val>], b: &[Vec<Val>]) -> Result<Vec<Vec<Val>>, CalcError> {
+    let (mut a, mut b) = (a.to_vec(), b.to_vec());
+    if row_reduce(&mut a, &mut b)?.is_none() {
+        return Err(CalcError::Other("Matrix is singular".to_string()));
+    }
+
+    // back substitution, from the last row up
+    let n = a.len();
+    let mut x: Vec<Vec<Val>> = b.clone();
+    for i in (0..n).rev() {
+        for k in 0..b[i].len() {
+            let mut rhs = b[i][k].clone();
+            for j in i + 1..n {
+                rhs = (rhs - (a[i][j].clone() * x[j][k].clone())?)?;
+            }
+            x[i][k] = (rhs / a[i][i].clone())?;
+        }
+    }
+
+    Ok(x)
+}
+
+fn inv(a: &[Vec<Val>]) -> Result<Vec<Vec<Val>>, CalcError> {
+    solve(a, &identity(a.len()))
+}
+
+// Integer powers of a square matrix, negative ones use the inverse
+fn matrix_pow(a: &[Vec<Val>], p: &Val) -> Result<Value, CalcError> {
+    let n = p.base_num();
+    if !p.unit.desc.is_empty() || n.fract() != 0.0 || !is_square(a) {
+        return Err(CalcError::Other(
+            "Matrices can only be raised to integer powers".to_string(),
+        ));
+    }
+
+    let mut base = if n < 0.0 { inv(a)? } else { a.to_vec() };
+    // like scalar powers, large ones are left to floats so the exact values can't blow up
+    if n.abs() > 1024.0 {
+        base.iter_mut().flatten().for_each(|v| v.exact = None);
+    }
+
+    // square and multiply, one bit of the exponent at a time
+    let mut res = identity(a.len());
+    let mut bits = n.abs() as u64;
+    while bits > 0 {
+        if bits & 1 == 1 {
+            res = matmul(&res, &base)?;
+        }
+        bits >>= 1;
+        if bits > 0 {
+            base = matmul(&base, &base)?;
+        }
+    }
+    Ok(Value::Matrix(res).snap_exact().clamp_num())
+}
@@ -1,15 +1,47 @@
+use crate::chem::{eval_chem_fn, is_chem_fn};
+use crate::expr::symbolic::eval_diff;
+use crate::expr::unit::exact_from_float;
+use crate::expr::value::{eval_linalg_fn, is_linalg_fn, Value};
+use crate::latex::display_prefix;
+use crate::special;
 use crate::{error::CalcError, expr::val::Val, parser::fn_call::FnCall};
 use crate::{expr::unit::Unit, expr::Expr, statement::Scope};
+use num::complex::Complex64;
+use num::rational::{BigRational, Ratio};
+use num::Signed;
+use std::convert::TryFrom;
 use std::ops::RangeInclusive;
 
+// The maximum nesting depth of user defined function calls. Since there's no
+// way to write a base case, hitting this means the functions are recursive.
+const MAX_CALL_DEPTH: usize = 128;
+// integrate always splits this many times, so a step in the integrand
+// can't hide between the first few samples
+const MIN_SIMPSON_DEPTH: u32 = 5;
+const MAX_SIMPSON_DEPTH: u32 = 40;
+// A sum or product longer than this would take too long to add up term by term
+const MAX_TERMS: i128 = 1_000_000;
+
 type FunctionArgsRange = (RangeInclusive<usize>, fn(&[f64]) -> f64, UnitBehavior);
 
+#[allow(clippy::enum_variant_names)]
 enum UnitBehavior {
     PreserveUnit,
     NoUnit,
-    // Map(Box<dyn Fn(Unit) -> Unit>), // can be used when a function changes the unit
+    // accepts any unit, but the result has none, e.g. the phase of an impedance
+    DropUnit,
+    // the result's unit is worked out from the arguments' units, which are in base units
+    Map(fn(&[Unit]) -> Result<Unit, CalcError>),
 }
 
+// Builtins of whole lists, like percentile(xs, 90). Each argument is given as its list
+// of numbers in base units, with a single number being a list of one.
+type ListFunction = (
+    usize,
+    fn(&[Vec<f64>]) -> Result<f64, CalcError>,
+    UnitBehavior,
+);
+
 macro_rules! match_unary_fn {
     ($f:expr, $u:expr, [$($name:ident),* $(,)?]) => {
         {
@@ -33,27 +65,48 @@ fn assert_units_match(args: &Vec<Val>) -> Result<(), CalcError> {
     }
 }
 
-pub fn eval_fn_call(fc: &FnCall, scope: &Scope) -> Result<Val, CalcError> {
-    let e = |a: &Expr| a.eval(scope);
-
-    let name = fc.name.as_str();
-    let args_len = fc.args.len();
+// A function defined in the document, e.g. `KE(m, v) = 1/2 * m * v^2`
+#[derive(Debug, Clone)]
+pub struct FnDef {
+    pub params: Vec<String>,
+    pub body: Expr,
+}
 
-    let fn_args_range = match_unary_fn!(
+fn get_builtin(name: &str) -> Option<FunctionArgsRange> {
+    match_unary_fn!(
         name,
         UnitBehavior::NoUnit,
-        [
-            acos, acosh, asin, asinh, atan, atanh, cbrt, cos, cosh, exp, ln, log10, log2, sin,
-            sinh, sqrt, tan, tanh
-        ]
+        [acos, acosh, asin, asinh, atan, atanh, cosh, exp, ln, log10, log2, sinh, tanh]
     )
+    .or_else(|| match_unary_fn!(name, UnitBehavior::Map(angle), [sin, cos, tan]))
     .or_else(|| match_unary_fn!(name, UnitBehavior::PreserveUnit, [abs, ceil, floor, round]))
     .or_else(|| match name {
+        "sqrt" => Some((
+            1..=1,
+            |x: &[f64]| x[0].sqrt(),
+            UnitBehavior::Map(|u| root(u, 2)),
+        )),
+        "cbrt" => Some((
+            1..=1,
+            |x: &[f64]| x[0].cbrt(),
+            UnitBehavior::Map(|u| root(u, 3)),
+        )),
+        // the angle of a point, whose coordinates can be lengths
         "atan2" => Some((
             2..=2,
             |x: &[f64]| f64::atan2(x[0], x[1]),
-            UnitBehavior::NoUnit,
+            UnitBehavior::Map(|u: &[Unit]| {
+                shared_unit("atan2", u)?;
+                Ok(Unit::empty())
+            }),
         )),
+        "arg" => Some((
+            1..=1,
+            |x: &[f64]| f64::atan2(0.0, x[0]),
+            UnitBehavior::DropUnit,
+        )),
+        "re" | "conj" => Some((1..=1, |x: &[f64]| x[0], UnitBehavior::PreserveUnit)),
+        "im" => Some((1..=1, |_: &[f64]| 0.0, UnitBehavior::PreserveUnit)),
         "min" => Some((
             1..=usize::MAX,
             |x: &[f64]| x.iter().cloned().reduce(f64::min).unwrap(),
@@ -64,14 +117,725 @@ pub fn eval_fn_call(fc: &FnCall, scope: &Scope) -> Result<Val, CalcError> {
             |x: &[f64]| x.iter().cloned().reduce(f64::max).unwrap(),
             UnitBehavior::PreserveUnit,
         )),
+        "mean" => Some((
+            1..=usize::MAX,
+            |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64,
+            UnitBehavior::PreserveUnit,
+        )),
+        // the sample standard deviation
+        "stddev" => Some((
+            2..=usize::MAX,
+            |x: &[f64]| {
+                let mean = x.iter().sum::<f64>() / x.len() as f64;
+                let ss: f64 = x.iter().map(|x| (x - mean).powi(2)).sum();
+                (ss / (x.len() - 1) as f64).sqrt()
+            },
+            UnitBehavior::PreserveUnit,
+        )),
+        "median" => Some((
+            1..=usize::MAX,
+            |x: &[f64]| percentile(x, 50.0).unwrap(),
+            UnitBehavior::PreserveUnit,
+        )),
+        "gamma" => Some((
+            1..=1,
+            |x: &[f64]| special::gamma(x[0]),
+            UnitBehavior::NoUnit,
+        )),
+        "erf" => Some((1..=1, |x: &[f64]| special::erf(x[0]), UnitBehavior::NoUnit)),
+        "beta" => Some((
+            2..=2,
+            |x: &[f64]| special::beta(x[0], x[1]),
+            UnitBehavior::NoUnit,
+        )),
+        // normcdf(x) is the standard normal distribution, and normcdf(x, mu, sigma) any other
+        "normcdf" => Some((
+            1..=3,
+            |x: &[f64]| {
+                let (mu, sigma) = (
+                    x.get(1).cloned().unwrap_or(0.0),
+                    x.get(2).cloned().unwrap_or(1.0),
+                );
+                special::norm_cdf(x[0], mu, sigma)
+            },
+            UnitBehavior::Map(|u: &[Unit]| {
+                normal_args("normcdf", u)?;
+                shared_unit("normcdf", u)?;
+                Ok(Unit::empty())
+            }),
+        )),
+        "norminv" => Some((
+            1..=3,
+            |x: &[f64]| {
+                let (mu, sigma) = (
+                    x.get(1).cloned().unwrap_or(0.0),
+                    x.get(2).cloned().unwrap_or(1.0),
+                );
+                special::norm_inv(x[0], mu, sigma)
+            },
+            UnitBehavior::Map(|u: &[Unit]| {
+                normal_args("norminv", u)?;
+                if !u[0].desc.is_empty() {
+                    return Err(CalcError::UnitError(
+                        "The probability given to norminv can't have units".to_string(),
+                    ));
+                }
+                shared_unit("norminv", &u[1..])
+            }),
+        )),
+        "binompdf" => Some((
+            3..=3,
+            |x: &[f64]| special::binom_pdf(x[0], x[1], x[2]),
+            UnitBehavior::NoUnit,
+        )),
+        "poissonpdf" => Some((
+            2..=2,
+            |x: &[f64]| special::poisson_pdf(x[0], x[1]),
+            UnitBehavior::NoUnit,
+        )),
+        "tcdf" => Some((
+            2..=2,
+            |x: &[f64]| special::t_cdf(x[0], x[1]),
+            UnitBehavior::NoUnit,
+        )),
+        _ => None,
+    })
+}
+
+fn get_list_fn(name: &str) -> Option<ListFunction> {
+    Some(match name {
+        "percentile" => (
+            2,
+            |x: &[Vec<f64>]| percentile(&x[0], single(&x[1], "percentile")?),
+            UnitBehavior::Map(|u: &[Unit]| {
+                if !u[1].desc.is_empty() {
+                    return Err(CalcError::UnitError(
+                        "The percentile can't have units".to_string(),
+                    ));
+                }
+                Ok(u[0].clone())
+            }),
+        ),
+        "slope" => (
+            2,
+            |x: &[Vec<f64>]| Ok(linear_fit(&x[0], &x[1])?.0),
+            UnitBehavior::Map(|u: &[Unit]| u[1].clone() / u[0].clone()),
+        ),
+        "intercept" => (
+            2,
+            |x: &[Vec<f64>]| Ok(linear_fit(&x[0], &x[1])?.1),
+            UnitBehavior::Map(|u: &[Unit]| Ok(u[1].clone())),
+        ),
+        // the fitted line evaluated at x
+        "linpredict" => (
+            3,
+            |x: &[Vec<f64>]| {
+                let (slope, intercept) = linear_fit(&x[0], &x[1])?;
+                Ok(slope * single(&x[2], "linpredict")? + intercept)
+            },
+            UnitBehavior::Map(|u: &[Unit]| {
+                if u[2].desc != u[0].desc {
+                    return Err(CalcError::UnitError(format!(
+                        "linpredict can't be evaluated at a value with units {}, expected {}",
+                        u[2], u[0]
+                    )));
+                }
+                Ok(u[1].clone())
+            }),
+        ),
+        _ => return None,
+    })
+}
+
+// The unit every argument has, or an error if they differ
+fn shared_unit(name: &str, units: &[Unit]) -> Result<Unit, CalcError> {
+    match units.first() {
+        Some(first) if units.iter().any(|u| u.desc != first.desc) => Err(CalcError::UnitError(
+            format!("The arguments of {} must all have the same unit", name),
+        )),
+        Some(first) => Ok(first.clone()),
+        None => Ok(Unit::empty()),
+    }
+}
+
+// The normal distribution functions take just x, which is then dimensionless,
+// or x with the mean and standard deviation
+fn normal_args(name: &str, units: &[Unit]) -> Result<(), CalcError> {
+    match units {
+        [u] if !u.desc.is_empty() => Err(CalcError::UnitError(format!(
+            "{} of a value with units needs a mean and standard deviation in the same units",
+            name
+        ))),
+        [_] | [_, _, _] => Ok(()),
+        _ => Err(CalcError::Other(format!(
+            "{} takes x, or x with a mean and standard deviation, but got {} arguments",
+            name,
+            units.len()
+        ))),
+    }
+}
+
+// The unit of sqrt or cbrt of a value, like sqrt(16 m^2) = 4 m
+fn root(units: &[Unit], n: i8) -> Result<Unit, CalcError> {
+    Ok(Unit {
+        desc: units[0].desc.pow(Ratio::new(1, n))?,
+        ..Unit::empty()
+    })
+}
+
+// Trig functions take angles, which are dimensionless, in rad, deg or rev
+fn angle(units: &[Unit]) -> Result<Unit, CalcError> {
+    if units[0].desc.is_empty() {
+        Ok(Unit::empty())
+    } else {
+        Err(CalcError::UnitError(format!(
+            "Trig functions take an angle, like 30 deg or 0.5 rad, not a value in {}",
+            units[0]
+        )))
+    }
+}
+
+fn single(x: &[f64], name: &str) -> Result<f64, CalcError> {
+    match x {
+        [x] => Ok(*x),
+        _ => Err(CalcError::Other(format!(
+            "The last argument of {} must be a single number",
+            name
+        ))),
+    }
+}
+
+fn sorted(x: &[f64]) -> Vec<f64> {
+    let mut x = x.to_vec();
+    x.sort_by(f64::total_cmp);
+    x
+}
+
+// Linearly interpolated between the closest ranks, so the 50th percentile is the median
+fn percentile(x: &[f64], p: f64) -> Result<f64, CalcError> {
+    if !(0.0..=100.0).contains(&p) {
+        return Err(CalcError::Other(format!(
+            "Percentiles must be between 0 and 100, got {}",
+            p
+        )));
+    }
+    // like the mean, a percentile of values including NaN is NaN
+    if x.iter().any(|x| x.is_nan()) {
+        return Ok(f64::NAN);
+    }
+    let x = sorted(x);
+    let rank = p / 100.0 * (x.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    Ok(x[lo] + (x[hi] - x[lo]) * (rank - lo as f64))
+}
+
+// The least squares line through the points (x, y), as its slope and intercept
+fn linear_fit(x: &[f64], y: &[f64]) -> Result<(f64, f64), CalcError> {
+    if x.len() != y.len() {
+        return Err(CalcError::Other(format!(
+            "Can't fit a line to {} x values and {} y values",
+            x.len(),
+            y.len()
+        )));
+    }
+    let n = x.len() as f64;
+    let (mean_x, mean_y) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let sxx: f64 = x.iter().map(|x| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = x
+        .iter()
+        .zip(y)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    if sxx == 0.0 {
+        return Err(CalcError::Other(
+            "Can't fit a line without at least two different x values".to_string(),
+        ));
+    }
+
+    let slope = sxy / sxx;
+    Ok((slope, mean_y - slope * mean_x))
+}
+
+// The slope and intercept of the least squares line, which is
+// the only vector whose elements can have different units
+fn eval_linfit(fc: &FnCall, scope: &Scope) -> Result<Value, CalcError> {
+    let part = |name| eval_list_fn(fc, get_list_fn(name).unwrap(), scope)?.scalar();
+    Ok(Value::Vector(vec![part("slope")?, part("intercept")?]))
+}
+
+fn eval_list_fn(fc: &FnCall, function: ListFunction, scope: &Scope) -> Result<Value, CalcError> {
+    let (args_len, calc, unit_behavior) = function;
+    let name = fc.name.as_str();
+    if fc.args.len() != args_len {
+        return Err(CalcError::Other(format!(
+            "Incorrect number of arguments to function {}, expected {} but got {}",
+            name,
+            args_len,
+            fc.args.len()
+        )));
+    }
+
+    let lists = fc
+        .args
+        .iter()
+        .map(|a| {
+            let vals: Vec<Val> = a
+                .eval(scope)?
+                .elements()
+                .iter()
+                .map(Val::without_offset)
+                .collect();
+            if vals.is_empty() {
+                return Err(CalcError::Other(format!("{} of an empty list", name)));
+            }
+            if vals.iter().any(|v| v.imag != 0.0) {
+                return Err(CalcError::Other(format!(
+                    "Can't take {} of a complex value",
+                    name
+                )));
+            }
+            Ok(vals)
+        })
+        .collect::<Result<Vec<Vec<Val>>, CalcError>>()?;
+
+    let units: Vec<Unit> = lists
+        .iter()
+        .map(|vals| Unit {
+            desc: vals[0].unit.desc.clone(),
+            ..Unit::empty()
+        })
+        .collect();
+    let unit = match unit_behavior {
+        UnitBehavior::PreserveUnit => units[0].clone(),
+        UnitBehavior::NoUnit | UnitBehavior::DropUnit => Unit::empty(),
+        UnitBehavior::Map(f) => f(&units)?,
+    };
+
+    let nums: Vec<Vec<f64>> = lists
+        .iter()
+        .map(|vals| vals.iter().map(Val::base_num).collect())
+        .collect();
+    let res = Val {
+        sig_figs: lists.iter().flatten().filter_map(|v| v.sig_figs).min(),
+        ..Val::empty(calc(&nums)?)
+    };
+    Ok(res.with_unit(&unit)?.clamp_num().into())
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    get_builtin(name).is_some()
+        || get_list_fn(name).is_some()
+        || name == "linfit"
+        || is_linalg_fn(name)
+        || is_lazy_fn(name)
+        || is_chem_fn(name)
+}
+
+// Builtins which take an unevaluated expression and the variable it's in terms of
+fn is_lazy_fn(name: &str) -> bool {
+    matches!(name, "integrate" | "derivative" | "sum" | "prod" | "diff")
+}
+
+// The expression argument of a calculus builtin as a function of
+// its variable, with the variable given in base units
+struct Integrand<'a> {
+    expr: &'a Expr,
+    var: &'a str,
+    var_unit: Unit,
+    scope: &'a Scope<'a>,
+}
+
+impl Integrand<'_> {
+    fn eval_at(&self, x: f64) -> Result<Val, CalcError> {
+        let mut scope = self.scope.child();
+        let bound = Val::empty(x).with_unit(&self.var_unit)?.clamp_num();
+        scope.variables.insert(self.var.to_string(), bound.into());
+
+        let v = self.expr.eval(&scope)?.scalar()?.without_offset();
+        if v.imag != 0.0 {
+            return Err(CalcError::Other(format!(
+                "Can't integrate or differentiate {}, it's complex at {} = {}",
+                self.var, self.var, x
+            )));
+        }
+        Ok(v)
+    }
+
+    fn at(&self, x: f64) -> Result<f64, CalcError> {
+        Ok(self.eval_at(x)?.base_num())
+    }
+
+    // Adaptive Simpson's rule, splitting intervals until the estimate stops changing.
+    // tol is the absolute error allowed on this interval, halved with each split.
+    fn simpson(
+        &self,
+        (a, fa): (f64, f64),
+        (b, fb): (f64, f64),
+        fm: f64,
+        whole: f64,
+        tol: f64,
+        depth: u32,
+    ) -> Result<f64, CalcError> {
+        let m = (a + b) / 2.0;
+        let (lm, rm) = ((a + m) / 2.0, (m + b) / 2.0);
+        let (flm, frm) = (self.at(lm)?, self.at(rm)?);
+        let left = (m - a) / 6.0 * (fa + 4.0 * flm + fm);
+        let right = (b - m) / 6.0 * (fm + 4.0 * frm + fb);
+
+        let delta = left + right - whole;
+        let converged =
+            delta.abs() <= 15.0 * tol || delta.abs() <= 1e-10 * (left + right).abs().max(1e-300);
+        if depth == MAX_SIMPSON_DEPTH || (depth >= MIN_SIMPSON_DEPTH && converged) {
+            return Ok(left + right + delta / 15.0);
+        }
+
+        Ok(
+            self.simpson((a, fa), (m, fm), flm, left, tol / 2.0, depth + 1)?
+                + self.simpson((m, fm), (b, fb), frm, right, tol / 2.0, depth + 1)?,
+        )
+    }
+}
+
+// sum(i, 1, 10, i^2) or prod(i, 1, 10, i^2), with the index bound in a child scope,
+// or the sum or product of a list like sum(d_x)
+fn eval_series(fc: &FnCall, scope: &Scope) -> Result<Value, CalcError> {
+    let name = fc.name.as_str();
+    let (index, start, end, body) = match fc.args.as_slice() {
+        [list] => {
+            // an empty list gives the identity
+            let mut terms = list.eval(scope)?.elements().into_iter().map(Value::Scalar);
+            let first = terms
+                .next()
+                .unwrap_or_else(|| Val::empty(if name == "sum" { 0.0 } else { 1.0 }).into());
+            return terms.try_fold(
+                first,
+                |acc, v| if name == "sum" { acc + v } else { acc * v },
+            );
+        }
+        [Expr::Ident(index), start, end, body] => (index, start, end, body),
+        [_, _, _, _] => {
+            return Err(CalcError::Other(format!(
+                "The first argument of {} must be the index, like {}(i, 1, 10, i^2)",
+                name, name
+            )))
+        }
+        _ => {
+            return Err(CalcError::Other(format!(
+                "Incorrect number of arguments to function {}, expected 4 but got {}",
+                name,
+                fc.args.len()
+            )))
+        }
+    };
+
+    let bound = |e: &Expr| -> Result<i64, CalcError> {
+        let v = e.eval(scope)?.scalar()?;
+        let n = v.base_num();
+        if !v.unit.desc.is_empty() || n.fract() != 0.0 {
+            return Err(CalcError::Other(format!(
+                "The limits of {} must be whole numbers without units",
+                name
+            )));
+        }
+        Ok(n as i64)
+    };
+    let (start, end) = (bound(start)?, bound(end)?);
+    let terms = end as i128 - start as i128 + 1;
+    if terms > MAX_TERMS {
+        return Err(CalcError::Other(format!(
+            "{} would have {} terms, at most {} are allowed",
+            name, terms, MAX_TERMS
+        )));
+    }
+
+    // an empty range gives the identity
+    let mut res: Value = Val::empty(if name == "sum" { 0.0 } else { 1.0 }).into();
+    let mut child = scope.child();
+    for (n, k) in (start..=end).enumerate() {
+        let k = Val {
+            exact: Some(BigRational::from_integer(k.into())),
+            ..Val::empty(k as f64)
+        };
+        child.variables.insert(index.clone(), k.into());
+        let term = body.eval(&child)?;
+        res = match (n, name) {
+            (0, _) => term,
+            (_, "sum") => (res + term)?,
+            _ => (res * term)?,
+        };
+    }
+    Ok(res)
+}
+
+fn eval_lazy_fn(fc: &FnCall, scope: &Scope) -> Result<Value, CalcError> {
+    let name = fc.name.as_str();
+    if matches!(name, "sum" | "prod") {
+        return eval_series(fc, scope);
+    }
+    if name == "diff" {
+        return eval_diff(fc, scope)?.eval(scope);
+    }
+
+    let var = match fc.args.get(1) {
+        Some(Expr::Ident(var)) => var,
+        _ => {
+            return Err(CalcError::Other(format!(
+                "The second argument of {} must be the variable, like {}(x^2, x, ...)",
+                name, name
+            )))
+        }
+    };
+
+    let e = |a: &Expr| {
+        a.eval(scope)
+            .and_then(Value::scalar)
+            .map(|v| v.without_offset())
+    };
+    let base = |u: &Unit| Unit {
+        desc: u.desc.clone(),
+        ..Unit::empty()
+    };
+
+    match (name, fc.args.as_slice()) {
+        ("integrate", [expr, _, a, b]) => {
+            let (a, b) = (e(a)?, e(b)?);
+            if a.unit.desc != b.unit.desc {
+                return Err(CalcError::UnitError(format!(
+                    "The limits of integration have different units, {} and {}",
+                    a.unit, b.unit
+                )));
+            }
+
+            let f = Integrand {
+                expr,
+                var,
+                var_unit: base(&a.unit),
+                scope,
+            };
+            let (a, b) = (a.base_num(), b.base_num());
+            let (fa, fm, fb) = (f.eval_at(a)?, f.at((a + b) / 2.0)?, f.at(b)?);
+            let whole = (b - a) / 6.0 * (fa.base_num() + 4.0 * fm + fb);
+            // the absolute tolerance is relative to the size of the integrand
+            let size = fa.base_num().abs().max(fm.abs()).max(fb.abs());
+            let tol = 1e-12 * (b - a).abs() * size;
+            let res = f.simpson((a, fa.base_num()), (b, fb), fm, whole, tol, 0)?;
+            // e.g. 1/sqrt(x) from 0, where the integrand blows up at a limit
+            if !res.is_finite() {
+                return Err(CalcError::MathError);
+            }
+
+            // integrating over x multiplies by its unit
+            let unit = (base(&fa.unit) * f.var_unit)?;
+            Ok(Val::empty(res).with_unit(&unit)?.clamp_num().into())
+        }
+        ("derivative", [expr, _, at]) => {
+            let at = e(at)?;
+            let f = Integrand {
+                expr,
+                var,
+                var_unit: base(&at.unit),
+                scope,
+            };
+
+            // five point central difference, with the step balancing
+            // truncation and rounding error. The step is relative to x, or at
+            // zero to the unit it's given in, so 0 nm takes nanometre steps
+            let x = at.base_num();
+            let size = if x == 0.0 { at.unit.scale() } else { x.abs() };
+            let h = f64::EPSILON.powf(0.2) * size;
+            let above = f.eval_at(x + h)?;
+            let res = (8.0 * (above.base_num() - f.at(x - h)?) - f.at(x + 2.0 * h)?
+                + f.at(x - 2.0 * h)?)
+                / (12.0 * h);
+
+            let unit = (base(&above.unit) / f.var_unit)?;
+            Ok(Val::empty(res).with_unit(&unit)?.clamp_num().into())
+        }
+        _ => Err(CalcError::Other(format!(
+            "Incorrect number of arguments to function {}",
+            name
+        ))),
+    }
+}
+
+// The unit a value is shown in, so round(25.4 degC) is 25 degC rather than 298 K
+fn own_unit(v: &Val) -> Unit {
+    // absolute temperatures are on a named scale, whose degree size
+    // is mixed up with the value's magnitude once it's put onto it
+    if v.unit.offset != 0.0 {
+        let scale = ["degC", "degF"]
+            .iter()
+            .filter_map(|name| Unit::try_from(*name).ok())
+            .find(|scale| scale.offset == v.unit.offset);
+        if let Some(scale) = scale {
+            return scale;
+        }
+    }
+
+    if v.unit.mult != 1.0 {
+        v.unit.clone()
+    } else {
+        Unit {
+            exp: display_prefix(v),
+            ..v.unit.clone()
+        }
+    }
+}
+
+// Builtins that stay rational in exact mode, anything
+// transcendental falls back to floats
+fn exact_builtin(name: &str, args: &[BigRational]) -> Option<BigRational> {
+    match name {
+        "abs" => Some(args[0].abs()),
+        "floor" => Some(args[0].floor()),
+        "ceil" => Some(args[0].ceil()),
+        "round" => Some(args[0].round()),
+        "min" => args.iter().min().cloned(),
+        "max" => args.iter().max().cloned(),
+        "mean" => {
+            let len = BigRational::from_integer(args.len().into());
+            Some(args.iter().sum::<BigRational>() / len)
+        }
         _ => None,
-    });
+    }
+}
+
+// Builtins which work on a single complex argument
+fn complex_builtin(name: &str, args: &[Val]) -> Option<Complex64> {
+    let z = Complex64::new(args[0].base_num(), args[0].imag);
+    Some(match name {
+        "abs" => z.norm().into(),
+        "arg" => z.arg().into(),
+        "conj" => z.conj(),
+        "re" => z.re.into(),
+        "im" => z.im.into(),
+        "exp" => z.exp(),
+        "ln" => z.ln(),
+        "sqrt" => z.sqrt(),
+        "cbrt" => z.cbrt(),
+        "sin" => z.sin(),
+        "cos" => z.cos(),
+        "tan" => z.tan(),
+        "sinh" => z.sinh(),
+        "cosh" => z.cosh(),
+        "tanh" => z.tanh(),
+        _ => return None,
+    })
+}
+
+// First order uncertainty of a builtin, using a central difference
+// for the partial derivative with respect to each uncertain argument
+fn propagate_uncertainty(calc: fn(&[f64]) -> f64, args: &[f64], uncertainties: &[f64]) -> f64 {
+    let mut args = args.to_vec();
+    let mut total = 0.0f64;
+    for (i, &u) in uncertainties.iter().enumerate() {
+        if u == 0.0 {
+            continue;
+        }
+
+        let x = args[i];
+        let h = 1e-6 * x.abs().max(u);
+        args[i] = x + h;
+        let above = calc(&args);
+        args[i] = x - h;
+        let below = calc(&args);
+        args[i] = x;
+
+        total = total.hypot((above - below) / (2.0 * h) * u);
+    }
+    total
+}
+
+fn eval_user_fn(fc: &FnCall, fn_def: &FnDef, scope: &Scope) -> Result<Value, CalcError> {
+    if fc.args.len() != fn_def.params.len() {
+        return Err(CalcError::Other(format!(
+            "Incorrect number of arguments to function {}, expected {} but got {}",
+            fc.name,
+            fn_def.params.len(),
+            fc.args.len()
+        )));
+    }
+
+    if scope.depth >= MAX_CALL_DEPTH {
+        return Err(CalcError::Other(format!(
+            "Function {} is recursive",
+            fc.name
+        )));
+    }
+
+    // arguments are evaluated in the caller's scope, but the body only sees them over the
+    // document's variables, so it can't pick up whatever the caller happens to call x
+    let global = scope.global();
+    let mut fn_scope = global.child();
+    fn_scope.depth = scope.depth + 1;
+    for (param, arg) in fn_def.params.iter().zip(fc.args.iter()) {
+        fn_scope.variables.insert(param.clone(), arg.eval(scope)?);
+    }
+
+    fn_def.body.eval(&fn_scope)
+}
+
+pub fn eval_fn_call(fc: &FnCall, scope: &Scope) -> Result<Value, CalcError> {
+    let e = |a: &Expr| a.eval(scope);
+
+    let name = fc.name.as_str();
 
     // TODO: Handle values with units
-    if let Some((args_range, calc, unit_behavior)) = fn_args_range {
+    // these take an expression, which is evaluated as their variable changes
+    if is_lazy_fn(name) {
+        eval_lazy_fn(fc, scope)
+    } else if is_chem_fn(name) {
+        eval_chem_fn(fc)
+    } else if is_linalg_fn(name) {
+        let args: Result<Vec<Value>, CalcError> = fc.args.iter().map(e).collect();
+        eval_linalg_fn(name, args?)
+    } else if let Some(list_fn) = get_list_fn(name) {
+        eval_list_fn(fc, list_fn, scope)
+    } else if name == "linfit" {
+        eval_linfit(fc, scope)
+    } else if let Some((args_range, calc, unit_behavior)) = get_builtin(name) {
+        // functions of any number of arguments also take lists, like max(d_x)
+        let variadic = *args_range.end() == usize::MAX;
+        let evaled_args: Vec<Value> = fc.args.iter().map(e).collect::<Result<_, _>>()?;
+        let evaled_args: Vec<Value> = if variadic {
+            evaled_args
+                .into_iter()
+                .flat_map(|v| v.elements())
+                .map(Value::Scalar)
+                .collect()
+        } else {
+            evaled_args
+        };
+        let args_len = evaled_args.len();
+
         if args_range.contains(&args_len) {
-            let evaled_args: Result<Vec<Val>, CalcError> = fc.args.iter().map(|a| e(&a)).collect();
+            let evaled_args: Result<Vec<Val>, CalcError> =
+                evaled_args.into_iter().map(Value::scalar).collect();
             let evaled_args = evaled_args?;
+
+            // builtins keeping the unit work in the first argument's own unit, so
+            // round(2.6 km) is 3 km rather than 2600 m rounded, and the rest work in
+            // base units and kelvin
+            let unit: Result<Unit, CalcError> = match unit_behavior {
+                UnitBehavior::NoUnit | UnitBehavior::DropUnit => Ok(Unit::empty()),
+                UnitBehavior::PreserveUnit => {
+                    assert_units_match(&evaled_args)?;
+                    Ok(own_unit(&evaled_args[0]))
+                }
+                UnitBehavior::Map(f) => {
+                    let units: Vec<Unit> = evaled_args
+                        .iter()
+                        .map(|a| Unit {
+                            desc: a.unit.desc.clone(),
+                            ..Unit::empty()
+                        })
+                        .collect();
+                    f(&units)
+                }
+            };
+
+            let unit = unit?;
+
             let args: Result<Vec<f64>, CalcError> = evaled_args
                 .iter()
                 .map(|a| {
@@ -81,30 +845,72 @@ pub fn eval_fn_call(fc: &FnCall, scope: &Scope) -> Result<Val, CalcError> {
                             fc.name
                         )))
                     } else {
-                        Ok(a.num * a.unit.mult * 10f64.powi(a.unit.exp as i32))
+                        Ok((a.base_num() + a.unit.offset - unit.offset) / unit.scale())
                     }
                 })
                 .collect();
             let args = args?;
+            let result = calc(args.as_slice());
 
-            let unit: Result<Unit, CalcError> = match unit_behavior {
-                UnitBehavior::NoUnit => Ok(Unit::empty()),
-                UnitBehavior::PreserveUnit => {
-                    assert_units_match(&evaled_args)?;
-                    let mut u = evaled_args[0].unit.clone();
-                    u.exp = 0;
-                    Ok(u)
-                } // UnitBehavior::Map(_) => todo!(),
-            };
+            // complex arguments, or real ones without a real result like sqrt(-4),
+            // but a NaN argument like sqrt(0/0) just gives NaN
+            let complex = evaled_args.iter().any(|a| a.imag != 0.0);
+            if complex || (result.is_nan() && !args.iter().any(|a| a.is_nan())) {
+                let kelvin: Vec<Val> = evaled_args.iter().map(Val::without_offset).collect();
+                match complex_builtin(name, &kelvin) {
+                    Some(z) => {
+                        let res = Val {
+                            imag: z.im,
+                            ..(z.re / unit.scale(), unit).into()
+                        };
+                        return Ok(res.clamp_num().into());
+                    }
+                    None if complex => {
+                        return Err(CalcError::Other(format!(
+                            "Can't take {} of a complex value",
+                            name
+                        )))
+                    }
+                    None => {}
+                }
+            }
 
-            let res: Val = (calc(args.as_slice()), unit?).into();
-            Ok(res.clamp_num())
+            let uncertainties: Vec<f64> = evaled_args
+                .iter()
+                .map(|a| a.uncertainty / unit.scale())
+                .collect();
+            let uncertainty = propagate_uncertainty(calc, &args, &uncertainties)
+                * unit.mult
+                * 10f64.powi(unit.exp as i32);
+
+            let res = Val {
+                uncertainty,
+                sig_figs: evaled_args.iter().filter_map(|a| a.sig_figs).min(),
+                exact: evaled_args
+                    .iter()
+                    .map(|a| {
+                        let offset = exact_from_float(a.unit.offset - unit.offset)?;
+                        Some((a.exact.clone()? + offset) / unit.exact_scale()?)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|exact_args| exact_builtin(name, &exact_args))
+                    .and_then(|r| Some(r * unit.exact_scale()?)),
+                ..(result, unit).into()
+            };
+            Ok(res.clamp_num().into())
         } else {
+            let expected = match (*args_range.start(), *args_range.end()) {
+                (min, usize::MAX) => format!("at least {}", min),
+                (min, max) if min == max => min.to_string(),
+                (min, max) => format!("{} to {}", min, max),
+            };
             Err(CalcError::Other(format!(
-                "Incorrect number of arguments to function {}, expected {:?} but got {}",
-                name, args_range, args_len
+                "Incorrect number of arguments to function {}, expected {} but got {}",
+                name, expected, args_len
             )))
         }
+    } else if let Some(fn_def) = scope.function(name) {
+        eval_user_fn(fc, fn_def, scope)
     } else {
         Err(CalcError::Other(format!("Unknown function {}", name)))
     }
@@ -3,16 +3,16 @@ use crate::{
     expr::bool_expr::{BoolExpr, BoolOp},
     parser::naive_string::StringExpr,
 };
+use crate::chem::{is_chem_fn, Formula};
 use crate::{parser::fn_call::FnCall, CalcError};
 
-use crate::expr::unit::BASE_UNITS;
 use crate::expr::unit::UNIT_PREFIXES_ABBR;
-use crate::expr::unit::{BaseUnit, UnitDesc};
-use crate::expr::{unit::Unit, val::Val, Expr, Op};
+use crate::expr::val::order_of_magnitude;
+use crate::expr::{unit::Unit, val::Val, value::Value, Expr, Op};
 use num::One;
 use num::Signed;
 use num::Zero;
-use num::{rational::Ratio, ToPrimitive};
+use num::{rational::BigRational, rational::Ratio, ToPrimitive};
 
 // The plan I had in mind when I started this was for LaTeX to be a proper
 // LaTeX subset AST.
@@ -35,6 +35,12 @@ pub struct FormatArgs {
     pub unit_hint: Option<UnitHint>,
     pub max_digits: usize,
     pub scientific_notation: bool,
+    // round to each value's significant figures instead of max_digits
+    pub sig_figs: bool,
+    // print values as fractions when they're known exactly
+    pub exact: bool,
+    // print values as phasors, magnitude and angle
+    pub polar: bool,
 }
 
 impl Default for FormatArgs {
@@ -43,6 +49,9 @@ impl Default for FormatArgs {
             unit_hint: None,
             max_digits: 3,
             scientific_notation: false,
+            sig_figs: false,
+            exact: false,
+            polar: false,
         }
     }
 }
@@ -66,10 +75,43 @@ impl ToString for LaTeX {
 impl ToLaTeX for Expr {
     fn to_latex_ext(&self, args: &FormatArgs) -> Result<LaTeX, CalcError> {
         Ok(match self {
+            // number literals are printed in full, as decimals
+            Expr::Atom(Val { exact: Some(r), .. }) if args.exact => {
+                LaTeX::Math(format_exact_decimal(r))
+            }
             Expr::Atom(v) => LaTeX::Math(v.to_latex_ext(args)?.to_string()),
             Expr::ParenExpr(v) => LaTeX::Math(format!("({})", v.to_latex_ext(args)?.to_string())),
             Expr::Ident(n) => LaTeX::Math(n.to_string()),
+            Expr::Formula(f) => Formula::parse(f)?.to_latex()?,
             Expr::FnCall(f) => LaTeX::Math(f.to_latex_ext(args)?.to_string()),
+            // a list of lists is a matrix, written row by row
+            Expr::Vector(es) if es.iter().all(|e| matches!(e, Expr::Vector(_))) => {
+                let rows = es
+                    .iter()
+                    .map(|r| match r {
+                        Expr::Vector(r) => Ok(r
+                            .iter()
+                            .map(|e| Ok(e.to_latex_ext(args)?.to_string()))
+                            .collect::<Result<Vec<String>, CalcError>>()?
+                            .join(" & ")),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Result<Vec<String>, CalcError>>()?;
+                LaTeX::Math(format!(
+                    "\\begin{{bmatrix}} {} \\end{{bmatrix}}",
+                    rows.join(" \\\\ ")
+                ))
+            }
+            Expr::Vector(es) => {
+                let elems = es
+                    .iter()
+                    .map(|e| Ok(e.to_latex_ext(args)?.to_string()))
+                    .collect::<Result<Vec<String>, CalcError>>()?;
+                LaTeX::Math(format!(
+                    "\\begin{{pmatrix}} {} \\end{{pmatrix}}",
+                    elems.join(" \\\\ ")
+                ))
+            }
             Expr::Cons(op, e) => match (op, e.as_slice()) {
                 (Op::Plus, [a, b, ..]) => LaTeX::Math(format!(
                     "{} + {}",
@@ -97,6 +139,31 @@ impl ToLaTeX for Expr {
                     a.to_latex_ext(args)?.to_string(),
                     b.remove_parens().to_latex_ext(args)?.to_string()
                 )),
+                (Op::PlusMinus, [a, b, ..]) => LaTeX::Math(format!(
+                    "{} \\pm {}",
+                    a.to_latex_ext(args)?.to_string(),
+                    b.to_latex_ext(args)?.to_string()
+                )),
+                (Op::Dot, [a, b, ..]) => LaTeX::Math(format!(
+                    "{} \\cdot {}",
+                    a.to_latex_ext(args)?.to_string(),
+                    b.to_latex_ext(args)?.to_string()
+                )),
+                (Op::Cross, [a, b, ..]) => LaTeX::Math(format!(
+                    "{} \\times {}",
+                    a.to_latex_ext(args)?.to_string(),
+                    b.to_latex_ext(args)?.to_string()
+                )),
+                (Op::Norm, [a]) => LaTeX::Math(format!(
+                    "\\left| {} \\right|",
+                    a.to_latex_ext(args)?.to_string()
+                )),
+                // 10.2 \pm 0.1 kg applies the unit to both halves
+                (Op::AddUnit(_, s), [v @ Expr::Cons(Op::PlusMinus, _)]) => LaTeX::Math(format!(
+                    "({})\\ \\mathrm{{{}}}",
+                    v.to_latex_ext(args)?.to_string(),
+                    s
+                )),
                 (Op::AddUnit(_, s), [v]) => LaTeX::Math(format!(
                     "{}\\ \\mathrm{{{}}}",
                     v.to_latex_ext(args)?.to_string(),
@@ -169,40 +236,70 @@ impl ToLaTeX for UnitExpr {
 
 impl ToLaTeX for Val {
     fn to_latex_ext(&self, args: &FormatArgs) -> Result<LaTeX, CalcError> {
+        let sig_figs = self.sig_figs.filter(|_| args.sig_figs);
+        // with !exact, values known exactly are printed as fractions in units of `scale`
+        // instead, and scientific notation doesn't apply to them
+        let exact_in = |scale: Option<BigRational>| {
+            let exact = self
+                .exact
+                .as_ref()
+                .filter(|_| args.exact && self.imag == 0.0)?;
+            Some(format_exact(&(exact / scale?)))
+        };
+        let scientific_notation = args.scientific_notation && exact_in(Some(One::one())).is_none();
+
         Ok(match &args.unit_hint {
+            // convert between temperature scales before formatting
+            Some(UnitHint { unit, .. })
+                if unit.desc == self.unit.desc && unit.offset != self.unit.offset =>
+            {
+                return self.with_offset(unit).to_latex_ext(args)
+            }
+            None if self.unit.offset != 0.0 => return self.without_offset().to_latex_ext(args),
+            // dimensionless units like deg have no symbol to print, so they're plain numbers
+            None if self.unit.desc.is_empty() && self.unit.mult != 1.0 => {
+                let plain = Val {
+                    num: self.num * self.unit.mult,
+                    unit: Unit {
+                        exp: self.unit.exp,
+                        ..Unit::empty()
+                    },
+                    ..self.clone()
+                };
+                return plain.clamp_num().to_latex_ext(args);
+            }
             Some(UnitHint {
                 unit,
                 pretty_string,
             }) if unit.desc == self.unit.desc => {
-                let out = if args.scientific_notation && self.unit.exp != unit.exp {
-                    let max_digits = if self.num.fract() == 0.0 {
-                        0
-                    } else {
-                        args.max_digits
-                    };
-
+                let out = if scientific_notation && self.unit.exp != unit.exp {
                     format!(
-                        "{:.*} \\times 10^{{{}}} \\ {} ",
-                        max_digits,
-                        self.num,
+                        "{} \\times 10^{{{}}} \\ {} ",
+                        format_num(
+                            self.num,
+                            self.num_imag(),
+                            self.num_uncertainty(),
+                            sig_figs,
+                            args
+                        ),
                         self.unit.exp - unit.exp,
                         pretty_string.to_latex()?.to_string()
                     )
                 } else {
-                    let num = self.num
-                        / 10f64.powi((unit.exp - self.unit.exp) as i32)
-                        / (unit.mult / self.unit.mult);
-
-                    let max_digits = if num.fract() == 0.0 {
-                        0
-                    } else {
-                        args.max_digits
+                    let convert = |n: f64| {
+                        n / 10f64.powi((unit.exp - self.unit.exp) as i32)
+                            / (unit.mult / self.unit.mult)
                     };
 
                     format!(
-                        "{:.*} \\ {}",
-                        max_digits,
-                        num,
+                        "{} \\ {}",
+                        exact_in(unit.exact_scale()).unwrap_or_else(|| format_num(
+                            convert(self.num),
+                            convert(self.num_imag()),
+                            convert(self.num_uncertainty()),
+                            sig_figs,
+                            args
+                        )),
                         pretty_string.to_latex()?.to_string()
                     )
                 };
@@ -217,71 +314,66 @@ impl ToLaTeX for Val {
             }
             None => {
                 let out = {
-                    // TODO don't round this
-                    let largest_power = self.unit.desc.largest_power().round().to_i64().unwrap();
-
-                    let mut display_exp = (self.unit.exp / largest_power.max(1)).clamp(-3, 3);
-                    if display_exp == 1 || display_exp == 2 {
-                        display_exp = 0;
-                    }
-
-                    let unit_str = Unit {
-                        exp: display_exp,
-                        ..self.unit.clone()
-                    }
-                    .to_latex()?
-                    .to_string();
+                    let (display_exp, unit_str) = display_unit(self)?;
 
                     if !unit_str.is_empty() {
-                        if args.scientific_notation && self.unit.exp != 0 {
-                            let num = self.num * self.unit.mult;
-
-                            let max_digits = if num.fract() == 0.0 {
-                                0
-                            } else {
-                                args.max_digits
-                            };
+                        if scientific_notation && self.unit.exp != 0 {
+                            let scale = self.unit.mult;
 
                             format!(
-                                "{:.*}\\times 10^{{{}}} \\ {}",
-                                max_digits,
-                                num,
-                                self.unit.exp - display_exp * largest_power,
+                                "{}\\times 10^{{{}}} \\ {}",
+                                format_num(
+                                    self.num * scale,
+                                    self.num_imag() * scale,
+                                    self.num_uncertainty() * scale,
+                                    sig_figs,
+                                    args,
+                                ),
+                                self.unit.exp - display_exp,
                                 unit_str
                             )
                         } else {
-                            let num = self.num
-                                * self.unit.mult
-                                * 10f64.powi((self.unit.exp - display_exp * largest_power) as i32);
-
-                            let max_digits = if num.fract() == 0.0 {
-                                0
-                            } else {
-                                args.max_digits
-                            };
+                            let scale =
+                                self.unit.mult * 10f64.powi((self.unit.exp - display_exp) as i32);
 
-                            format!("{:.*} \\ {}", max_digits, num, unit_str)
+                            let ten = BigRational::from_integer(10.into());
+                            format!(
+                                "{} \\ {}",
+                                exact_in(Some(ten.pow(display_exp as i32))).unwrap_or_else(|| {
+                                    format_num(
+                                        self.num * scale,
+                                        self.num_imag() * scale,
+                                        self.num_uncertainty() * scale,
+                                        sig_figs,
+                                        args,
+                                    )
+                                }),
+                                unit_str
+                            )
                         }
-                    } else if args.scientific_notation && self.unit.exp != 0 {
-                        let max_digits = if self.num.fract() == 0.0 {
-                            0
-                        } else {
-                            args.max_digits
-                        };
-
+                    } else if scientific_notation && self.unit.exp != 0 {
                         format!(
-                            "{:.*}\\times 10^{{{}}}",
-                            max_digits, self.num, self.unit.exp
+                            "{}\\times 10^{{{}}}",
+                            format_num(
+                                self.num,
+                                self.num_imag(),
+                                self.num_uncertainty(),
+                                sig_figs,
+                                args
+                            ),
+                            self.unit.exp
                         )
                     } else {
-                        let num = self.num * 10f64.powi(self.unit.exp as i32);
-                        let max_digits = if num.fract() == 0.0 {
-                            0
-                        } else {
-                            args.max_digits
-                        };
-
-                        format!("{:.*}", max_digits, num)
+                        let scale = 10f64.powi(self.unit.exp as i32);
+                        exact_in(Some(One::one())).unwrap_or_else(|| {
+                            format_num(
+                                self.num * scale,
+                                self.num_imag() * scale,
+                                self.num_uncertainty() * scale,
+                                sig_figs,
+                                args,
+                            )
+                        })
                     }
                 };
 
@@ -291,17 +383,249 @@ impl ToLaTeX for Val {
     }
 }
 
+impl ToLaTeX for Value {
+    fn to_latex_ext(&self, args: &FormatArgs) -> Result<LaTeX, CalcError> {
+        // vectors are columns in round brackets, matrices use square ones
+        let (rows, env) = match self {
+            Value::Scalar(v) => return v.to_latex_ext(args),
+            Value::Vector(vs) => (vs.iter().map(std::slice::from_ref).collect(), "pmatrix"),
+            Value::Matrix(rows) => (rows.iter().map(Vec::as_slice).collect::<Vec<_>>(), "bmatrix"),
+        };
+
+        let vals: Vec<&Val> = rows.iter().copied().flatten().collect();
+        // the slope and intercept from linfit each have their own unit
+        let (mut elems, unit_str) = if vals.iter().any(|v| v.unit.desc != vals[0].unit.desc) {
+            let elems = vals
+                .iter()
+                .map(|v| Ok(v.to_latex_ext(args)?.to_string().trim().to_string()))
+                .collect::<Result<Vec<_>, CalcError>>()?;
+            (elems, String::new())
+        } else {
+            format_shared_unit(&vals, args)?
+        };
+        let elems = rows
+            .iter()
+            .map(|r| elems.drain(..r.len()).collect::<Vec<_>>().join(" & "))
+            .collect::<Vec<_>>();
+
+        let out = format!(
+            "\\begin{{{}}} {} \\end{{{}}}",
+            env,
+            elems.join(" \\\\ "),
+            env
+        );
+        Ok(LaTeX::Math(if unit_str.is_empty() {
+            out
+        } else {
+            format!("{} \\ {}", out, unit_str)
+        }))
+    }
+}
+
+// The unit values which are all shown together are written in, picked to suit the
+// largest of them. Returns what to divide base numbers by, and the LaTeX of the unit.
+pub fn shared_unit(vals: &[&Val], args: &FormatArgs) -> Result<(f64, String), CalcError> {
+    // NaN and infinite values can't pick a scale, but they still have a unit
+    let largest = vals
+        .iter()
+        .map(|v| v.without_offset())
+        .filter(|v| v.base_num().is_finite())
+        .max_by(|a, b| a.base_num().abs().total_cmp(&b.base_num().abs()))
+        .or_else(|| vals.first().map(|v| v.without_offset()));
+    Ok(match (&args.unit_hint, largest) {
+        (_, None) => (1.0, String::new()),
+        (
+            Some(UnitHint {
+                unit,
+                pretty_string,
+            }),
+            Some(v),
+        ) if unit.desc == v.unit.desc => (unit.scale(), pretty_string.to_latex()?.to_string()),
+        (Some(UnitHint { unit, .. }), Some(v)) => {
+            return Err(CalcError::UnitError(format!(
+                "Unit hint {} does not match value with unit {}",
+                unit, v.unit
+            )))
+        }
+        (None, Some(v)) => {
+            let (display_exp, unit_str) = display_unit(&v)?;
+            (10f64.powi(display_exp as i32), unit_str)
+        }
+    })
+}
+
+// Formats values which are all shown in the same unit, like the elements of a vector
+// or a column of a table. Returns the numbers, and the LaTeX of the unit to write once.
+pub fn format_shared_unit(
+    vals: &[&Val],
+    args: &FormatArgs,
+) -> Result<(Vec<String>, String), CalcError> {
+    let (scale, unit_str) = shared_unit(vals, args)?;
+    // a hint like degC puts the values on its scale
+    let offset = args.unit_hint.as_ref().map_or(0.0, |hint| hint.unit.offset);
+    let elems = vals
+        .iter()
+        .map(|v| {
+            let v = v.without_offset();
+            format_num(
+                (v.base_num() - offset) / scale,
+                v.imag / scale,
+                v.uncertainty / scale,
+                v.sig_figs.filter(|_| args.sig_figs),
+                args,
+            )
+        })
+        .collect();
+
+    Ok((elems, unit_str))
+}
+
+// The power of ten of the prefix a value is shown with when there's no hint,
+// e.g. 0.002 m^2 is shown in mm^2 so its prefix is 10^-3
+pub fn display_prefix(v: &Val) -> i64 {
+    // TODO don't round this
+    let largest_power = v.unit.desc.largest_power().round().to_i64().unwrap();
+
+    let display_exp = (v.unit.exp / largest_power.max(1)).clamp(-3, 3);
+    // a prefix needs a unit in the numerator to go on, 5000 1/s isn't 5 1/ks
+    if display_exp == 1 || display_exp == 2 || largest_power <= 0 {
+        0
+    } else {
+        display_exp
+    }
+}
+
+// The unit a value is shown in without a hint, as the power of ten it's scaled by
+// and its LaTeX. e.g. 1500 m^2 is shown in m^2, and 0.002 m^2 in mm^2 which is 10^-6
+fn display_unit(v: &Val) -> Result<(i64, String), CalcError> {
+    let largest_power = v.unit.desc.largest_power().round().to_i64().unwrap();
+    let display_exp = display_prefix(v);
+
+    let unit_str = Unit {
+        exp: display_exp,
+        ..v.unit.clone()
+    }
+    .to_latex()?
+    .to_string();
+
+    Ok((display_exp * largest_power, unit_str))
+}
+
+fn format_exact(r: &BigRational) -> String {
+    if r.is_integer() {
+        r.to_string()
+    } else if r.is_negative() {
+        format!("-\\frac{{{}}}{{{}}}", -r.numer(), r.denom())
+    } else {
+        format!("\\frac{{{}}}{{{}}}", r.numer(), r.denom())
+    }
+}
+
+// Prints a rational with a power of ten denominator, like a parsed literal, as a decimal
+fn format_exact_decimal(r: &BigRational) -> String {
+    let ten = BigRational::from_integer(10.into());
+    match (0..64).find(|&k| (r * ten.pow(k)).is_integer()) {
+        Some(k) => {
+            let digits = (r.abs() * ten.pow(k)).to_integer().to_string();
+            let digits = format!("{:0>width$}", digits, width = k as usize + 1);
+            let (whole, fract) = digits.split_at(digits.len() - k as usize);
+            let sign = if r.is_negative() { "-" } else { "" };
+            if fract.is_empty() {
+                format!("{}{}", sign, whole)
+            } else {
+                format!("{}{}.{}", sign, whole, fract)
+            }
+        }
+        None => format_exact(r),
+    }
+}
+
+// Formats a number to max_digits decimal places, or none if it's whole,
+// bracketing it with its uncertainty if it has one. With sig_figs the number
+// is instead rounded to its last significant place, which may be left of the point.
+fn format_num(
+    num: f64,
+    imag: f64,
+    uncertainty: f64,
+    sig_figs: Option<u32>,
+    args: &FormatArgs,
+) -> String {
+    // parts which are only floating point error, like the imaginary part of e^(i pi)
+    let negligible = |a: f64, b: f64| a.abs() < 1e-12 * b.abs();
+    let imag = if negligible(imag, num) { 0.0 } else { imag };
+    if imag != 0.0 || args.polar {
+        let num = if negligible(num, imag) { 0.0 } else { num };
+        let z = format_complex(num, imag, sig_figs, args);
+        return if uncertainty == 0.0 {
+            z
+        } else {
+            // the uncertainty is in the magnitude
+            let digits = if uncertainty.fract() == 0.0 { 0 } else { args.max_digits };
+            format!("({} \\pm {:.*})", z, digits, uncertainty)
+        };
+    }
+
+    let (num, digits) = match sig_figs {
+        Some(n) => {
+            let place = order_of_magnitude(num) - n as i32 + 1;
+            let rounded = (num / 10f64.powi(place)).round() * 10f64.powi(place);
+            (rounded, (-place).max(0) as usize)
+        }
+        None => {
+            let digits = |n: f64| if n.fract() == 0.0 { 0 } else { args.max_digits };
+            let uncertainty_digits = if uncertainty == 0.0 { 0 } else { digits(uncertainty) };
+            (num, digits(num).max(uncertainty_digits))
+        }
+    };
+
+    if uncertainty == 0.0 {
+        format!("{:.*}", digits, num)
+    } else {
+        format!("({:.*} \\pm {:.*})", digits, num, digits, uncertainty)
+    }
+}
+
+// Formats a complex number as a + bi, or as a phasor with `= ? polar`
+fn format_complex(re: f64, im: f64, sig_figs: Option<u32>, args: &FormatArgs) -> String {
+    let part = |n: f64| {
+        format_num(
+            n,
+            0.0,
+            0.0,
+            sig_figs,
+            &FormatArgs {
+                unit_hint: None,
+                polar: false,
+                ..*args
+            },
+        )
+    };
+
+    if args.polar {
+        format!(
+            "({} \\angle {}^{{\\circ}})",
+            part(re.hypot(im)),
+            part(im.atan2(re).to_degrees())
+        )
+    } else if re == 0.0 {
+        format!("{}i", part(im))
+    } else {
+        let sign = if im < 0.0 { "-" } else { "+" };
+        format!("({} {} {}i)", part(re), sign, part(im.abs()))
+    }
+}
+
 impl ToLaTeX for Unit {
     fn to_latex_ext(&self, _: &FormatArgs) -> Result<LaTeX, CalcError> {
         Ok(match self.desc.clone() {
             d @ _ if d.is_empty() => LaTeX::Math("".to_string()),
-            UnitDesc::Base(arr) => {
+            desc => {
+                let powers = desc.powers();
                 let mut numerator = Vec::new();
                 let mut denominator = Vec::new();
-                arr.iter()
+                powers.iter()
                     .rev()
-                    .zip(BASE_UNITS.iter().rev())
-                    .for_each(|(pow, unit)| {
+                    .for_each(|(unit, pow)| {
                         use std::cmp::Ordering::*;
 
                         match pow.cmp(&Ratio::zero()) {
@@ -311,11 +635,11 @@ impl ToLaTeX for Unit {
                         }
                     });
 
-                let latexify_single_unit = |(pow, unit): &(&Ratio<i8>, &BaseUnit)| {
+                let latexify_single_unit = |(pow, unit): &(&Ratio<i8>, &String)| {
                     if pow.abs() == Ratio::one() {
                         unit.to_string()
                     } else {
-                        format!("{}^{{{}}}", unit.to_string(), pow.abs())
+                        format!("{}^{{{}}}", unit, pow.abs())
                     }
                 };
 
@@ -358,15 +682,82 @@ impl ToLaTeX for Unit {
 
                 LaTeX::Math(format!("\\mathrm{{{}}}", unit_str))
             }
-            UnitDesc::Custom(_map) => {
-                todo!()
-            }
         })
     }
 }
 
 impl ToLaTeX for FnCall {
     fn to_latex_ext(&self, args: &FormatArgs) -> Result<LaTeX, CalcError> {
+        let arg_strs = self
+            .args
+            .iter()
+            .map(|a| Ok(a.to_latex_ext(args)?.to_string()))
+            .collect::<Result<Vec<String>, CalcError>>()?;
+
+        // formulas are written with subscripts, and molar mass is M(...)
+        if is_chem_fn(&self.name) {
+            let symbol = match self.name.as_str() {
+                "molar_mass" => "M",
+                _ => "\\text{atoms}",
+            };
+            return Ok(LaTeX::Math(format!("{}({})", symbol, arg_strs.join(", "))));
+        }
+
+        // vector and matrix functions get their usual notation
+        match (self.name.as_str(), arg_strs.as_slice()) {
+            ("dot", [a, b]) => return Ok(LaTeX::Math(format!("{} \\cdot {}", a, b))),
+            ("cross", [a, b]) => return Ok(LaTeX::Math(format!("{} \\times {}", a, b))),
+            ("norm", [a]) => return Ok(LaTeX::Math(format!("\\lVert {} \\rVert", a))),
+            ("det", [a]) => return Ok(LaTeX::Math(format!("\\det({})", a))),
+            ("inv", [a]) => return Ok(LaTeX::Math(format!("{{{}}}^{{-1}}", a))),
+            ("transpose", [a]) => return Ok(LaTeX::Math(format!("{{{}}}^{{T}}", a))),
+            ("solve", [a, b]) => return Ok(LaTeX::Math(format!("{{{}}}^{{-1}} {}", a, b))),
+            ("integrate", [f, x, a, b]) => {
+                return Ok(LaTeX::Math(format!(
+                    "\\int_{{{}}}^{{{}}} {} \\,d{}",
+                    a, b, f, x
+                )))
+            }
+            ("sum" | "prod", [i, start, end, body]) => {
+                let body = match &self.args[3] {
+                    Expr::Cons(Op::Plus | Op::Minus, _) => format!("\\left({}\\right)", body),
+                    _ => body.to_string(),
+                };
+                let symbol = if self.name == "sum" { "\\sum" } else { "\\prod" };
+                return Ok(LaTeX::Math(format!(
+                    "{}_{{{}={}}}^{{{}}} {}",
+                    symbol, i, start, end, body
+                )));
+            }
+            ("gamma", [x]) => return Ok(LaTeX::Math(format!("\\Gamma({})", x))),
+            ("beta", [a, b]) => return Ok(LaTeX::Math(format!("\\mathrm{{B}}({}, {})", a, b))),
+            ("erf", [x]) => return Ok(LaTeX::Math(format!("\\operatorname{{erf}}({})", x))),
+            ("normcdf", [x]) => return Ok(LaTeX::Math(format!("\\Phi({})", x))),
+            ("norminv", [p]) => return Ok(LaTeX::Math(format!("\\Phi^{{-1}}({})", p))),
+            ("normcdf" | "norminv", [x, rest @ ..]) => {
+                let symbol = if self.name == "normcdf" { "\\Phi" } else { "\\Phi^{-1}" };
+                return Ok(LaTeX::Math(format!(
+                    "{}({};\\ {})",
+                    symbol,
+                    x,
+                    rest.join(", ")
+                )));
+            }
+            ("diff", [f, x]) => {
+                return Ok(LaTeX::Math(format!(
+                    "\\frac{{d}}{{d{}}} \\left({}\\right)",
+                    x, f
+                )))
+            }
+            ("derivative", [f, x, at]) => {
+                return Ok(LaTeX::Math(format!(
+                    "\\left. \\frac{{d}}{{d{}}} \\left({}\\right) \\right|_{{{} = {}}}",
+                    x, f, x, at
+                )))
+            }
+            _ => {}
+        }
+
         let mut arg_latex = self
             .args
             .iter()
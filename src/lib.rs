@@ -1,6 +1,14 @@
+mod chem;
+mod constants;
+mod data;
 mod expr;
 mod latex;
+mod ode;
 mod parser;
+mod plot;
+mod solver;
+mod special;
+mod sweep;
 mod ttable;
 
 mod statement;
@@ -11,8 +19,16 @@ use error::CalcError;
 
 pub mod function;
 
+use std::path::Path;
+
 pub fn generate_latex(input: &str) -> Result<String, CalcError> {
-    let mut state = State::new(input)?;
+    generate_latex_in(input, Path::new("."))
+}
+
+// Like generate_latex, with files the input loads found relative to dir,
+// which is usually the directory the input file is in
+pub fn generate_latex_in(input: &str, dir: &Path) -> Result<String, CalcError> {
+    let mut state = State::new(input, dir)?;
     state.exec()?;
     Ok(state.output)
 }
@@ -28,10 +44,11 @@ pub fn generate_latex(input: &str) -> Result<String, CalcError> {
 #[cfg(not(target_os = "windows"))]
 mod tests {
     use crate::CalcError;
+    use std::path::Path;
 
     fn run_on_file(filename: &str) -> Result<String, CalcError> {
         let contents = std::fs::read_to_string(filename).unwrap();
-        super::generate_latex(&contents)
+        super::generate_latex_in(&contents, Path::new(filename).parent().unwrap())
     }
 
     macro_rules! test_file {
@@ -67,4 +84,29 @@ mod tests {
     test_file!(prefix_op);
     test_file!(ttable);
     test_file!(negative_ten);
+    test_file!(user_function);
+    test_file!(custom_unit);
+    test_file!(us_customary);
+    test_file!(temperature);
+    test_file!(uncertainty);
+    test_file!(sig_figs);
+    test_file!(exact);
+    test_file!(complex);
+    test_file!(vector);
+    test_file!(matrix);
+    test_file!(solve);
+    test_file!(calculus);
+    test_file!(series);
+    test_file!(symbolic);
+    test_file!(simulate);
+    test_file!(table);
+    test_file!(plot);
+    test_file!(load);
+    test_file!(statistics);
+    test_file!(probability);
+    test_file!(constants);
+    test_file!(molar_mass);
+    test_file!(balance);
+    test_file!(unit_functions);
+    test_file!(unit_powers);
 }
@@ -0,0 +1,187 @@
+use crate::expr::unit::Unit;
+use crate::expr::{val::Val, Expr};
+use crate::latex::{FormatArgs, UnitHint};
+use crate::statement::Scope;
+use crate::sweep::{range_steps, zero_start, Steps};
+use crate::ttable::{generate_tabular, unit_column};
+use crate::CalcError;
+
+// More steps than this is almost certainly a typo in the step size
+const MAX_STEPS: usize = 1_000_000;
+// roughly how many rows the table of results has
+const TABLE_ROWS: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OdeMethod {
+    Euler,
+    RK4,
+}
+
+// dstate/dtime = rhs, as a function of the time and state in base units,
+// which are given to rhs in the units they started in. The scope has the
+// time and state in it, which are replaced at each step.
+struct Ode<'a> {
+    state: &'a str,
+    time: &'a str,
+    rhs: &'a Expr,
+    state_unit: Unit,
+    time_unit: Unit,
+    scope: Scope<'a>,
+}
+
+impl Ode<'_> {
+    fn derivative(&mut self, t: f64, v: f64) -> Result<f64, CalcError> {
+        self.scope
+            .variables
+            .insert(self.time.to_string(), bind(t, &self.time_unit)?.into());
+        self.scope
+            .variables
+            .insert(self.state.to_string(), bind(v, &self.state_unit)?.into());
+
+        let res = self.rhs.eval(&self.scope)?.scalar()?.without_offset();
+        let expected = (base_unit(&self.state_unit) / base_unit(&self.time_unit))?;
+        if res.unit.desc != expected.desc {
+            return Err(CalcError::UnitError(format!(
+                "d{}/d{} has units {} but should have units {}",
+                self.state, self.time, res.unit, expected
+            )));
+        }
+        if res.imag != 0.0 {
+            return Err(CalcError::Other(format!(
+                "d{}/d{} is complex at {} = {}",
+                self.state, self.time, self.time, t
+            )));
+        }
+        Ok(res.base_num())
+    }
+
+    fn step(&mut self, method: OdeMethod, t: f64, v: f64, h: f64) -> Result<f64, CalcError> {
+        let mut f = |t: f64, v: f64| self.derivative(t, v);
+        Ok(match method {
+            OdeMethod::Euler => v + h * f(t, v)?,
+            OdeMethod::RK4 => {
+                let k1 = f(t, v)?;
+                let k2 = f(t + h / 2.0, v + h / 2.0 * k1)?;
+                let k3 = f(t + h / 2.0, v + h / 2.0 * k2)?;
+                let k4 = f(t + h, v + h * k3)?;
+                v + h / 6.0 * (k1 + 2.0 * k2 + 2.0 * k3 + k4)
+            }
+        })
+    }
+}
+
+fn base_unit(unit: &Unit) -> Unit {
+    Unit {
+        desc: unit.desc.clone(),
+        ..Unit::empty()
+    }
+}
+
+// A number in base units as a value in `unit`, which may be a temperature scale.
+// It isn't clamped so that whole numbers of the unit stay whole.
+pub fn bind(x: f64, unit: &Unit) -> Result<Val, CalcError> {
+    Val::empty((x - unit.offset) / unit.scale()).with_unit(unit)
+}
+
+// The results of a simulation, the table of samples and the final time and state
+pub struct Simulation {
+    pub table: String,
+    pub time: Val,
+    pub state: Val,
+}
+
+// `dstate/dtime = rhs` with `state = initial` at the start of the range.
+// The results are shown in the units of the hints when there are any.
+pub struct OdeProblem<'a> {
+    pub state: &'a str,
+    pub time: &'a str,
+    pub rhs: &'a Expr,
+    pub initial: Val,
+    pub range: (Val, Val),
+    pub step: Val,
+    pub method: OdeMethod,
+    pub state_hint: Option<UnitHint>,
+    pub time_hint: Option<UnitHint>,
+}
+
+// Steps the problem over its time range, printing about
+// TABLE_ROWS evenly spaced samples along the way
+pub fn simulate(
+    problem: OdeProblem,
+    scope: &Scope,
+    args: &FormatArgs,
+) -> Result<Simulation, CalcError> {
+    let OdeProblem {
+        state,
+        time,
+        rhs,
+        initial,
+        range: (start, end),
+        step,
+        method,
+        state_hint,
+        time_hint,
+    } = problem;
+    let start = zero_start(start, &end)?;
+    let Steps {
+        start: t0,
+        end: t1,
+        step: h,
+        whole,
+        partial,
+    } = range_steps(time, (&start, &end), &step)?;
+    let steps = whole + partial as usize;
+    if steps > MAX_STEPS {
+        return Err(CalcError::Other(format!(
+            "Simulating would take {} steps, try a larger step",
+            steps
+        )));
+    }
+
+    let mut ode = Ode {
+        state,
+        time,
+        rhs,
+        state_unit: initial.unit.clone(),
+        time_unit: start.unit.clone(),
+        scope: scope.child(),
+    };
+
+    let sample_every = steps.div_ceil(TABLE_ROWS).max(1);
+    let (mut t, mut v) = (t0, initial.without_offset().base_num());
+    let mut samples = vec![(t, v)];
+    for i in 1..=steps {
+        // the last step is shortened to finish exactly at the end of the range
+        let dt = h.min(t1 - t);
+        v = ode.step(method, t, v, dt)?;
+        t = (t0 + i as f64 * h).min(t1);
+        if i % sample_every == 0 || i == steps {
+            samples.push((t, v));
+        }
+    }
+
+    // the results are kept in the units the range and initial value were given in
+    let times: Vec<Val> = samples
+        .iter()
+        .map(|&(t, _)| bind(t, &ode.time_unit))
+        .collect::<Result<_, _>>()?;
+    let states: Vec<Val> = samples
+        .iter()
+        .map(|&(_, v)| bind(v, &ode.state_unit))
+        .collect::<Result<_, _>>()?;
+
+    let (time_header, time_cells) = unit_column(time, &times, time_hint, args)?;
+    let (state_header, state_cells) = unit_column(state, &states, state_hint, args)?;
+
+    let rows: Vec<Vec<String>> = time_cells
+        .into_iter()
+        .zip(state_cells)
+        .map(|(t, v)| vec![t, v])
+        .collect();
+
+    Ok(Simulation {
+        table: generate_tabular(&[time_header, state_header], &rows),
+        time: times.last().unwrap().clamp_num(),
+        state: states.last().unwrap().clamp_num(),
+    })
+}
@@ -1,11 +1,17 @@
 use crate::expr::bool_expr::BoolExpr;
 use crate::parser::naive_string::parse_naive_string;
 use crate::{error::CalcError, latex::UnitHint};
-use pest::iterators::Pair;
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
 use pest_derive::*;
 
-use crate::statement::Statement;
+use crate::data::ColumnSpec;
+use crate::expr::unit::{define_custom_unit, CustomUnits, Unit};
+use crate::expr::{val::Val, Expr, Op};
+use crate::latex::ToLaTeX;
+use crate::ode::OdeMethod;
+use crate::plot::PlotScale;
+use crate::statement::{Scope, Statement};
 
 pub mod unit;
 use unit::parse_unit_expr;
@@ -24,57 +30,85 @@ pub mod naive_string;
 #[grammar = "parser/grammar.pest"]
 pub struct MathParser;
 
-fn parse_var_dec(r: Pair<Rule>) -> Result<Statement, CalcError> {
+fn parse_var_dec(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
     assert_eq!(r.as_rule(), Rule::var_dec);
     let mut inner = r.into_inner();
     let lhs = inner.next().unwrap();
     let rhs = inner.next().unwrap();
     Ok(Statement::VarDec {
         lhs: lhs.as_str().to_string(),
-        rhs: parse_expr(rhs)?,
+        rhs: parse_expr(rhs, units)?,
     })
 }
 
-fn parse_print_stmt(r: Pair<Rule>) -> Result<Statement, CalcError> {
+fn parse_fn_dec(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::fn_dec);
+    let mut inner = r.into_inner().peekable();
+    let name = inner.next().unwrap().as_str().to_string();
+
+    let mut params = Vec::new();
+    while let Some(r) = inner.next_if(|r| r.as_rule() == Rule::ident) {
+        params.push(r.as_str().to_string());
+    }
+
+    let body = inner.next().unwrap();
+    Ok(Statement::FnDec {
+        name,
+        params,
+        body: parse_expr(body, units)?,
+    })
+}
+
+fn parse_print_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
     assert_eq!(r.as_rule(), Rule::print_expr);
     let mut inner = r.into_inner();
     let lhs = inner.next().unwrap();
-
-    let unit_hint = match inner.next() {
-        Some(r) => Some(UnitHint {
-            unit: parse_unit_expr(r.clone())?.eval(),
-            pretty_string: parse_naive_string(r)?,
-        }),
-        None => None,
-    };
+    let (unit_hint, polar) = parse_output_format(inner, units)?;
 
     Ok(Statement::PrintExpr {
-        expr: parse_expr(lhs)?,
+        expr: parse_expr(lhs, units)?,
         unit_hint,
+        polar,
     })
 }
 
-fn parse_dec_print_stmt(r: Pair<Rule>) -> Result<Statement, CalcError> {
+fn parse_dec_print_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
     assert_eq!(r.as_rule(), Rule::dec_print_expr);
     let mut inner = r.into_inner();
     let lhs = inner.next().unwrap();
     let rhs = inner.next().unwrap();
-
-    let unit_hint = match inner.next() {
-        Some(r) => Some(UnitHint {
-            unit: parse_unit_expr(r.clone())?.eval(),
-            pretty_string: parse_naive_string(r)?,
-        }),
-        None => None,
-    };
+    let (unit_hint, polar) = parse_output_format(inner, units)?;
 
     Ok(Statement::DecPrintExpr {
         lhs: lhs.as_str().to_string(),
-        rhs: parse_expr(rhs)?,
+        rhs: parse_expr(rhs, units)?,
         unit_hint,
+        polar,
     })
 }
 
+// The part after `= ?`, an optional unit hint and `polar`
+fn parse_output_format(
+    inner: Pairs<Rule>,
+    units: &CustomUnits,
+) -> Result<(Option<UnitHint>, bool), CalcError> {
+    let mut unit_hint = None;
+    let mut polar = false;
+    for r in inner {
+        match r.as_rule() {
+            Rule::unit_expr => {
+                unit_hint = Some(UnitHint {
+                    unit: parse_unit_expr(r.clone(), units)?.eval()?,
+                    pretty_string: parse_naive_string(r)?,
+                })
+            }
+            Rule::polar => polar = true,
+            _ => unreachable!(),
+        }
+    }
+    Ok((unit_hint, polar))
+}
+
 fn parse_digit_set(r: Pair<Rule>) -> Result<Statement, CalcError> {
     assert_eq!(r.as_rule(), Rule::digit_set);
     let mut inner = r.into_inner();
@@ -90,6 +124,48 @@ fn parse_alias_stmt(r: Pair<Rule>) -> Result<Statement, CalcError> {
     Ok(Statement::Alias { lhs, rhs })
 }
 
+fn parse_unit_dec(r: Pair<Rule>, units: &mut CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::unit_dec);
+    let mut inner = r.into_inner().skip(1);
+    let name = inner.next().unwrap().as_str().to_string();
+
+    // `unit furlong = 201.168 m` is stored as the expression `201.168 m`,
+    // while `unit widget` creates a new base dimension
+    let rhs = match (inner.next(), inner.next()) {
+        (Some(n), Some(u)) => Some((n.as_str().parse::<f64>().unwrap(), u)),
+        (Some(u), None) => Some((1.0, u)),
+        _ => None,
+    };
+
+    let rhs = match rhs {
+        Some((n, u)) => {
+            let naive_expr = parse_naive_string(u.clone())?.to_latex()?;
+            let unit_expr = parse_unit_expr(u, units)?;
+            Some(Expr::Cons(
+                Op::AddUnit(unit_expr.eval()?, naive_expr.to_string()),
+                vec![Expr::Atom(Val::empty(n))],
+            ))
+        }
+        None => None,
+    };
+
+    // Units are resolved while parsing, so the unit has to be
+    // defined here rather than when the statement is executed.
+    let unit = match &rhs {
+        Some(expr) => {
+            let val = expr.eval(&Scope::default())?.scalar()?;
+            Some(Unit {
+                mult: val.num * val.unit.mult,
+                ..val.unit
+            })
+        }
+        None => None,
+    };
+    define_custom_unit(&name, unit, units)?;
+
+    Ok(Statement::UnitDec { name, rhs })
+}
+
 fn parse_ident_list(r: Pair<Rule>) -> Result<Vec<String>, CalcError> {
     assert_eq!(r.as_rule(), Rule::ident_list);
     let inner = r.into_inner();
@@ -122,7 +198,167 @@ fn parse_ttable_stmt(r: Pair<Rule>) -> Result<Statement, CalcError> {
     Ok(Statement::TTable { args, exprs })
 }
 
-pub fn parse_block(s: &str) -> Result<Vec<(usize, Statement)>, CalcError> {
+fn parse_range(r: Pair<Rule>, units: &CustomUnits) -> Result<(Expr, Expr), CalcError> {
+    assert_eq!(r.as_rule(), Rule::range);
+    let mut inner = r.into_inner();
+    let start = parse_expr(inner.next().unwrap(), units)?;
+    let end = parse_expr(inner.next().unwrap(), units)?;
+    Ok((start, end))
+}
+
+fn parse_solve_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::solve_stmt);
+    let mut inner = r.into_inner().peekable();
+    let var = inner.next().unwrap().as_str().to_string();
+    let range = match inner.next_if(|r| r.as_rule() == Rule::range) {
+        Some(r) => Some(Box::new(parse_range(r, units)?)),
+        None => None,
+    };
+
+    let lhs = parse_expr(inner.next().unwrap(), units)?;
+    let rhs = parse_expr(inner.next().unwrap(), units)?;
+    Ok(Statement::Solve {
+        var,
+        lhs,
+        rhs,
+        range,
+    })
+}
+
+// simulate dv/dt = <expr>, v = <initial>, t in <start>..<end>, step <step>, rk4
+fn parse_simulate_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::simulate_stmt);
+    let mut inner = r.into_inner();
+    let state = inner.next().unwrap().as_str().to_string();
+    let time = inner.next().unwrap().as_str().to_string();
+    let rhs = parse_expr(inner.next().unwrap(), units)?;
+
+    let initial_name = inner.next().unwrap().as_str();
+    let initial = parse_expr(inner.next().unwrap(), units)?;
+    let range_name = inner.next().unwrap().as_str();
+    if initial_name != state || range_name != time {
+        return Err(CalcError::Other(format!(
+            "Expected an initial value for {} and a range for {}",
+            state, time
+        )));
+    }
+
+    let range = Box::new(parse_range(inner.next().unwrap(), units)?);
+    let step = parse_expr(inner.next().unwrap(), units)?;
+    let method = match inner.next().map(|r| r.as_str()) {
+        Some("euler") => OdeMethod::Euler,
+        _ => OdeMethod::RK4,
+    };
+
+    Ok(Statement::Simulate {
+        state,
+        time,
+        rhs,
+        initial,
+        range,
+        step,
+        method,
+    })
+}
+
+// table t in <start>..<end>, step <step>: <expr>, <expr>, ...
+fn parse_table_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::table_stmt);
+    let mut inner = r.into_inner();
+    let var = inner.next().unwrap().as_str().to_string();
+    let range = Box::new(parse_range(inner.next().unwrap(), units)?);
+    let step = parse_expr(inner.next().unwrap(), units)?;
+    let exprs = inner
+        .map(|r| parse_expr(r, units))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Statement::Table {
+        var,
+        range,
+        step,
+        exprs,
+    })
+}
+
+// plot <expr>, <expr>, ... for t in <start>..<end>, logy
+fn parse_plot_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::plot_stmt);
+    let mut exprs = Vec::new();
+    let mut var = String::new();
+    let mut range = None;
+    let mut scale = PlotScale::default();
+    for r in r.into_inner() {
+        match r.as_rule() {
+            Rule::expression => exprs.push(parse_expr(r, units)?),
+            Rule::ident => var = r.as_str().to_string(),
+            Rule::range => range = Some(Box::new(parse_range(r, units)?)),
+            Rule::plot_scale => match r.as_str() {
+                "logx" => scale.log_x = true,
+                "logy" => scale.log_y = true,
+                _ => {
+                    scale.log_x = true;
+                    scale.log_y = true;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(Statement::Plot {
+        exprs,
+        var,
+        range: range.unwrap(),
+        scale,
+    })
+}
+
+// load data.csv as d with [t: s, x: m]
+fn parse_load_stmt(r: Pair<Rule>, units: &CustomUnits) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::load_stmt);
+    let mut inner = r.into_inner();
+    let path = inner.next().unwrap().as_str().trim_matches('"').to_string();
+    let name = inner.next().unwrap().as_str().to_string();
+
+    let columns = inner
+        .map(|c| {
+            let mut inner = c.into_inner();
+            let name = inner.next().unwrap().as_str().to_string();
+            let unit = match inner.next() {
+                Some(u) => Some(parse_unit_expr(u, units)?.eval()?),
+                None => None,
+            };
+            Ok(ColumnSpec { name, unit })
+        })
+        .collect::<Result<Vec<_>, CalcError>>()?;
+
+    Ok(Statement::Load {
+        path,
+        name,
+        columns,
+    })
+}
+
+// balance Fe + O2 -> Fe2O3
+fn parse_balance_stmt(r: Pair<Rule>) -> Result<Statement, CalcError> {
+    assert_eq!(r.as_rule(), Rule::balance_stmt);
+    let mut inner = r.into_inner();
+    let mut species = || {
+        inner
+            .next()
+            .unwrap()
+            .into_inner()
+            .map(|f| f.as_str().to_string())
+            .collect()
+    };
+    Ok(Statement::Balance {
+        reactants: species(),
+        products: species(),
+    })
+}
+
+// Parses a document, defining the units it declares in `units` as it goes,
+// since units are resolved while parsing
+pub fn parse_block(s: &str, units: &mut CustomUnits) -> Result<Vec<(usize, Statement)>, CalcError> {
     let inp = MathParser::parse(Rule::program, s)?;
     inp.map(|s| {
         let stmt = s.into_inner().next().unwrap();
@@ -133,10 +369,15 @@ pub fn parse_block(s: &str) -> Result<Vec<(usize, Statement)>, CalcError> {
             match stmt.as_rule() {
                 Rule::digit_set => parse_digit_set(stmt).map_err(add_line)?,
                 Rule::set_scientific => Statement::SetScientific,
-                Rule::var_dec => parse_var_dec(stmt).map_err(add_line)?,
-                Rule::print_expr => parse_print_stmt(stmt).map_err(add_line)?,
-                Rule::dec_print_expr => parse_dec_print_stmt(stmt).map_err(add_line)?,
+                Rule::set_sig_figs => Statement::SetSigFigs,
+                Rule::set_exact => Statement::SetExact,
+                Rule::import_constants => Statement::ImportConstants,
+                Rule::var_dec => parse_var_dec(stmt, units).map_err(add_line)?,
+                Rule::fn_dec => parse_fn_dec(stmt, units).map_err(add_line)?,
+                Rule::print_expr => parse_print_stmt(stmt, units).map_err(add_line)?,
+                Rule::dec_print_expr => parse_dec_print_stmt(stmt, units).map_err(add_line)?,
                 Rule::alias_stmt => parse_alias_stmt(stmt).map_err(add_line)?,
+                Rule::unit_dec => parse_unit_dec(stmt, units).map_err(add_line)?,
                 Rule::line_gap_stmt => Statement::LineGap,
                 Rule::latex_block => Statement::RawLaTeX(
                     stmt.as_str()
@@ -145,6 +386,12 @@ pub fn parse_block(s: &str) -> Result<Vec<(usize, Statement)>, CalcError> {
                         .to_owned(),
                 ),
                 Rule::truth_table_stmt => parse_ttable_stmt(stmt).map_err(add_line)?,
+                Rule::solve_stmt => parse_solve_stmt(stmt, units).map_err(add_line)?,
+                Rule::simulate_stmt => parse_simulate_stmt(stmt, units).map_err(add_line)?,
+                Rule::table_stmt => parse_table_stmt(stmt, units).map_err(add_line)?,
+                Rule::plot_stmt => parse_plot_stmt(stmt, units).map_err(add_line)?,
+                Rule::load_stmt => parse_load_stmt(stmt, units).map_err(add_line)?,
+                Rule::balance_stmt => parse_balance_stmt(stmt).map_err(add_line)?,
                 Rule::error => {
                     return Err(CalcError::Other(format!(
                         "Invalid statement {}",
@@ -171,16 +418,220 @@ mod tests {
                 x = 5
                 5 + 10 = ?
             ",
+            &mut CustomUnits::new(),
         )
         .unwrap();
     }
 
+    #[test]
+    fn test_fn_dec_parse() {
+        let stmts = parse_block(
+            "
+                KE(m, v) = 1/2 * m * v^2
+                KE(2 kg, 3 m/s) = ?
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(matches!(&stmts[0].1, Statement::FnDec { name, params, .. }
+            if name == "KE" && params == &["m", "v"]));
+        assert!(matches!(&stmts[1].1, Statement::PrintExpr { .. }));
+    }
+
+    #[test]
+    fn test_unit_dec_parse() {
+        let stmts = parse_block(
+            "
+                unit apple
+                unitcost = 5 kg
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(matches!(&stmts[0].1, Statement::UnitDec { .. }));
+        assert!(matches!(&stmts[1].1, Statement::VarDec { .. }));
+
+        // a unit only exists in the document that defines it
+        let mut units = CustomUnits::new();
+        assert!(parse_block("unit apple\nx = 3 apple", &mut units).is_ok());
+        assert!(units.contains_key("apple"));
+        assert!(parse_block("x = 3 apple", &mut CustomUnits::new()).is_err());
+    }
+
+    #[test]
+    fn test_solve_parse() {
+        let stmts = parse_block(
+            "
+                solve x: x^2 = 2
+                solve v in 0 m/s..100 m/s: v^2 = 20 m^2/s^2
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(matches!(&stmts[0].1, Statement::Solve { var, range: None, .. } if var == "x"));
+        assert!(matches!(&stmts[1].1, Statement::Solve { var, range: Some(_), .. } if var == "v"));
+    }
+
+    #[test]
+    fn test_simulate_parse() {
+        let stmts = parse_block(
+            "
+                simulate dv/dt = 9.81 m/s^2, v = 0 m/s, t in 0 s..2 s, step 0.1 s, euler
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(
+            matches!(&stmts[0].1, Statement::Simulate { state, time, method: OdeMethod::Euler, .. }
+            if state == "v" && time == "t")
+        );
+        assert!(parse_block(
+            "simulate dv/dt = 1, x = 0, t in 0..1, step 0.1
+",
+            &mut CustomUnits::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_table_parse() {
+        let stmts = parse_block(
+            "
+                table t in 0 s..5 s, step 0.5 s: v * t, t^2
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(matches!(&stmts[0].1, Statement::Table { var, exprs, .. }
+            if var == "t" && exprs.len() == 2));
+    }
+
+    #[test]
+    fn test_plot_parse() {
+        let stmts = parse_block(
+            "
+                plot y(t), v * t for t in 1 s..5 s, logy
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(
+            matches!(&stmts[0].1, Statement::Plot { var, exprs, scale, .. }
+            if var == "t" && exprs.len() == 2 && scale.log_y && !scale.log_x)
+        );
+
+        // `for` only ends the expressions of a plot, elsewhere it can be a unit
+        let stmts = parse_block(
+            "
+                plot t * 2 m for t in 0 s..1 s
+                unit for
+                x = 2 for
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+        assert!(matches!(&stmts[0].1, Statement::Plot { exprs, .. } if exprs.len() == 1));
+        assert!(matches!(&stmts[2].1, Statement::VarDec { .. }));
+    }
+
+    #[test]
+    fn test_load_parse() {
+        let stmts = parse_block(
+            "
+                load \"lab data.csv\" as d with [t: s, x: m, n]
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(
+            matches!(&stmts[0].1, Statement::Load { path, name, columns }
+            if path == "lab data.csv" && name == "d" && columns.len() == 3 && columns[2].unit.is_none())
+        );
+    }
+
+    #[test]
+    fn test_chem_fn_parse() {
+        let stmts = parse_block(
+            "
+                molar_mass(Ca(OH)2) + atoms(CuSO4*5H2O, O) = ?
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        let fn_calls = match &stmts[0].1 {
+            Statement::PrintExpr {
+                expr: Expr::Cons(Op::Plus, args),
+                ..
+            } => args,
+            other => panic!("Expected a sum, got {:?}", other),
+        };
+        assert!(matches!(&fn_calls[0], Expr::FnCall(f)
+            if f.name == "molar_mass" && matches!(&f.args[..], [Expr::Formula(formula)] if formula == "Ca(OH)2")));
+        assert!(matches!(&fn_calls[1], Expr::FnCall(f) if f.args.len() == 2));
+    }
+
+    #[test]
+    fn test_balance_parse() {
+        let stmts = parse_block(
+            "
+                balance MnO4- + Fe^2+ + H+ -> Mn^2+ + Fe^3+ + H2O
+            ",
+            &mut CustomUnits::new(),
+        )
+        .unwrap();
+
+        assert!(
+            matches!(&stmts[0].1, Statement::Balance { reactants, products }
+            if reactants == &["MnO4-", "Fe^2+", "H+"] && products.len() == 3)
+        );
+    }
+
+    #[test]
+    fn test_unit_exponent_parse() {
+        use crate::parser::unit::parse_unit_exponent;
+        use num::rational::Ratio;
+
+        assert_eq!(parse_unit_exponent("^-2").unwrap(), Ratio::from(-2));
+        assert_eq!(parse_unit_exponent("^0.5").unwrap(), Ratio::new(1, 2));
+        assert_eq!(
+            parse_unit_exponent("^( -1 / 2 )").unwrap(),
+            Ratio::new(-1, 2)
+        );
+        assert!(parse_unit_exponent("^(1/0)").is_err());
+        assert!(parse_unit_exponent("^0.123").is_err());
+
+        let unit = MathParser::parse(Rule::unit_expr, "V/Hz^(1/2)")
+            .unwrap()
+            .next()
+            .unwrap();
+        let unit = parse_unit_expr(unit, &CustomUnits::new())
+            .unwrap()
+            .eval()
+            .unwrap();
+        let seconds = unit
+            .desc
+            .powers()
+            .into_iter()
+            .find(|(u, _)| u == "s")
+            .unwrap();
+        assert_eq!(seconds.1, Ratio::new(-5, 2));
+    }
+
     #[test]
     fn test_ttable_parse() {
         parse_block(
             "
                 ttable [p, q] [p, q, p and q]
             ",
+            &mut CustomUnits::new(),
         )
         .unwrap();
     }
@@ -1,9 +1,13 @@
 use crate::parser::fn_call::parse_fn_call;
 use crate::parser::naive_string::parse_naive_string;
 use crate::CalcError;
+use num::rational::BigRational;
+use num::traits::Pow;
+use num::BigInt;
 use pest::iterators::{Pair, Pairs};
 
 use crate::{
+    expr::unit::CustomUnits,
     expr::val::Val,
     expr::{Expr, Op},
     parser::{parse_unit_expr, Rule},
@@ -11,16 +15,25 @@ use crate::{
 
 use crate::latex::ToLaTeX;
 
-pub fn parse_expr(r: Pair<Rule>) -> Result<Expr, CalcError> {
+pub fn parse_expr(r: Pair<Rule>, units: &CustomUnits) -> Result<Expr, CalcError> {
     assert_eq!(r.as_rule(), Rule::expression);
 
-    fn expr_bp(inp: &mut Pairs<Rule>, bp: u8) -> Result<Expr, CalcError> {
+    fn expr_bp(inp: &mut Pairs<Rule>, bp: u8, units: &CustomUnits) -> Result<Expr, CalcError> {
         if let Some(nx) = inp.next() {
             let mut lhs = match nx.as_rule() {
-                Rule::number => Expr::Atom(Val::empty(nx.as_str().trim().parse::<f64>().unwrap())),
+                Rule::number => Expr::Atom(parse_number(nx.as_str().trim())),
                 Rule::ident => Expr::Ident(nx.as_str().trim().to_string()),
-                Rule::fn_call => Expr::FnCall(parse_fn_call(nx)?),
-                Rule::expression => Expr::ParenExpr(Box::new(parse_expr(nx)?)),
+                Rule::fn_call | Rule::chem_fn_call => Expr::FnCall(parse_fn_call(nx, units)?),
+                Rule::expression => Expr::ParenExpr(Box::new(parse_expr(nx, units)?)),
+                Rule::norm_expr => Expr::Cons(
+                    Op::Norm,
+                    vec![parse_expr(nx.into_inner().next().unwrap(), units)?],
+                ),
+                Rule::vector => Expr::Vector(
+                    nx.into_inner()
+                        .map(|r| parse_expr(r, units))
+                        .collect::<Result<_, _>>()?,
+                ),
                 Rule::operation => {
                     let op = match nx.as_str().trim() {
                         "-" => Op::Minus,
@@ -28,7 +41,7 @@ pub fn parse_expr(r: Pair<Rule>) -> Result<Expr, CalcError> {
                     };
 
                     if let Some(((), r_bp)) = prefix_binding_power(&op) {
-                        let rhs = expr_bp(inp, r_bp)?;
+                        let rhs = expr_bp(inp, r_bp, units)?;
                         Expr::Cons(op, vec![rhs])
                     } else {
                         return Err(CalcError::Other("Invalid prefix operation".to_string()));
@@ -48,12 +61,15 @@ pub fn parse_expr(r: Pair<Rule>) -> Result<Expr, CalcError> {
                         "*" => Op::Mul,
                         "/" => Op::Div,
                         "^" => Op::Exp,
+                        "±" | "\\pm" => Op::PlusMinus,
+                        "\\cdot" => Op::Dot,
+                        "\\times" => Op::Cross,
                         _ => panic!("Bad operator {}", nx.as_str().trim()),
                     },
                     Rule::unit_expr => {
                         let naive_expr = parse_naive_string(nx.clone())?.to_latex()?;
-                        let unit_expr = parse_unit_expr(nx)?;
-                        Op::AddUnit(unit_expr.eval(), naive_expr.to_string())
+                        let unit_expr = parse_unit_expr(nx, units)?;
+                        Op::AddUnit(unit_expr.eval()?, naive_expr.to_string())
                     }
                     _ => todo!(),
                 };
@@ -75,8 +91,19 @@ pub fn parse_expr(r: Pair<Rule>) -> Result<Expr, CalcError> {
                 }
                 inp.next();
 
-                let rhs = expr_bp(inp, r_bp)?;
-                lhs = Expr::Cons(op, vec![lhs, rhs]);
+                let rhs = expr_bp(inp, r_bp, units)?;
+                let plus_minus = matches!(op, Op::PlusMinus);
+                lhs = match rhs {
+                    // a unit after a bare number applies to both halves, so
+                    // 10.2 \pm 0.1 kg is (10.2 \pm 0.1) kg
+                    Expr::Cons(unit @ Op::AddUnit(..), mut args)
+                        if plus_minus && is_literal(&lhs) =>
+                    {
+                        let value = Expr::Cons(op, vec![lhs, args.remove(0)]);
+                        Expr::Cons(unit, vec![value])
+                    }
+                    rhs => Expr::Cons(op, vec![lhs, rhs]),
+                };
             }
 
             Ok(lhs)
@@ -85,19 +112,65 @@ pub fn parse_expr(r: Pair<Rule>) -> Result<Expr, CalcError> {
         }
     }
 
-    expr_bp(&mut r.into_inner(), 0)
+    expr_bp(&mut r.into_inner(), 0, units)
+}
+
+// A decimal literal, remembering its significant figures and exact value
+pub fn parse_number(s: &str) -> Val {
+    Val {
+        sig_figs: Some(count_sig_figs(s)),
+        exact: Some(parse_exact(s)),
+        ..Val::empty(s.parse::<f64>().unwrap())
+    }
+}
+
+// Leading zeros are never significant, trailing zeros
+// only count when there's a decimal point
+fn count_sig_figs(s: &str) -> u32 {
+    // a zero has no significant digits, so count the places it's written to
+    // instead, which puts the last one in the same column as it would for 1.00
+    if s.chars().all(|c| c == '0' || c == '.') {
+        let decimals = s.split_once('.').map_or(0, |(_, fract)| fract.len());
+        return decimals as u32 + 1;
+    }
+
+    let digits: String = s.chars().filter(|c| c.is_ascii_digit()).collect();
+    let digits = digits.trim_start_matches('0');
+    let digits = if s.contains('.') {
+        digits
+    } else {
+        digits.trim_end_matches('0')
+    };
+    (digits.len() as u32).max(1)
+}
+
+// A decimal literal like 12.50 as the fraction 1250/100
+fn parse_exact(s: &str) -> BigRational {
+    let (whole, fract) = s.split_once('.').unwrap_or((s, ""));
+    let numer: BigInt = format!("{}{}", whole, fract).parse().unwrap();
+    let denom = BigInt::from(10).pow(fract.len() as u32);
+    BigRational::new(numer, denom)
+}
+
+// A number without a unit, like the 10.2 or -3 in front of a \pm
+fn is_literal(e: &Expr) -> bool {
+    match e {
+        Expr::Atom(_) => true,
+        Expr::Cons(Op::Minus, args) => matches!(args.as_slice(), [Expr::Atom(_)]),
+        _ => false,
+    }
 }
 
 fn prefix_binding_power(op: &Op) -> Option<((), u8)> {
     Some(match op {
-        Op::Minus => ((), 9),
+        Op::Minus => ((), 11),
         _ => return None,
     })
 }
 
 fn postfix_binding_power(op: &Op) -> Option<(u8, ())> {
     Some(match op {
-        Op::AddUnit(_, _) => (6, ()),
+        Op::AddUnit(_, _) => (8, ()),
         _ => return None,
     })
 }
@@ -105,8 +178,10 @@ fn postfix_binding_power(op: &Op) -> Option<(u8, ())> {
 fn infix_binding_power(op: &Op) -> (u8, u8) {
     match op {
         Op::Plus | Op::Minus => (1, 2),
-        Op::Mul | Op::Div => (3, 4),
-        Op::Exp => (7, 8),
+        // looser than products so 2 * 3 \pm 0.1 is (2 * 3) \pm 0.1
+        Op::PlusMinus => (3, 4),
+        Op::Mul | Op::Div | Op::Dot | Op::Cross => (5, 6),
+        Op::Exp => (9, 10),
         _ => panic!(),
     }
 }
@@ -118,6 +193,7 @@ mod test {
 
     use crate::{
         expr::unit::Unit,
+        expr::value::Value,
         parser::{expr::parse_expr, MathParser, Rule},
         statement::Scope,
     };
@@ -125,15 +201,23 @@ mod test {
     use pest::Parser;
 
     fn full_eval(s: &str) -> Val {
+        full_eval_value(s).scalar().unwrap()
+    }
+
+    fn full_eval_value(s: &str) -> Value {
+        try_eval(s).unwrap()
+    }
+
+    fn try_eval(s: &str) -> Result<Value, CalcError> {
         parse_expr(
             MathParser::parse(Rule::expression, s)
                 .unwrap()
                 .next()
                 .unwrap(),
+            &CustomUnits::new(),
         )
         .unwrap()
         .eval(&Scope::default())
-        .unwrap()
     }
 
     impl PartialEq<&str> for Val {
@@ -161,6 +245,7 @@ mod test {
         assert_eq!(full_eval("4 / 2"), "2");
         assert_eq!(full_eval("9 m / 3 meters"), "3");
         assert_eq!(full_eval("12 grams / 4 g"), "3");
+        assert!(try_eval("1 m^100 / 1 m^-100").is_err());
     }
 
     #[test]
@@ -168,6 +253,7 @@ mod test {
         assert_eq!(full_eval("4 * 2"), "8");
         assert_eq!(full_eval("2 m * 3 meters"), "6 m^2");
         assert_eq!(full_eval("1 grams * 4 g"), "4 g^2");
+        assert!(try_eval("1 m^100 * 1 m^100").is_err());
     }
 
     #[test]
@@ -188,4 +274,94 @@ mod test {
         assert_eq!(full_eval("2 N + -0.5 N"), "1500 m g s^-2");
         assert_eq!(full_eval("2 kN + -1 centinewton"), "1999990 m g s^-2");
     }
+
+    #[test]
+    fn sig_figs() {
+        assert_eq!(full_eval("12.0 * 4.5").sig_figs, Some(2));
+        assert_eq!(full_eval("0.0030 * 2.000").sig_figs, Some(2));
+        assert_eq!(full_eval("1.2 km + 30.55 m").sig_figs, Some(2));
+        assert_eq!(full_eval("1500 * 3.14159").sig_figs, Some(2));
+        assert_eq!(full_eval("0.00 + 1.2").sig_figs, Some(2));
+    }
+
+    #[test]
+    fn exact() {
+        let half = BigRational::new(1.into(), 2.into());
+        assert_eq!(full_eval("1/3 + 1/6").exact, Some(half));
+        assert_eq!(full_eval("0.1 + 0.2").exact, Some(parse_exact("0.3")));
+        assert_eq!(full_eval("sin(0.5)").exact, None);
+    }
+
+    #[test]
+    fn vectors() {
+        assert_eq!(full_eval("norm([3, 4, 0] m)"), "5 m");
+        assert!((full_eval("norm([1 + i, 2])").num - 6f64.sqrt()).abs() < 1e-9);
+        let uncertain = full_eval("norm([3 ± 0.1, 4])");
+        assert!((uncertain.uncertainty - 0.06).abs() < 1e-9);
+        assert_eq!(full_eval("dot([1, 2, 3], [4, 5, 6])"), "32");
+        match full_eval_value("[1, 2] m + [3, 4] m") {
+            Value::Vector(vs) => assert_eq!(vs, vec![full_eval("4 m"), full_eval("6 m")]),
+            v => panic!("expected a vector, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn calculus() {
+        let work = full_eval("integrate(x * 3 N/m, x, 0 m, 2 m)");
+        assert!((work.base_num() - 6000.0).abs() < 1e-6);
+        assert_eq!(work.unit.desc, full_eval("1 J").unit.desc);
+
+        let slope = full_eval("derivative(x^2 * 1 m, x, 3)");
+        assert!((slope.base_num() - 6.0).abs() < 1e-6);
+        assert_eq!(slope.unit.desc, full_eval("1 m").unit.desc);
+
+        // the step shrinks with the scale of x
+        let small = full_eval("derivative(exp(x / (1 nm)), x, 1 nm)");
+        assert!((small.base_num() / 1e9 - std::f64::consts::E).abs() < 1e-6);
+        assert!(try_eval("integrate(1 / sqrt(x), x, 0, 1)").is_err());
+    }
+
+    #[test]
+    fn series() {
+        assert_eq!(full_eval("sum(i, 1, 10, i^2)"), "385");
+        assert_eq!(full_eval("prod(k, 1, 5, k)"), "120");
+        assert_eq!(full_eval("sum(i, 1, 3, i * 2 m)"), "12 m");
+        // i is only the index inside the sum
+        assert_eq!(full_eval("sum(i, 1, 2, i) * i").imag, 3.0);
+        assert!(try_eval("sum(i, 1, 1000000000, i)").is_err());
+    }
+
+    #[test]
+    fn matrices() {
+        assert_eq!(full_eval("det([[1, 2], [3, 4]] m)"), "-2 m^2");
+        match full_eval_value("solve([[2, 1], [1, 3]] ohm, [3, 5] V)") {
+            Value::Vector(vs) => assert_eq!(vs, vec![full_eval("0.8 A"), full_eval("1.4 A")]),
+            v => panic!("expected a vector, got {:?}", v),
+        }
+        assert!(try_eval("inv([[1, 2], [2, 4]])").is_err());
+        assert!(try_eval("det([[0/0, 1], [1, 1]])").is_err());
+        match full_eval_value("[[1, 1], [1, 0]]^30") {
+            Value::Matrix(rows) => assert!((rows[0][1].base_num() - 832040.0).abs() < 1e-6),
+            v => panic!("expected a matrix, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn statistics() {
+        assert!(full_eval("median([0/0, 1, 2])").num.is_nan());
+        match full_eval_value("linfit([0 s, 1 s, 2 s], [1 m, 3 m, 5 m])") {
+            Value::Vector(vs) => assert_eq!(vs, vec![full_eval("2 m/s"), full_eval("1 m")]),
+            v => panic!("expected a vector, got {:?}", v),
+        }
+        let err = try_eval("stddev(1)").unwrap_err().to_string();
+        assert!(err.contains("expected at least 2"), "{}", err);
+    }
+
+    #[test]
+    fn normal_distribution_args() {
+        assert!(try_eval("normcdf(1, 0)").is_err());
+        assert!(try_eval("norminv(0.5, 1 m)").is_err());
+        assert!(try_eval("normcdf(2 m)").is_err());
+        assert_eq!(full_eval("norminv(0.5, 2 m, 1 m)"), "2 m");
+    }
 }
@@ -1,3 +1,4 @@
+use crate::expr::unit::CustomUnits;
 use crate::expr::Expr;
 use crate::parser::parse_expr;
 use crate::CalcError;
@@ -12,8 +13,12 @@ pub struct FnCall {
     pub args: Vec<Expr>,
 }
 
-pub fn parse_fn_call(r: Pair<Rule>) -> Result<FnCall, CalcError> {
-    assert_eq!(r.as_rule(), Rule::fn_call);
+pub fn parse_fn_call(r: Pair<Rule>, units: &CustomUnits) -> Result<FnCall, CalcError> {
+    let is_chem = match r.as_rule() {
+        Rule::fn_call => false,
+        Rule::chem_fn_call => true,
+        _ => panic!("Expected a function call, got {:?}", r.as_rule()),
+    };
     let mut inner = r.into_inner();
 
     let name = inner
@@ -24,7 +29,12 @@ pub fn parse_fn_call(r: Pair<Rule>) -> Result<FnCall, CalcError> {
 
     let mut args: Vec<Expr> = Vec::new();
     while let Some(r) = inner.next() {
-        args.push(parse_expr(r)?);
+        // formulas are kept whole for the chemistry builtins to read
+        if is_chem {
+            args.push(Expr::Formula(r.as_str().to_string()));
+        } else {
+            args.push(parse_expr(r, units)?);
+        }
     }
     Ok(FnCall { name, args })
 }
@@ -1,7 +1,7 @@
 use crate::parser::Rule;
 use crate::{
     error::CalcError,
-    parser::unit::{unit_infix_binding_power, unit_postfix_binding_power},
+    parser::unit::{parse_unit_exponent, unit_infix_binding_power, unit_postfix_binding_power},
 };
 
 use pest::iterators::{Pair, Pairs};
@@ -14,6 +14,19 @@ pub enum StringExpr {
     Cons(UnitOp, Vec<StringExpr>),
 }
 
+// Some units are typed differently than they're usually written
+fn unit_symbol(name: &str) -> String {
+    match name {
+        "degC" | "celsius" => "{}^{\\circ}C",
+        "degF" | "fahrenheit" => "{}^{\\circ}F",
+        "degR" | "rankine" => "{}^{\\circ}R",
+        "deltaC" => "\\Delta{}^{\\circ}C",
+        "deltaF" => "\\Delta{}^{\\circ}F",
+        _ => name,
+    }
+    .to_string()
+}
+
 pub fn parse_naive_string(r: Pair<Rule>) -> Result<StringExpr, CalcError> {
     assert_eq!(r.as_rule(), Rule::unit_expr);
 
@@ -23,7 +36,7 @@ pub fn parse_naive_string(r: Pair<Rule>) -> Result<StringExpr, CalcError> {
                 match nx.as_rule() {
                     Rule::unit => {
                         let rule_str = nx.as_str();
-                        StringExpr::Atom(rule_str.to_string())
+                        StringExpr::Atom(unit_symbol(rule_str.trim()))
                     }
                     Rule::unit_expr => {
                         let s = parse_naive_string(nx)?.to_latex()?.to_string();
@@ -37,10 +50,7 @@ pub fn parse_naive_string(r: Pair<Rule>) -> Result<StringExpr, CalcError> {
                 let op = match nx.as_str().trim() {
                     "*" => UnitOp::Mul,
                     "/" => UnitOp::Div,
-                    s if s.starts_with('^') => {
-                        let n = s.strip_prefix('^').unwrap();
-                        UnitOp::Exp(n.parse().unwrap())
-                    }
+                    s if s.starts_with('^') => UnitOp::Exp(parse_unit_exponent(s)?),
                     _ => {
                         dbg!(nx);
                         panic!();
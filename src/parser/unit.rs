@@ -1,28 +1,24 @@
 use crate::CalcError;
-use std::convert::TryInto;
+use num::rational::Ratio;
 
 use pest::iterators::{Pair, Pairs};
 
 use crate::{
-    expr::unit::Unit,
+    expr::unit::{find_unit, power_ratio, CustomUnits},
     expr::unit_expr::{UnitExpr, UnitOp},
     parser::Rule,
 };
 
-pub fn parse_unit_expr(r: Pair<Rule>) -> Result<UnitExpr, CalcError> {
+pub fn parse_unit_expr(r: Pair<Rule>, units: &CustomUnits) -> Result<UnitExpr, CalcError> {
     assert_eq!(r.as_rule(), Rule::unit_expr);
 
-    fn expr_bp(inp: &mut Pairs<Rule>, bp: u8) -> Result<UnitExpr, CalcError> {
+    fn expr_bp(inp: &mut Pairs<Rule>, bp: u8, units: &CustomUnits) -> Result<UnitExpr, CalcError> {
         if let Some(nx) = inp.next() {
             let mut lhs = {
                 match nx.as_rule() {
-                    Rule::unit => {
-                        let rule_str = nx.as_str();
-                        let unit: Unit = rule_str.try_into()?;
-                        UnitExpr::Atom(unit)
-                    }
+                    Rule::unit => UnitExpr::Atom(find_unit(nx.as_str(), units)?),
                     Rule::unit_expr => {
-                        let unit = parse_unit_expr(nx)?.eval();
+                        let unit = parse_unit_expr(nx, units)?.eval()?;
                         UnitExpr::Atom(unit)
                     }
                     _ => unreachable!(),
@@ -33,10 +29,7 @@ pub fn parse_unit_expr(r: Pair<Rule>) -> Result<UnitExpr, CalcError> {
                 let op = match nx.as_str().trim() {
                     "*" => UnitOp::Mul,
                     "/" => UnitOp::Div,
-                    s if s.starts_with('^') => {
-                        let n = s.strip_prefix('^').unwrap();
-                        UnitOp::Exp(n.parse().unwrap())
-                    }
+                    s if s.starts_with('^') => UnitOp::Exp(parse_unit_exponent(s)?),
                     _ => {
                         dbg!(nx);
                         panic!();
@@ -60,7 +53,7 @@ pub fn parse_unit_expr(r: Pair<Rule>) -> Result<UnitExpr, CalcError> {
                 }
                 inp.next();
 
-                let rhs = expr_bp(inp, r_bp)?;
+                let rhs = expr_bp(inp, r_bp, units)?;
                 lhs = UnitExpr::Cons(op, vec![lhs, rhs]);
 
                 continue;
@@ -72,7 +65,34 @@ pub fn parse_unit_expr(r: Pair<Rule>) -> Result<UnitExpr, CalcError> {
         }
     }
 
-    expr_bp(&mut r.into_inner(), 0)
+    expr_bp(&mut r.into_inner(), 0, units)
+}
+
+// The power in a unit like m^-2, Hz^0.5 or Hz^(1/2), given with its ^. Powers are
+// stored as fractions, so decimals have to be simple ones like 0.5 or 1.5.
+pub fn parse_unit_exponent(s: &str) -> Result<Ratio<i8>, CalcError> {
+    let invalid = || CalcError::Other(format!("Invalid unit power {}", s.trim()));
+    let power: String = s
+        .trim()
+        .trim_start_matches('^')
+        .chars()
+        .filter(|c| !matches!(c, '(' | ')' | ' '))
+        .collect();
+
+    match power.split_once('/') {
+        Some((numer, denom)) => {
+            let numer: i8 = numer.parse().map_err(|_| invalid())?;
+            let denom: i8 = denom.parse().map_err(|_| invalid())?;
+            if denom == 0 {
+                return Err(invalid());
+            }
+            Ok(Ratio::new(numer, denom))
+        }
+        None => {
+            let power: f64 = power.parse().map_err(|_| invalid())?;
+            power_ratio(power).ok_or_else(invalid)
+        }
+    }
 }
 
 pub fn unit_postfix_binding_power(op: &UnitOp) -> Option<(u8, ())> {
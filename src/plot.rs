@@ -0,0 +1,188 @@
+use crate::expr::unit::Unit;
+use crate::expr::{val::Val, Expr};
+use crate::latex::{shared_unit, FormatArgs, ToLaTeX};
+use crate::statement::Scope;
+use crate::sweep::zero_start;
+use crate::CalcError;
+
+// points sampled along each curve
+const PLOT_SAMPLES: usize = 100;
+
+// The packages a document with plots needs
+pub const PLOT_PREAMBLE: &str = "\\usepackage{pgfplots}\n\\pgfplotsset{compat=1.16}\n";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlotScale {
+    pub log_x: bool,
+    pub log_y: bool,
+}
+
+// `plot exprs for var in start..end`
+pub struct Plot<'a> {
+    pub exprs: &'a [Expr],
+    pub var: &'a str,
+    pub range: (Val, Val),
+    pub scale: PlotScale,
+}
+
+fn label(name: &str, unit: &str) -> String {
+    match (name.is_empty(), unit.is_empty()) {
+        (_, true) => format!("${}$", name),
+        (true, false) => format!("$({})$", unit),
+        (false, false) => format!("${}\\ ({})$", name, unit),
+    }
+}
+
+// pgfplots reads numbers in this form, and a few digits are plenty for a graph
+fn coord(x: f64) -> String {
+    format!("{:.5e}", x)
+}
+
+// Samples each expression along the range, giving a pgfplots axis with one series per
+// expression. The curves share the y axis, so they must all have the same unit.
+pub fn plot(plot: Plot, scope: &Scope, args: &FormatArgs) -> Result<String, CalcError> {
+    let Plot {
+        exprs,
+        var,
+        range: (start, end),
+        scale,
+    } = plot;
+    let start = zero_start(start, &end)?;
+    let (start, end) = (start.without_offset(), end.without_offset());
+    if end.unit.desc != start.unit.desc {
+        return Err(CalcError::UnitError(format!(
+            "The range for {} has different units, {} and {}",
+            var, start.unit, end.unit
+        )));
+    }
+
+    let (x0, x1) = (start.base_num(), end.base_num());
+    if x1 <= x0 {
+        return Err(CalcError::Other(format!(
+            "The range of {} must go forwards",
+            var
+        )));
+    }
+    if scale.log_x && x0 <= 0.0 {
+        return Err(CalcError::Other(format!(
+            "The range of {} must be positive for a log axis",
+            var
+        )));
+    }
+
+    // samples are evenly spaced along the axis, so a log axis spaces them geometrically
+    let unit = Unit {
+        desc: start.unit.desc.clone(),
+        ..Unit::empty()
+    };
+    let xs: Vec<Val> = (0..=PLOT_SAMPLES)
+        .map(|i| {
+            let f = i as f64 / PLOT_SAMPLES as f64;
+            let x = if scale.log_x {
+                x0 * (x1 / x0).powf(f)
+            } else {
+                x0 + (x1 - x0) * f
+            };
+            Ok(Val::empty(x).with_unit(&unit)?.clamp_num())
+        })
+        .collect::<Result<_, CalcError>>()?;
+
+    let mut series = Vec::new();
+    for expr in exprs {
+        let ys = xs
+            .iter()
+            .map(|x| {
+                let mut scope = scope.child();
+                scope.variables.insert(var.to_string(), x.clone().into());
+                Ok(expr.eval(&scope)?.scalar()?.without_offset())
+            })
+            .collect::<Result<Vec<Val>, CalcError>>()?;
+        series.push(ys);
+    }
+
+    let args = FormatArgs {
+        unit_hint: None,
+        ..*args
+    };
+    let (x_scale, x_unit) = shared_unit(&[&start, &end], &args)?;
+
+    let ys: Vec<&Val> = series.iter().flatten().collect();
+    if let Some(first) = ys.first() {
+        if let Some(y) = ys.iter().find(|y| y.unit.desc != first.unit.desc) {
+            return Err(CalcError::UnitError(format!(
+                "Everything plotted together must have the same units, got {} and {}",
+                first.unit, y.unit
+            )));
+        }
+    }
+    // complex values, and values a log axis can't show, are plotted as nan
+    // so the curve jumps over them, and don't count towards the unit of the axis
+    let shown =
+        |y: &Val| y.imag == 0.0 && y.base_num().is_finite() && (!scale.log_y || y.base_num() > 0.0);
+    let shown_ys: Vec<&Val> = ys.iter().copied().filter(|y| shown(y)).collect();
+    // a curve with nothing to show still has a unit
+    let unit_ys = if shown_ys.is_empty() { &ys } else { &shown_ys };
+    let (y_scale, y_unit) = shared_unit(unit_ys, &args)?;
+
+    let names = exprs
+        .iter()
+        .map(|e| Ok(e.to_latex_ext(&args)?.to_string().trim().to_string()))
+        .collect::<Result<Vec<String>, CalcError>>()?;
+
+    let mut options = vec![
+        format!("xlabel={{{}}}", label(var, &x_unit)),
+        format!(
+            "ylabel={{{}}}",
+            label(if names.len() == 1 { &names[0] } else { "" }, &y_unit)
+        ),
+    ];
+    if scale.log_x {
+        options.push("xmode=log".to_string());
+    }
+    if scale.log_y {
+        options.push("ymode=log".to_string());
+    }
+    if names.len() > 1 {
+        options.push("legend pos=outer north east".to_string());
+    }
+    if shown_ys.len() < ys.len() {
+        options.push("unbounded coords=jump".to_string());
+    }
+
+    let mut plots = String::new();
+    for (name, ys) in names.iter().zip(&series) {
+        let coords: Vec<String> = xs
+            .iter()
+            .zip(ys)
+            .map(|(x, y)| {
+                let y = if shown(y) {
+                    coord(y.base_num() / y_scale)
+                } else {
+                    "nan".to_string()
+                };
+                format!("({}, {})", coord(x.base_num() / x_scale), y)
+            })
+            .collect();
+
+        plots.push_str(&format!(
+            "            \\addplot[no marks] coordinates {{{}}};\n",
+            coords.join(" ")
+        ));
+        if names.len() > 1 {
+            plots.push_str(&format!("            \\addlegendentry{{${}$}}\n", name));
+        }
+    }
+
+    Ok(format!(
+        "
+    \\begin{{center}}
+        \\begin{{tikzpicture}}
+        \\begin{{axis}}[{options}]
+{plots}        \\end{{axis}}
+        \\end{{tikzpicture}}
+    \\end{{center}}
+    ",
+        options = options.join(", "),
+        plots = plots
+    ))
+}
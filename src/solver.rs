@@ -0,0 +1,243 @@
+use crate::expr::symbolic::unknown_unit;
+use crate::expr::unit::Unit;
+use crate::expr::{val::Val, Expr, Op};
+use crate::statement::Scope;
+use crate::CalcError;
+
+const MAX_ITERATIONS: usize = 200;
+// points checked for a sign change when looking for a bracket
+const SCAN_POINTS: usize = 200;
+
+// The equation `lhs = rhs`, as a function of the unknown `var`
+struct Equation<'a> {
+    var: &'a str,
+    lhs: &'a Expr,
+    rhs: &'a Expr,
+    unit: Unit,
+    scope: &'a Scope<'a>,
+}
+
+impl Equation<'_> {
+    // The unknown with the value x, in base units
+    fn bind(&self, x: f64) -> Result<Val, CalcError> {
+        Ok(Val::empty(x).with_unit(&self.unit)?.clamp_num())
+    }
+
+    // lhs - rhs in base units, or None where it isn't a real number
+    fn residual(&self, x: f64) -> Result<Option<f64>, CalcError> {
+        let mut scope = self.scope.child();
+        scope
+            .variables
+            .insert(self.var.to_string(), self.bind(x)?.into());
+
+        let diff = (self.lhs.eval(&scope)?.scalar()? - self.rhs.eval(&scope)?.scalar()?)?;
+        let r = diff.base_num();
+        Ok(if diff.imag != 0.0 || !r.is_finite() {
+            None
+        } else {
+            Some(r)
+        })
+    }
+
+    // The size of the two sides, to judge whether a residual is close enough to zero
+    fn magnitude(&self, x: f64) -> Result<f64, CalcError> {
+        let mut scope = self.scope.child();
+        scope
+            .variables
+            .insert(self.var.to_string(), self.bind(x)?.into());
+
+        let lhs = self.lhs.eval(&scope)?.scalar()?.base_num().abs();
+        let rhs = self.rhs.eval(&scope)?.scalar()?.base_num().abs();
+        Ok(lhs.max(rhs))
+    }
+
+    fn is_root(&self, x: f64) -> Result<bool, CalcError> {
+        Ok(match self.residual(x)? {
+            Some(r) => r.abs() <= 1e-9 * self.magnitude(x)? || r == 0.0,
+            None => false,
+        })
+    }
+
+    // Bisection between two points where the residual has opposite signs
+    fn bisect(&self, mut lo: f64, mut hi: f64) -> Result<Option<f64>, CalcError> {
+        let mut r_lo = self.residual(lo)?.ok_or(CalcError::MathError)?;
+        for _ in 0..MAX_ITERATIONS {
+            let mid = lo + (hi - lo) / 2.0;
+            if mid == lo || mid == hi {
+                break;
+            }
+
+            match self.residual(mid)? {
+                Some(0.0) => return Ok(Some(mid)),
+                Some(r) if r.signum() == r_lo.signum() => {
+                    lo = mid;
+                    r_lo = r;
+                }
+                Some(_) => hi = mid,
+                None => return Ok(None),
+            }
+        }
+
+        let x = lo + (hi - lo) / 2.0;
+        // a sign change across a pole like 1/x isn't a root
+        Ok(if self.is_root(x)? { Some(x) } else { None })
+    }
+
+    // Looks for the first sign change between consecutive points
+    fn scan(&self, points: &[f64]) -> Result<Option<f64>, CalcError> {
+        let mut prev: Option<(f64, f64)> = None;
+        for &x in points {
+            let r = match self.residual(x)? {
+                Some(r) => r,
+                None => {
+                    prev = None;
+                    continue;
+                }
+            };
+            if r == 0.0 {
+                return Ok(Some(x));
+            }
+            if let Some((px, pr)) = prev {
+                if pr.signum() != r.signum() {
+                    if let Some(root) = self.bisect(px, x)? {
+                        return Ok(Some(root));
+                    }
+                }
+            }
+            prev = Some((x, r));
+        }
+        Ok(None)
+    }
+
+    // The secant method, which is quick when started near a root
+    fn secant(&self, guess: f64) -> Result<Option<f64>, CalcError> {
+        let mut x0 = guess;
+        let mut x1 = if guess == 0.0 { 1e-3 } else { guess * 1.001 };
+        let mut r0 = match self.residual(x0)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        for _ in 0..MAX_ITERATIONS {
+            let r1 = match self.residual(x1)? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            if r1 == r0 {
+                break;
+            }
+
+            let x2 = x1 - r1 * (x1 - x0) / (r1 - r0);
+            if !x2.is_finite() {
+                return Ok(None);
+            }
+            if (x2 - x1).abs() <= 1e-14 * x2.abs().max(1e-300) {
+                x1 = x2;
+                break;
+            }
+
+            x0 = x1;
+            r0 = r1;
+            x1 = x2;
+        }
+
+        Ok(if self.is_root(x1)? { Some(x1) } else { None })
+    }
+}
+
+// Finds a value of `var` which makes `lhs = rhs`. With a range the first root inside
+// it is found, otherwise the search starts at the variable's current value, or 1
+// in whatever unit makes the two sides match.
+pub fn solve_equation(
+    var: &str,
+    lhs: &Expr,
+    rhs: &Expr,
+    range: Option<(Val, Val)>,
+    scope: &Scope,
+) -> Result<Val, CalcError> {
+    let (start, assumed) = match (&range, scope.variable(var)) {
+        (Some((lo, _)), _) => (lo.without_offset(), false),
+        (None, Some(v)) => (v.clone().scalar()?.without_offset(), false),
+        (None, None) => {
+            let residual = Expr::Cons(Op::Minus, vec![lhs.clone(), rhs.clone()]);
+            let start = match unknown_unit(&residual, var, scope)? {
+                Some(unit) => Val::empty(1.0).with_unit(&unit)?,
+                None => Val::empty(1.0),
+            };
+            (start, true)
+        }
+    };
+
+    let eq = Equation {
+        var,
+        lhs,
+        rhs,
+        unit: Unit {
+            desc: start.unit.desc.clone(),
+            ..Unit::empty()
+        },
+        scope,
+    };
+
+    // the unknown's unit comes from its range or current value, without
+    // either it's worked out from the equation, which is the likely cause of an error here
+    match eq.residual(start.base_num()) {
+        Err(e) if assumed => {
+            return Err(CalcError::UnitError(format!(
+                "{}, give {} a range in the units it should have like `solve {} in a..b: ...`",
+                e, var, var
+            )))
+        }
+        Err(e) => return Err(e),
+        Ok(_) => {}
+    }
+
+    let root = match range {
+        Some((lo, hi)) => {
+            let hi = hi.without_offset();
+            if hi.unit.desc != start.unit.desc {
+                return Err(CalcError::UnitError(format!(
+                    "The range for {} has different units, {} and {}",
+                    var, start.unit, hi.unit
+                )));
+            }
+
+            let (a, b) = (start.base_num(), hi.base_num());
+            let points: Vec<f64> = (0..=SCAN_POINTS)
+                .map(|i| a + (b - a) * i as f64 / SCAN_POINTS as f64)
+                .collect();
+            eq.scan(&points)?.ok_or_else(|| {
+                CalcError::Other(format!("No solution for {} between {} and {}", var, lo, hi))
+            })?
+        }
+        None => {
+            let guess = start.base_num();
+            match eq.secant(guess)? {
+                Some(x) => x,
+                None => {
+                    // fall back to looking for a sign change further and further
+                    // out from zero, on the guess's side first
+                    let size = if guess == 0.0 { 1.0 } else { guess.abs() };
+                    let side = if guess < 0.0 { -1.0 } else { 1.0 };
+                    let outwards = |sign: f64| -> Vec<f64> {
+                        std::iter::once(0.0)
+                            .chain((-12..=12).map(|k| sign * size * 2f64.powi(k)))
+                            .collect()
+                    };
+                    let root = match eq.scan(&outwards(side))? {
+                        Some(x) => Some(x),
+                        None => eq.scan(&outwards(-side))?,
+                    };
+                    root.ok_or_else(|| {
+                        CalcError::Other(format!(
+                            "Couldn't find a solution for {}, try giving a range like `solve {} in 0..10: ...`",
+                            var, var
+                        ))
+                    })?
+                }
+            }
+        }
+    };
+
+    eq.bind(root)
+}
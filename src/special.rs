@@ -0,0 +1,309 @@
+// Special functions and the distributions built on them, accurate to around 1e-14
+use std::f64::consts::{PI, SQRT_2};
+
+const MAX_ITERATIONS: usize = 300;
+const EPS: f64 = 1e-15;
+
+// Lanczos approximation with g = 7
+const LANCZOS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+pub fn gamma(x: f64) -> f64 {
+    if x.fract() == 0.0 && (1.0..=171.0).contains(&x) {
+        // whole numbers are factorials, which are exact up to 22!
+        (1..x as u32).map(f64::from).product()
+    } else if x.fract() == 0.0 && x <= 0.0 {
+        // the poles 0, -1, -2... go to infinity with a different sign on each side
+        f64::NAN
+    } else if x < 0.5 {
+        // reflection formula
+        PI / ((PI * x).sin() * gamma(1.0 - x))
+    } else {
+        ln_gamma(x).exp()
+    }
+}
+
+// ln(gamma(x)) for x > 0, which doesn't overflow for large x
+pub fn ln_gamma(x: f64) -> f64 {
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = LANCZOS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0));
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+pub fn beta(a: f64, b: f64) -> f64 {
+    if a > 0.0 && b > 0.0 {
+        (ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)).exp()
+    } else {
+        gamma(a) * gamma(b) / gamma(a + b)
+    }
+}
+
+// The regularized lower incomplete gamma function P(a, x), and its complement Q(a, x)
+fn inc_gamma(a: f64, x: f64) -> (f64, f64) {
+    if x <= 0.0 {
+        return (0.0, 1.0);
+    }
+    let prefix = (a * x.ln() - x - ln_gamma(a)).exp();
+
+    if x < a + 1.0 {
+        // the series converges quickly here
+        let (mut term, mut sum) = (1.0 / a, 1.0 / a);
+        for n in 1..MAX_ITERATIONS {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * EPS {
+                break;
+            }
+        }
+        let p = sum * prefix;
+        (p, 1.0 - p)
+    } else {
+        // and the continued fraction converges quickly here, using Lentz's method
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for n in 1..MAX_ITERATIONS {
+            let an = -(n as f64) * (n as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            d = if d.abs() < tiny { tiny } else { d };
+            c = b + an / c;
+            c = if c.abs() < tiny { tiny } else { c };
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < EPS {
+                break;
+            }
+        }
+        let q = prefix * h;
+        (1.0 - q, q)
+    }
+}
+
+pub fn erf(x: f64) -> f64 {
+    let p = inc_gamma(0.5, x * x).0;
+    if x < 0.0 {
+        -p
+    } else {
+        p
+    }
+}
+
+// 1 - erf(x), without losing the tail to rounding
+pub fn erfc(x: f64) -> f64 {
+    let q = inc_gamma(0.5, x * x).1;
+    if x < 0.0 {
+        2.0 - q
+    } else {
+        q
+    }
+}
+
+// The continued fraction for the incomplete beta function
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+    let tiny = 1e-300;
+    let clamp = |v: f64| if v.abs() < tiny { tiny } else { v };
+    let mut c = 1.0;
+    let mut d = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+    for m in 1..MAX_ITERATIONS {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / clamp(1.0 + even * d);
+        c = clamp(1.0 + even / c);
+        h *= d * c;
+
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / clamp(1.0 + odd * d);
+        c = clamp(1.0 + odd / c);
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < EPS {
+            break;
+        }
+    }
+    h
+}
+
+// The regularized incomplete beta function I_x(a, b)
+fn inc_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let prefix =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        prefix * beta_cf(a, b, x) / a
+    } else {
+        1.0 - prefix * beta_cf(b, a, 1.0 - x) / b
+    }
+}
+
+pub fn norm_cdf(x: f64, mu: f64, sigma: f64) -> f64 {
+    if sigma <= 0.0 {
+        return f64::NAN;
+    }
+    0.5 * erfc(-(x - mu) / (sigma * SQRT_2))
+}
+
+// The inverse of the standard normal CDF, using Acklam's rational approximation
+// followed by a step of Halley's method
+pub fn norm_inv(p: f64, mu: f64, sigma: f64) -> f64 {
+    if !(0.0..=1.0).contains(&p) || sigma <= 0.0 {
+        return f64::NAN;
+    }
+    if p == 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p == 1.0 {
+        return f64::INFINITY;
+    }
+
+    let a = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    let b = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    let c = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    let d = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    let poly = |coeffs: &[f64], x: f64| coeffs.iter().fold(0.0, |acc, c| acc * x + c);
+
+    let p_low = 0.02425;
+    let z = if p < p_low {
+        let q = (-2.0 * p.ln()).sqrt();
+        poly(&c, q) / (poly(&d, q) * q + 1.0)
+    } else if p <= 1.0 - p_low {
+        let q = p - 0.5;
+        let r = q * q;
+        poly(&a, r) * q / (poly(&b, r) * r + 1.0)
+    } else {
+        let q = (-2.0 * (1.0 - p).ln()).sqrt();
+        -poly(&c, q) / (poly(&d, q) * q + 1.0)
+    };
+
+    let e = norm_cdf(z, 0.0, 1.0) - p;
+    let u = e * (2.0 * PI).sqrt() * (z * z / 2.0).exp();
+    let z = z - u / (1.0 + z * u / 2.0);
+    mu + sigma * z
+}
+
+// The chance of exactly k successes in n trials which each succeed with probability p
+pub fn binom_pdf(n: f64, p: f64, k: f64) -> f64 {
+    if n.fract() != 0.0 || k.fract() != 0.0 || n < 0.0 || !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    if k < 0.0 || k > n {
+        return 0.0;
+    }
+    // 0^0 is 1 here, which the logs below can't handle
+    if p == 0.0 || p == 1.0 {
+        let certain = if p == 0.0 { 0.0 } else { n };
+        return if k == certain { 1.0 } else { 0.0 };
+    }
+    let ln_choose = ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0);
+    (ln_choose + k * p.ln() + (n - k) * (1.0 - p).ln()).exp()
+}
+
+// The chance of exactly k events when lambda are expected
+pub fn poisson_pdf(lambda: f64, k: f64) -> f64 {
+    if k.fract() != 0.0 || lambda < 0.0 {
+        return f64::NAN;
+    }
+    if k < 0.0 {
+        return 0.0;
+    }
+    if lambda == 0.0 {
+        return if k == 0.0 { 1.0 } else { 0.0 };
+    }
+    (k * lambda.ln() - lambda - ln_gamma(k + 1.0)).exp()
+}
+
+// The CDF of Student's t distribution with df degrees of freedom
+pub fn t_cdf(t: f64, df: f64) -> f64 {
+    if df <= 0.0 {
+        return f64::NAN;
+    }
+    let tail = 0.5 * inc_beta(df / 2.0, 0.5, df / (df + t * t));
+    if t > 0.0 {
+        1.0 - tail
+    } else {
+        tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-12 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn special_functions() {
+        assert!(close(gamma(5.0), 24.0));
+        assert!(close(gamma(0.5), PI.sqrt()));
+        assert!(close(gamma(-0.5), -2.0 * PI.sqrt()));
+        assert!(gamma(-1.0).is_nan() && gamma(0.0).is_nan());
+        assert!(close(beta(2.0, 3.0), 1.0 / 12.0));
+        assert!(close(erf(1.0), 0.842_700_792_949_714_9));
+        assert!(close(erf(-0.5), -0.520_499_877_813_046_5));
+        assert!(close(erfc(5.0), 1.537_459_794_428_034_8e-12));
+    }
+
+    #[test]
+    fn distributions() {
+        assert!(close(norm_cdf(1.96, 0.0, 1.0), 0.975_002_104_851_780));
+        assert!(close(norm_cdf(12.0, 10.0, 2.0), 0.841_344_746_068_542_9));
+        assert!(close(norm_inv(0.975, 0.0, 1.0), 1.959_963_984_540_054));
+        assert!(close(
+            norm_inv(0.001, 5.0, 2.0),
+            5.0 - 2.0 * 3.090_232_306_167_813_6
+        ));
+        assert!(close(binom_pdf(10.0, 0.5, 3.0), 120.0 / 1024.0));
+        assert!(close(poisson_pdf(2.0, 3.0), 8.0 / 6.0 * (-2.0f64).exp()));
+        assert!(close(t_cdf(2.0, 5.0), 0.949_030_260_585_070_5));
+        assert!(close(t_cdf(-1.0, 1.0), 0.25));
+    }
+}
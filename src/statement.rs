@@ -1,17 +1,75 @@
+use crate::chem::{exact_count, Reaction};
+use crate::constants::CONSTANTS;
+use crate::data::{load_csv, ColumnSpec};
 use crate::expr::bool_expr::BoolExpr;
+use crate::expr::symbolic::{diff_vars, expand_diffs};
+use crate::expr::unit::{find_unit, CustomUnits, Unit};
+use crate::function::{is_builtin, FnDef};
+use crate::latex::FormatArgs;
 use crate::latex::UnitHint;
+use crate::ode::{simulate, OdeMethod, OdeProblem};
+use crate::plot::{plot, Plot, PlotScale, PLOT_PREAMBLE};
+use crate::solver::solve_equation;
+use crate::sweep::{tabulate, Sweep};
+use crate::ttable::{generate_tabular, unit_column};
 use crate::CalcError;
-use crate::{expr::unit::Unit, latex::FormatArgs};
 use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-use crate::{expr::val::Val, parser};
+use crate::{expr::val::Val, expr::value::Value, parser};
 use crate::{expr::Expr, latex::ToLaTeX};
 
-pub struct Scope {
-    pub variables: BTreeMap<String, Val>,
+pub struct Scope<'a> {
+    pub variables: BTreeMap<String, Value>,
+    pub functions: BTreeMap<String, FnDef>,
+    // units defined with the `unit` statement, which only the document's scope has
+    pub units: CustomUnits,
+    // the scope this one was made from, whose names it sees unless it binds its own
+    pub parent: Option<&'a Scope<'a>>,
+    // how many user defined function calls deep this scope is
+    pub depth: usize,
 }
 
-impl Default for Scope {
+impl<'a> Scope<'a> {
+    // A scope which sees everything in this one, used to bind variables
+    // without clobbering the parent or copying its contents.
+    pub fn child(&'a self) -> Scope<'a> {
+        Scope {
+            variables: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            units: CustomUnits::new(),
+            parent: Some(self),
+            depth: self.depth + 1,
+        }
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables
+            .get(name)
+            .or_else(|| self.parent?.variable(name))
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FnDef> {
+        self.functions
+            .get(name)
+            .or_else(|| self.parent?.function(name))
+    }
+
+    // A unit by name, which may be one the document defined
+    pub fn unit(&self, name: &str) -> Result<Unit, CalcError> {
+        find_unit(name, &self.global().units)
+    }
+
+    // The document's scope, which every other scope is made from
+    pub fn global(&self) -> &Scope<'a> {
+        match self.parent {
+            Some(parent) => parent.global(),
+            None => self,
+        }
+    }
+}
+
+impl Default for Scope<'_> {
     fn default() -> Self {
         let mut variables = BTreeMap::new();
         variables.insert(
@@ -19,16 +77,40 @@ impl Default for Scope {
             Val {
                 num: std::f64::consts::PI,
                 unit: Unit::empty(),
-            },
+                uncertainty: 0.0,
+                sig_figs: None,
+                exact: None,
+                imag: 0.0,
+            }
+            .into(),
         );
         variables.insert(
             "e".to_owned(),
             Val {
                 num: std::f64::consts::E,
                 unit: Unit::empty(),
-            },
+                uncertainty: 0.0,
+                sig_figs: None,
+                exact: None,
+                imag: 0.0,
+            }
+            .into(),
         );
-        Scope { variables }
+        variables.insert(
+            "i".to_owned(),
+            Val {
+                imag: 1.0,
+                ..Val::empty(0.0)
+            }
+            .into(),
+        );
+        Scope {
+            variables,
+            functions: BTreeMap::new(),
+            units: CustomUnits::new(),
+            parent: None,
+            depth: 0,
+        }
     }
 }
 
@@ -41,23 +123,73 @@ pub enum Statement {
     PrintExpr {
         expr: Expr,
         unit_hint: Option<UnitHint>,
+        polar: bool,
     },
     DecPrintExpr {
         lhs: String,
         rhs: Expr,
         unit_hint: Option<UnitHint>,
+        polar: bool,
+    },
+    FnDec {
+        name: String,
+        params: Vec<String>,
+        body: Expr,
     },
     Alias {
         lhs: String,
         rhs: String,
     },
+    UnitDec {
+        name: String,
+        rhs: Option<Expr>,
+    },
     DigitSet(usize),
     SetScientific,
+    SetSigFigs,
+    SetExact,
+    ImportConstants,
     LineGap,
     TTable {
         args: Vec<String>,
         exprs: Vec<BoolExpr>,
     },
+    Solve {
+        var: String,
+        lhs: Expr,
+        rhs: Expr,
+        range: Option<Box<(Expr, Expr)>>,
+    },
+    Simulate {
+        state: String,
+        time: String,
+        rhs: Expr,
+        initial: Expr,
+        range: Box<(Expr, Expr)>,
+        step: Expr,
+        method: OdeMethod,
+    },
+    Table {
+        var: String,
+        range: Box<(Expr, Expr)>,
+        step: Expr,
+        exprs: Vec<Expr>,
+    },
+    Load {
+        path: String,
+        name: String,
+        columns: Vec<ColumnSpec>,
+    },
+    Plot {
+        exprs: Vec<Expr>,
+        var: String,
+        range: Box<(Expr, Expr)>,
+        scale: PlotScale,
+    },
+    Balance {
+        reactants: Vec<String>,
+        products: Vec<String>,
+    },
     RawLaTeX(String),
 }
 
@@ -66,28 +198,41 @@ pub struct State {
     // Contains the variables in the program.
     // Currently there is only one global scope
     // and I don't think more is necessary
-    pub scope: Scope,
+    pub scope: Scope<'static>,
     // The statements to be executed
     pub statements: Vec<(usize, Statement)>,
     // The LaTeX output buffer
     pub output: String,
     pub format_args: FormatArgs,
     pub aliases: BTreeMap<String, String>,
+    // where files loaded by the program are looked for
+    pub dir: PathBuf,
 }
 
 impl State {
-    pub fn new(contents: &str) -> Result<Self, CalcError> {
-        let output = "\\documentclass{article}\n\\begin{document}\n".to_string();
+    pub fn new(contents: &str, dir: &Path) -> Result<Self, CalcError> {
+        let mut scope = Scope::default();
+        let statements = parser::parse_block(&contents, &mut scope.units)?;
+
+        let mut output = "\\documentclass{article}\n".to_string();
+        if statements
+            .iter()
+            .any(|(_, s)| matches!(s, Statement::Plot { .. }))
+        {
+            output.push_str(PLOT_PREAMBLE);
+        }
+        output.push_str("\\begin{document}\n");
 
         let mut aliases = BTreeMap::new();
         aliases.insert("pi".to_string(), "\\pi".to_string());
 
         Ok(State {
-            scope: Scope::default(),
-            statements: parser::parse_block(&contents)?,
+            scope,
+            statements,
             output,
             format_args: FormatArgs::default(),
             aliases,
+            dir: dir.to_path_buf(),
         })
     }
 
@@ -105,10 +250,81 @@ impl State {
                 Statement::SetScientific => {
                     self.format_args.scientific_notation = !self.format_args.scientific_notation
                 }
+                Statement::SetSigFigs => self.format_args.sig_figs = !self.format_args.sig_figs,
+                Statement::SetExact => self.format_args.exact = !self.format_args.exact,
+                Statement::ImportConstants => {
+                    // Example: `!import constants` defines c, G, h and so on, and
+                    // prints a table of them with their uncertainties
+                    let mut rows = Vec::new();
+                    for constant in CONSTANTS {
+                        let val = constant.val().map_err(add_line)?;
+                        // importing twice is fine, but a variable of the user's isn't replaced
+                        if let Some(existing) = self.scope.variables.get(constant.name) {
+                            let same = matches!(existing, Value::Scalar(v)
+                                if v.unit.desc == val.unit.desc && v.base_num() == val.base_num());
+                            if !same {
+                                return Err(add_line(CalcError::Other(format!(
+                                    "Importing constants would replace the variable {}, rename it or import the constants first",
+                                    constant.name
+                                ))));
+                            }
+                        }
+                        if let Some(alias) = constant.alias {
+                            self.aliases
+                                .entry(alias.to_string())
+                                .or_insert_with(|| constant.name.to_string());
+                        }
+
+                        // constants are mostly tiny or huge, and read best in their usual units
+                        let format_args = FormatArgs {
+                            unit_hint: constant.unit_hint().map_err(add_line)?,
+                            scientific_notation: true,
+                            sig_figs: true,
+                            ..self.format_args
+                        };
+                        rows.push(vec![
+                            format!("${}$", constant.name),
+                            format!(
+                                "${}$",
+                                constant
+                                    .display_val()
+                                    .map_err(add_line)?
+                                    .to_latex_ext(&format_args)
+                                    .map_err(add_line)?
+                                    .to_string()
+                                    .trim_end()
+                            ),
+                        ]);
+                        self.scope
+                            .variables
+                            .insert(constant.name.to_string(), val.into());
+                    }
+                    let header = ["Constant".to_string(), "Value".to_string()];
+                    self.output.push_str(&generate_tabular(&header, &rows));
+                }
                 Statement::Alias { lhs, rhs } => {
                     self.aliases.insert(lhs.to_owned(), rhs.to_owned());
                 }
                 Statement::RawLaTeX(s) => self.output.push_str(s),
+                // the unit itself is defined by the parser, and new
+                // base dimensions don't print anything
+                Statement::UnitDec { rhs: None, .. } => {}
+                Statement::UnitDec {
+                    name,
+                    rhs: Some(rhs),
+                } => {
+                    self.output.push_str(
+                        format!(
+                            "$1\\ \\mathrm{{{}}} = {}$\\\\\n",
+                            name,
+                            rhs.to_latex_ext(&self.format_args)
+                                .map_err(add_line)?
+                                .to_string()
+                                .trim_end(),
+                        )
+                        .as_str(),
+                    );
+                }
                 Statement::VarDec { lhs, rhs } => {
                     // lhs is just the variable name.
                     // rhs is an expression. In this case, we don't
@@ -137,7 +353,42 @@ impl State {
                         .variables
                         .insert(lhs.clone(), rhs.eval(&self.scope).map_err(add_line)?);
                 }
-                Statement::PrintExpr { expr, unit_hint } => {
+                Statement::FnDec { name, params, body } => {
+                    // Example: `KE(m, v) = 1/2 * m * v^2` is rendered like a VarDec,
+                    // the body isn't evaluated until the function is called.
+                    if is_builtin(name) {
+                        return Err(add_line(CalcError::Other(format!(
+                            "Can't redefine builtin function {}",
+                            name
+                        ))));
+                    }
+
+                    let params: Vec<String> =
+                        params.iter().map(|p| self.resolve_alias(p)).collect();
+                    let mut body = body.clone();
+                    body.resolve_aliases(&self.aliases);
+
+                    self.output.push_str(
+                        format!(
+                            "$\\text{{{}}}({}) = {}$\\\\\n",
+                            name,
+                            params.join(", "),
+                            body.to_latex_ext(&self.format_args)
+                                .map_err(add_line)?
+                                .to_string()
+                                .trim_end(),
+                        )
+                        .as_str(),
+                    );
+                    self.scope
+                        .functions
+                        .insert(name.clone(), FnDef { params, body });
+                }
+                Statement::PrintExpr {
+                    expr,
+                    unit_hint,
+                    polar,
+                } => {
                     // Example: `5 * 10 kg = ? g` gets parsed roughly as
                     //
                     // ```
@@ -148,12 +399,53 @@ impl State {
                     // ```
                     let format_args = FormatArgs {
                         unit_hint: unit_hint.clone(),
+                        polar: *polar,
                         ..self.format_args
                     };
 
                     let mut expr = expr.clone();
                     expr.resolve_aliases(&self.aliases);
 
+                    // symbolic derivatives are printed as expressions, followed
+                    // by their value if the variables they're taken with respect to
+                    // are defined
+                    if let Some(symbolic) = expand_diffs(&expr, &self.scope).map_err(add_line)? {
+                        let symbolic = symbolic
+                            .remove_parens()
+                            .to_latex_ext(&self.format_args)
+                            .map_err(add_line)?
+                            .to_string();
+                        let defined = diff_vars(&expr)
+                            .iter()
+                            .all(|var| self.scope.variable(var).is_some());
+                        let value = if defined {
+                            expr.eval(&self.scope)
+                                .and_then(|v| v.to_latex_ext(&format_args))
+                                .map_err(add_line)?
+                                .to_string()
+                        } else {
+                            String::new()
+                        };
+                        let value = if value.is_empty() || value.trim() == symbolic.trim() {
+                            String::new()
+                        } else {
+                            format!(" = {}", value.trim_end())
+                        };
+                        self.output.push_str(
+                            format!(
+                                "${} = {}{}$\\\\\n",
+                                expr.to_latex_ext(&self.format_args)
+                                    .map_err(add_line)?
+                                    .to_string()
+                                    .trim(),
+                                symbolic.trim(),
+                                value,
+                            )
+                            .as_str(),
+                        );
+                        continue;
+                    }
+
                     self.output.push_str(
                         format!(
                             "${} = {}$\\\\\n",
@@ -175,6 +467,7 @@ impl State {
                     lhs,
                     rhs,
                     unit_hint,
+                    polar,
                 } => {
                     let lhs = self.resolve_alias(lhs);
                     let mut rhs = rhs.clone();
@@ -185,6 +478,7 @@ impl State {
                     let val = rhs.eval(&self.scope).map_err(add_line)?;
                     let format_args = FormatArgs {
                         unit_hint: unit_hint.clone(),
+                        polar: *polar,
                         ..self.format_args
                     };
 
@@ -205,6 +499,238 @@ impl State {
                     );
                     self.scope.variables.insert(lhs.clone(), val.clamp_num());
                 }
+                Statement::Solve {
+                    var,
+                    lhs,
+                    rhs,
+                    range,
+                } => {
+                    // Example: `solve v: v^2 / (2 * g) = 20 m` finds v numerically,
+                    // then prints the equation followed by the solution
+                    let var = self.resolve_alias(var);
+                    let (mut lhs, mut rhs) = (lhs.clone(), rhs.clone());
+                    lhs.resolve_aliases(&self.aliases);
+                    rhs.resolve_aliases(&self.aliases);
+
+                    let range = match range.as_deref() {
+                        Some((start, end)) => Some((
+                            start
+                                .eval(&self.scope)
+                                .and_then(Value::scalar)
+                                .map_err(add_line)?,
+                            end.eval(&self.scope)
+                                .and_then(Value::scalar)
+                                .map_err(add_line)?,
+                        )),
+                        None => None,
+                    };
+                    let val =
+                        solve_equation(&var, &lhs, &rhs, range, &self.scope).map_err(add_line)?;
+
+                    self.output.push_str(
+                        format!(
+                            "${} = {} \\quad \\Rightarrow \\quad {} = {}$\\\\\n",
+                            lhs.to_latex_ext(&self.format_args)
+                                .map_err(add_line)?
+                                .to_string()
+                                .trim(),
+                            rhs.to_latex_ext(&self.format_args)
+                                .map_err(add_line)?
+                                .to_string()
+                                .trim(),
+                            var.trim(),
+                            val.to_latex_ext(&self.format_args)
+                                .map_err(add_line)?
+                                .to_string()
+                                .trim_end(),
+                        )
+                        .as_str(),
+                    );
+                    self.scope.variables.insert(var, val.into());
+                }
+                Statement::Simulate {
+                    state,
+                    time,
+                    rhs,
+                    initial,
+                    range,
+                    step,
+                    method,
+                } => {
+                    // Example: `simulate dv/dt = -v / (2 s), v = 1 m/s, t in 0 s..10 s, step 0.1 s`
+                    // prints the equation and a table of samples, then stores the final v and t
+                    let (state, time) = (self.resolve_alias(state), self.resolve_alias(time));
+                    let mut rhs = rhs.clone();
+                    rhs.resolve_aliases(&self.aliases);
+
+                    let e = |e: &Expr| {
+                        e.eval(&self.scope)
+                            .and_then(Value::scalar)
+                            .map_err(add_line)
+                    };
+                    let (start, end) = range.as_ref();
+                    let problem = OdeProblem {
+                        state: &state,
+                        time: &time,
+                        rhs: &rhs,
+                        initial: e(initial)?,
+                        range: (e(start)?, e(end)?),
+                        step: e(step)?,
+                        method: *method,
+                        state_hint: initial.unit_hint(),
+                        // a range like 0..10 s is shown in the end's unit
+                        time_hint: start.unit_hint().or_else(|| end.unit_hint()),
+                    };
+                    let sim =
+                        simulate(problem, &self.scope, &self.format_args).map_err(add_line)?;
+
+                    let latex = |e: &Expr| -> Result<String, CalcError> {
+                        Ok(e.to_latex_ext(&self.format_args)
+                            .map_err(add_line)?
+                            .to_string()
+                            .trim()
+                            .to_string())
+                    };
+                    self.output.push_str(
+                        format!(
+                            "$\\frac{{d{}}}{{d{}}} = {}, \\quad {}({}) = {}$\\\\\n",
+                            state,
+                            time,
+                            latex(&rhs)?,
+                            state,
+                            latex(start)?,
+                            latex(initial)?,
+                        )
+                        .as_str(),
+                    );
+                    self.output.push_str(&sim.table);
+                    self.scope.variables.insert(time, sim.time.into());
+                    self.scope.variables.insert(state, sim.state.into());
+                }
+                Statement::Table {
+                    var,
+                    range,
+                    step,
+                    exprs,
+                } => {
+                    // Example: `table t in 0 s..2 s, step 0.5 s: 1/2 * g * t^2` prints
+                    // a row for each t, without changing any variables
+                    let var = self.resolve_alias(var);
+                    let mut exprs = exprs.clone();
+                    exprs
+                        .iter_mut()
+                        .for_each(|e| e.resolve_aliases(&self.aliases));
+
+                    let e = |e: &Expr| {
+                        e.eval(&self.scope)
+                            .and_then(Value::scalar)
+                            .map_err(add_line)
+                    };
+                    let (start, end) = range.as_ref();
+                    let sweep = Sweep {
+                        var: &var,
+                        range: (e(start)?, e(end)?),
+                        step: e(step)?,
+                        exprs: &exprs,
+                        unit_hint: start.unit_hint().or_else(|| end.unit_hint()),
+                    };
+                    self.output.push_str(
+                        &tabulate(sweep, &self.scope, &self.format_args).map_err(add_line)?,
+                    );
+                }
+                Statement::Load {
+                    path,
+                    name,
+                    columns,
+                } => {
+                    // Example: `load data.csv as d with [t: s, x: m]` prints the data
+                    // as a table and stores the columns as the lists d_t and d_x
+                    let columns = load_csv(&self.dir.join(path), columns).map_err(add_line)?;
+
+                    let mut header = Vec::new();
+                    let mut cells = Vec::new();
+                    for (column, vals) in columns {
+                        // longer names are braced so the whole name is the subscript,
+                        // and can still be typed without the braces
+                        let var = if column.chars().count() == 1 {
+                            format!("{}_{}", name, column)
+                        } else {
+                            let var = format!("{}_{{{}}}", name, column);
+                            self.aliases
+                                .insert(format!("{}_{}", name, column), var.clone());
+                            var
+                        };
+
+                        let (h, c) =
+                            unit_column(&var, &vals, None, &self.format_args).map_err(add_line)?;
+                        header.push(h);
+                        cells.push(c);
+                        self.scope
+                            .variables
+                            .insert(var, Value::vector(vals).map_err(add_line)?);
+                    }
+
+                    let rows: Vec<Vec<String>> = (0..cells.first().map_or(0, Vec::len))
+                        .map(|i| cells.iter().map(|c| c[i].clone()).collect())
+                        .collect();
+                    self.output.push_str(&generate_tabular(&header, &rows));
+                }
+                Statement::Plot {
+                    exprs,
+                    var,
+                    range,
+                    scale,
+                } => {
+                    // Example: `plot v * t, 1/2 * g * t^2 for t in 0 s..2 s` draws
+                    // both curves on one set of axes
+                    let var = self.resolve_alias(var);
+                    let mut exprs = exprs.clone();
+                    exprs
+                        .iter_mut()
+                        .for_each(|e| e.resolve_aliases(&self.aliases));
+
+                    let e = |e: &Expr| {
+                        e.eval(&self.scope)
+                            .and_then(Value::scalar)
+                            .map_err(add_line)
+                    };
+                    let (start, end) = range.as_ref();
+                    let graph = Plot {
+                        exprs: &exprs,
+                        var: &var,
+                        range: (e(start)?, e(end)?),
+                        scale: *scale,
+                    };
+                    self.output
+                        .push_str(&plot(graph, &self.scope, &self.format_args).map_err(add_line)?);
+                }
+                Statement::Balance {
+                    reactants,
+                    products,
+                } => {
+                    // Example: `balance Fe + O2 -> Fe2O3` prints 4Fe + 3O2 -> 2Fe2O3 and
+                    // stores the coefficients as \nu_{Fe}, \nu_{O2} and \nu_{Fe2O3}
+                    let reaction = Reaction::balance(reactants, products).map_err(add_line)?;
+                    for species in reaction.reactants.iter().chain(&reaction.products) {
+                        // formulas with brackets or charges can't be written as a variable
+                        if !species.name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                            continue;
+                        }
+                        let var = format!("\\nu_{{{}}}", species.name);
+                        self.aliases
+                            .insert(format!("nu_{{{}}}", species.name), var.clone());
+                        self.scope
+                            .variables
+                            .insert(var, exact_count(species.coefficient).into());
+                    }
+                    self.output.push_str(&format!(
+                        "${}$\\\\\n",
+                        reaction
+                            .to_latex_ext(&self.format_args)
+                            .map_err(add_line)?
+                            .to_string()
+                    ));
+                }
                 Statement::TTable { args, exprs } => {
                     self.output
                         .push_str(&crate::ttable::generate_ttable(args, exprs)?);
@@ -0,0 +1,133 @@
+use crate::expr::{val::Val, Expr};
+use crate::latex::{FormatArgs, ToLaTeX, UnitHint};
+use crate::ode::bind;
+use crate::statement::Scope;
+use crate::ttable::{generate_tabular, unit_column};
+use crate::CalcError;
+
+// A table longer than this won't fit in a document anyway
+const MAX_ROWS: usize = 1000;
+
+// A range like `0 s..10 s, step 1 s` in base units. The whole steps land on
+// the end, give or take rounding, or are followed by a partial step that does.
+pub struct Steps {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+    pub whole: usize,
+    pub partial: bool,
+}
+
+pub fn range_steps(var: &str, (start, end): (&Val, &Val), step: &Val) -> Result<Steps, CalcError> {
+    let (start, end, step) = (
+        start.without_offset(),
+        end.without_offset(),
+        step.without_offset(),
+    );
+    if end.unit.desc != start.unit.desc || step.unit.desc != start.unit.desc {
+        let unit = |v: &Val| {
+            if v.unit.desc.is_empty() {
+                "no unit".to_string()
+            } else {
+                v.unit.to_string()
+            }
+        };
+        return Err(CalcError::UnitError(format!(
+            "The range and step of {} must have the same units, like 0 s..5 s, step 1 s, got {}, {} and {}",
+            var,
+            unit(&start),
+            unit(&end),
+            unit(&step)
+        )));
+    }
+
+    let (x0, x1, h) = (start.base_num(), end.base_num(), step.base_num());
+    if h <= 0.0 || x1 < x0 {
+        return Err(CalcError::Other(format!(
+            "The step must be positive and the range of {} must go forwards",
+            var
+        )));
+    }
+    let n = (x1 - x0) / h;
+    let whole = (n * (1.0 + 1e-9)).floor();
+    Ok(Steps {
+        start: x0,
+        end: x1,
+        step: h,
+        whole: whole as usize,
+        partial: n - whole > n * 1e-9,
+    })
+}
+
+// A range like 0..5 s starts at 0 s, since a bare zero is the same in any unit,
+// except on a scale like degC where zero isn't the absence of anything
+pub fn zero_start(start: Val, end: &Val) -> Result<Val, CalcError> {
+    let bare_zero = start.unit.desc.is_empty() && start.num == 0.0 && start.imag == 0.0;
+    if bare_zero && end.unit.offset == 0.0 {
+        Val::empty(0.0).with_unit(&end.unit)
+    } else {
+        Ok(start)
+    }
+}
+
+// `table var in start..end, step step: exprs`, with the variable's column
+// shown in the unit of the hint when there is one
+pub struct Sweep<'a> {
+    pub var: &'a str,
+    pub range: (Val, Val),
+    pub step: Val,
+    pub exprs: &'a [Expr],
+    pub unit_hint: Option<UnitHint>,
+}
+
+// Evaluates each expression for every value of the variable in the range, giving
+// a table with a column for the variable followed by one for each expression
+pub fn tabulate(sweep: Sweep, scope: &Scope, args: &FormatArgs) -> Result<String, CalcError> {
+    let Sweep {
+        var,
+        range: (start, end),
+        step,
+        exprs,
+        unit_hint,
+    } = sweep;
+    let start = zero_start(start, &end)?;
+    let Steps {
+        start: x0,
+        step: h,
+        whole: steps,
+        ..
+    } = range_steps(var, (&start, &end), &step)?;
+    if steps >= MAX_ROWS {
+        return Err(CalcError::Other(format!(
+            "The table would have {} rows, try a larger step",
+            steps + 1
+        )));
+    }
+
+    // the variable takes values in the unit the range starts in
+    let xs: Vec<Val> = (0..=steps)
+        .map(|i| bind(x0 + i as f64 * h, &start.unit))
+        .collect::<Result<_, _>>()?;
+
+    let mut columns = vec![unit_column(var, &xs, unit_hint, args)?];
+    for expr in exprs {
+        let vals = xs
+            .iter()
+            .map(|x| {
+                let mut scope = scope.child();
+                scope.variables.insert(var.to_string(), x.clone().into());
+                expr.eval(&scope)?.scalar()
+            })
+            .collect::<Result<Vec<Val>, CalcError>>()?;
+
+        let name = expr.to_latex_ext(args)?.to_string();
+        columns.push(unit_column(name.trim(), &vals, None, args)?);
+    }
+
+    let header: Vec<String> = columns.iter().map(|(h, _)| h.clone()).collect();
+    let rows: Vec<Vec<String>> = (0..xs.len())
+        .map(|i| columns.iter().map(|(_, cells)| cells[i].clone()).collect())
+        .collect();
+
+    Ok(generate_tabular(&header, &rows))
+}
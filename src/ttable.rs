@@ -1,7 +1,8 @@
 use crate::expr::bool_expr::BoolExpr;
 use crate::CalcError;
 use std::collections::BTreeMap;
-use crate::latex::ToLaTeX;
+use crate::expr::val::Val;
+use crate::latex::{format_shared_unit, FormatArgs, ToLaTeX, UnitHint};
 
 pub fn generate_ttable(args: &[String], exprs: &[BoolExpr]) -> Result<String, CalcError> {
     let num_rows = 2usize.pow(args.len() as u32);
@@ -59,3 +60,47 @@ pub fn generate_ttable(args: &[String], exprs: &[BoolExpr]) -> Result<String, Ca
 
     Ok(table)
 }
+
+// A column of values in a shared unit, headed by the name and that unit
+pub fn unit_column(
+    name: &str,
+    vals: &[Val],
+    unit_hint: Option<UnitHint>,
+    args: &FormatArgs,
+) -> Result<(String, Vec<String>), CalcError> {
+    let args = FormatArgs {
+        unit_hint,
+        ..*args
+    };
+    let (cells, unit) = format_shared_unit(&vals.iter().collect::<Vec<_>>(), &args)?;
+    let header = if unit.is_empty() {
+        format!("${}$", name)
+    } else {
+        format!("${}\\ ({})$", name, unit)
+    };
+    Ok((header, cells))
+}
+
+// A table of already formatted cells, in the same layout as truth tables
+pub fn generate_tabular(header: &[String], rows: &[Vec<String>]) -> String {
+    let col_specifier = format!("{}|", "|c".repeat(header.len()));
+    let header = format!("{}\\\\\n", header.join(" & "));
+
+    let mut body = String::new();
+    for row in rows {
+        body.push_str(&row.join(" & "));
+        body.push_str("\\\\\n");
+        body.push_str("\\hline\n");
+    }
+
+    format!("
+    \\begin{{center}}
+        \\begin{{tabular}}{{{col_specifier}}}
+            \\hline
+            {header}
+            \\hline
+            {rows}
+        \\end{{tabular}}
+    \\end{{center}}
+    ", col_specifier=col_specifier, header=header, rows=body)
+}